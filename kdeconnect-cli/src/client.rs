@@ -0,0 +1,201 @@
+//! Client of the control socket of the daemon, see `kdeconnect/src/control.rs`.
+//!
+//! The daemon finds its socket with the same functions, so that both always agree on it.
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Name of the standard per-user directories of the daemon.
+pub const APP_DIR_NAME: &str = "kde-connect-rs";
+/// Directory to keep all files in, if `--home` is not given.
+pub const HOME_ENV: &str = "KDECONNECT_RS_HOME";
+
+#[cfg(unix)]
+type Stream = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+type Stream = std::fs::File;
+
+/// The directory given with `--home`, or else the one in [`HOME_ENV`].
+pub fn home_dir(home: Option<PathBuf>) -> Option<PathBuf> {
+    home.or_else(|| std::env::var_os(HOME_ENV).map(PathBuf::from))
+}
+
+/// Data directory of the daemon, in `home` if there is one or else the standard one.
+pub fn data_dir(home: Option<&Path>) -> Result<PathBuf> {
+    match home {
+        Some(home) => Ok(home.join("data")),
+        None => Ok(directories::BaseDirs::new()
+            .context("Failed to get base dirs")?
+            .data_dir()
+            .join(APP_DIR_NAME)),
+    }
+}
+
+/// Where the daemon accepts local clients.
+///
+/// A named pipe on Windows, named after the data directory so that daemons using
+/// different directories don't clash.
+pub fn control_socket(data_dir: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let digest = md5::compute(data_dir.to_string_lossy().as_bytes());
+        PathBuf::from(format!(r"\\.\pipe\{}-{:x}", APP_DIR_NAME, digest))
+    }
+    #[cfg(not(windows))]
+    {
+        data_dir.join("control.sock")
+    }
+}
+
+/// Control socket of the daemon started with `home`, see [`home_dir`].
+pub fn socket_path(home: Option<PathBuf>) -> Result<PathBuf> {
+    let home = home_dir(home);
+    Ok(control_socket(&data_dir(home.as_deref())?))
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+pub struct Client {
+    reader: BufReader<Stream>,
+    writer: Stream,
+    next_id: u64,
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> Result<Self> {
+        #[cfg(unix)]
+        let stream = Stream::connect(path);
+        #[cfg(windows)]
+        let stream = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path);
+        let stream = stream
+            .with_context(|| format!("Connect to the daemon at {:?}, is it running?", path))?;
+
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            next_id: 1,
+        })
+    }
+
+    /// Call a method and wait for its result, skipping event notifications.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                anyhow::bail!("The daemon closed the connection");
+            }
+
+            let response: Response = serde_json::from_str(&line).context("Parse response")?;
+            if response.id != Some(json!(id)) {
+                continue;
+            }
+            if let Some(error) = response.error {
+                anyhow::bail!("{} ({})", error.message, error.code);
+            }
+            return Ok(response.result.unwrap_or(Value::Null));
+        }
+    }
+
+    /// Share URLs and local files with a device, the files as one batch. Returns what was
+    /// shared, with files as absolute paths.
+    pub fn share(&mut self, device: &str, targets: &[String]) -> Result<Vec<String>> {
+        let (urls, files) = share_targets(targets)?;
+        let mut shared = Vec::new();
+        for url in urls {
+            self.call("share", json!({ "device": device, "url": url }))?;
+            shared.push(url.to_string());
+        }
+        if !files.is_empty() {
+            self.call("share", json!({ "device": device, "paths": files }))?;
+            shared.extend(files.iter().map(|f| f.display().to_string()));
+        }
+        Ok(shared)
+    }
+}
+
+/// Split share targets into URLs and files. Files are made absolute, since the daemon may run
+/// in another directory.
+fn share_targets(targets: &[String]) -> Result<(Vec<&str>, Vec<PathBuf>)> {
+    let (urls, files): (Vec<_>, Vec<_>) = targets
+        .iter()
+        .map(String::as_str)
+        .partition(|t| t.contains("://"));
+    let files = files
+        .iter()
+        .map(|f| std::fs::canonicalize(f).with_context(|| format!("Share {}", f)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((urls, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_is_named_after_the_data_dir() {
+        let data_dir = data_dir(Some(Path::new("home"))).unwrap();
+        assert_eq!(data_dir, Path::new("home").join("data"));
+
+        let socket = control_socket(&data_dir);
+        #[cfg(windows)]
+        {
+            let name = socket.to_string_lossy();
+            assert!(name.starts_with(r"\\.\pipe\kde-connect-rs-"), "{}", name);
+            assert_ne!(socket, control_socket(Path::new("other")));
+        }
+        #[cfg(not(windows))]
+        assert_eq!(socket, data_dir.join("control.sock"));
+    }
+
+    #[test]
+    fn share_targets_are_split() {
+        let targets = ["https://example.com", "Cargo.toml", "file:///tmp/a.txt"]
+            .map(String::from)
+            .to_vec();
+        let (urls, files) = share_targets(&targets).unwrap();
+        assert_eq!(urls, ["https://example.com", "file:///tmp/a.txt"]);
+        assert_eq!(
+            files,
+            [Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")]
+        );
+
+        let missing = ["no such file.txt".to_string()];
+        assert!(share_targets(&missing).is_err());
+    }
+}
@@ -0,0 +1,3 @@
+//! Client of the control socket of a running daemon, used by `kdeconnect-cli` and by the
+//! commands the daemon forwards to a running instance of itself.
+pub mod client;
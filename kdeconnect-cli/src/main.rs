@@ -0,0 +1,251 @@
+//! Command line client for a running daemon, with the flags of `kdeconnect-cli` from KDE
+//! Connect where they make sense, so that existing scripts keep working.
+use std::{path::PathBuf, process::ExitCode};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use kdeconnect_cli::client::{self, Client};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "kdeconnect-cli",
+    version,
+    about = "Control a running KDEConnect.rs daemon, like kdeconnect-cli does for KDE Connect."
+)]
+struct Args {
+    /// List all devices.
+    #[arg(short = 'l', long)]
+    list_devices: bool,
+    /// List available (paired and reachable) devices.
+    #[arg(short = 'a', long)]
+    list_available: bool,
+    /// Make --list-devices or --list-available print only the device IDs.
+    #[arg(long)]
+    id_only: bool,
+    /// Make --list-devices or --list-available print only the device names.
+    #[arg(long)]
+    name_only: bool,
+    /// Accepted for compatibility, devices are always discovered in background.
+    #[arg(long)]
+    refresh: bool,
+    /// Device ID.
+    #[arg(short = 'd', long = "device", value_name = "dev")]
+    device: Option<String>,
+    /// Device name.
+    #[arg(
+        short = 'n',
+        long = "name",
+        value_name = "name",
+        conflicts_with = "device"
+    )]
+    name: Option<String>,
+    /// Request pairing with the device.
+    #[arg(long)]
+    pair: bool,
+    /// Stop pairing with the device.
+    #[arg(long)]
+    unpair: bool,
+    /// Send a ping to the device.
+    #[arg(long)]
+    ping: bool,
+    /// Same as ping, but with a custom message.
+    #[arg(long, value_name = "message")]
+    ping_msg: Option<String>,
+    /// Make the device ring, to find it.
+    #[arg(long)]
+    ring: bool,
+    /// Share a URL or a file with the device, can be repeated.
+    #[arg(long, value_name = "path or url")]
+    share: Vec<String>,
+    /// Share text with the device.
+    #[arg(long, value_name = "text")]
+    share_text: Option<String>,
+    /// Request the battery status of the device.
+    #[arg(long)]
+    battery: bool,
+    /// Print results as JSON, keyed by action, e.g. `ping`, `share` or `share_text`.
+    #[arg(long)]
+    json: bool,
+    /// The directory the daemon was started with, if it was started with --home.
+    #[arg(long, value_name = "DIR")]
+    home: Option<PathBuf>,
+}
+
+/// A device as listed by the daemon, other fields are kept for JSON output.
+#[derive(Debug, Deserialize, Serialize)]
+struct Device {
+    id: String,
+    name: String,
+    connected: bool,
+    paired: bool,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+impl Device {
+    fn state(&self) -> &'static str {
+        match (self.paired, self.connected) {
+            (true, true) => "paired and reachable",
+            (false, true) => "reachable",
+            (true, false) => "paired",
+            (false, false) => "unreachable",
+        }
+    }
+}
+
+fn list_devices(client: &mut Client) -> Result<Vec<Device>> {
+    let devices = client.call("list_devices", json!({}))?;
+    serde_json::from_value(devices).context("Parse device list")
+}
+
+fn print_devices(client: &mut Client, args: &Args) -> Result<()> {
+    let devices = list_devices(client)?
+        .into_iter()
+        .filter(|d| !args.list_available || (d.paired && d.connected))
+        .collect::<Vec<_>>();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+    } else if args.id_only {
+        devices.iter().for_each(|d| println!("{}", d.id));
+    } else if args.name_only {
+        devices.iter().for_each(|d| println!("{}", d.name));
+    } else {
+        for d in &devices {
+            println!("- {}: {} ({})", d.name, d.id, d.state());
+        }
+        match devices.len() {
+            1 => println!("1 device found"),
+            n => println!("{} devices found", n),
+        }
+    }
+    Ok(())
+}
+
+/// The device given with `--device` or `--name`.
+fn find_device(client: &mut Client, args: &Args) -> Result<Device> {
+    let devices = list_devices(client)?;
+    if let Some(id) = &args.device {
+        devices
+            .into_iter()
+            .find(|d| &d.id == id)
+            .with_context(|| format!("Couldn't find device with id \"{}\"", id))
+    } else if let Some(name) = &args.name {
+        devices
+            .into_iter()
+            .find(|d| &d.name == name)
+            .with_context(|| format!("Couldn't find device with name \"{}\"", name))
+    } else {
+        anyhow::bail!("No device specified, use --device or --name")
+    }
+}
+
+/// Calls methods of one device, keeping their results for `--json`.
+struct DeviceCalls {
+    client: Client,
+    device: String,
+    results: Map<String, Value>,
+}
+
+impl DeviceCalls {
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.call_as(method, method, params)
+    }
+
+    /// Call a method, keeping its result as `key` instead of the method name.
+    fn call_as(&mut self, key: &str, method: &str, mut params: Value) -> Result<Value> {
+        params["device"] = json!(self.device);
+        let result = self.client.call(method, params)?;
+        self.results.insert(key.to_string(), result.clone());
+        Ok(result)
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let mut client = Client::connect(&client::socket_path(args.home.clone())?)?;
+
+    if args.list_devices || args.list_available {
+        return print_devices(&mut client, &args);
+    }
+
+    let has_action = args.pair
+        || args.unpair
+        || args.ping
+        || args.ping_msg.is_some()
+        || args.ring
+        || !args.share.is_empty()
+        || args.share_text.is_some()
+        || args.battery;
+    if !has_action {
+        if args.refresh {
+            return Ok(());
+        }
+        anyhow::bail!("Nothing to do, see --help");
+    }
+
+    let device = find_device(&mut client, &args)?.id;
+    let mut calls = DeviceCalls {
+        client,
+        device,
+        results: Map::new(),
+    };
+
+    if args.pair {
+        calls.call("pair", json!({}))?;
+        if !args.json {
+            println!("Pair requested");
+        }
+    }
+    if args.unpair {
+        calls.call("unpair", json!({}))?;
+        if !args.json {
+            println!("Unpaired");
+        }
+    }
+    if args.ping || args.ping_msg.is_some() {
+        calls.call("ping", json!({ "message": args.ping_msg }))?;
+    }
+    if args.ring {
+        calls.call("ring", json!({}))?;
+    }
+    if !args.share.is_empty() {
+        let shared = calls.client.share(&calls.device, &args.share)?;
+        if !args.json {
+            shared.iter().for_each(|s| println!("Shared {}", s));
+        }
+        calls.results.insert("share".to_string(), json!(shared));
+    }
+    if let Some(text) = &args.share_text {
+        calls.call_as("share_text", "share", json!({ "text": text }))?;
+    }
+    if args.battery {
+        let status = calls.call("battery", json!({}))?;
+        if !args.json {
+            match status["charge"].as_u64() {
+                Some(charge) if status["is_charging"] == json!(true) => {
+                    println!("Battery: {}% (charging)", charge)
+                }
+                Some(charge) => println!("Battery: {}%", charge),
+                None => println!("Battery: unknown"),
+            }
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&calls.results)?);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
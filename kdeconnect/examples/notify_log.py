@@ -0,0 +1,64 @@
+"""Example external plugin, logging the notifications of devices to the daemon's log.
+
+Enable it in the settings of the daemon, and for each device:
+
+    [external-plugins.notify-log]
+    command = "python"
+    args = ["C:/plugins/notify_log.py"]
+
+    [devices.phone.plugins.notify-log]
+
+The messages are described in kdeconnect/src/plugin/external.rs.
+"""
+import json
+import sys
+
+
+def send(message):
+    print(json.dumps(message), flush=True)
+
+
+def log(message):
+    send({"type": "log", "level": "info", "message": message})
+
+
+def main():
+    # Must come first, and is only read once.
+    send({
+        "type": "capabilities",
+        "incoming": ["kdeconnect.notification"],
+        "outgoing": ["kdeconnect.notification.request"],
+    })
+
+    names = {}
+    for line in sys.stdin:
+        message = json.loads(line)
+        device = message.get("device")
+
+        if message["type"] == "connected":
+            names[device] = message["name"]
+            # Ask for the notifications that are already shown.
+            send({
+                "type": "send",
+                "device": device,
+                "packet": {
+                    "type": "kdeconnect.notification.request",
+                    "body": {"request": True},
+                },
+            })
+        elif message["type"] == "disconnected":
+            names.pop(device, None)
+        elif message["type"] == "packet":
+            body = message["packet"]["body"]
+            if body.get("isCancel"):
+                continue
+            log("{} ({}): {} - {}".format(
+                names.get(device, device),
+                body.get("appName"),
+                body.get("title"),
+                body.get("text"),
+            ))
+
+
+if __name__ == "__main__":
+    main()
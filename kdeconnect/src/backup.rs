@@ -0,0 +1,88 @@
+//! Export and import of the device identity and trusted devices, so that a reinstalled
+//! machine doesn't need to be paired again.
+//!
+//! The archive is a JSON file encrypted with a passphrase, see [`crate::crypto`].
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, KeyProtection},
+    crypto::{self, EncryptedData},
+    paths::Paths,
+    settings::Settings,
+    tls,
+    trust::{TrustStore, TrustedDevices},
+};
+
+const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IdentityArchive {
+    version: u32,
+    uuid: String,
+    tls_key: String,
+    tls_cert: String,
+    trusted_devices: TrustedDevices,
+}
+
+/// Write the current identity and trusted devices to `file`.
+pub fn export(paths: &Paths, file: &Path, passphrase: &str) -> Result<()> {
+    let config = Config::load(paths.identity_file()).context("Load identity")?;
+    let trusted_devices = TrustStore::load(paths.trusted_devices_file())
+        .context("Load trusted devices")?
+        .all();
+
+    let archive = IdentityArchive {
+        version: ARCHIVE_VERSION,
+        uuid: config.uuid,
+        tls_key: base64::encode(&config.tls_key),
+        tls_cert: base64::encode(&config.tls_cert),
+        trusted_devices,
+    };
+
+    let encrypted = crypto::encrypt(&serde_json::to_vec(&archive)?, passphrase)?;
+    serde_json::to_writer_pretty(crate::paths::create_private_file(file)?, &encrypted)?;
+
+    Ok(())
+}
+
+/// Replace the current identity and trusted devices with the ones in `file`.
+///
+/// The daemon should not be running while importing.
+pub fn import(paths: &Paths, file: &Path, passphrase: &str) -> Result<()> {
+    let encrypted: EncryptedData =
+        serde_json::from_reader(BufReader::new(File::open(file)?)).context("Invalid archive")?;
+    let archive: IdentityArchive =
+        serde_json::from_slice(&crypto::decrypt(&encrypted, passphrase)?)
+            .context("Invalid archive")?;
+
+    if archive.version != ARCHIVE_VERSION {
+        anyhow::bail!("Unsupported archive version {}", archive.version);
+    }
+
+    let settings = Settings::load_or_default(paths.settings_file()).context("Load settings")?;
+    let config = Config {
+        uuid: archive.uuid,
+        tls_key: base64::decode(&archive.tls_key)?,
+        tls_cert: base64::decode(&archive.tls_cert)?,
+        key_protection: KeyProtection::new(settings.key_storage)?,
+    };
+    tls::check_key_pair(&config.tls_key, &config.tls_cert).context("Invalid identity")?;
+
+    config
+        .save(paths.identity_file())
+        .context("Save identity")?;
+    TrustStore::write(paths.trusted_devices_file(), &archive.trusted_devices)
+        .context("Save trusted devices")?;
+
+    log::info!(
+        "Imported identity {} with {} trusted devices",
+        config.uuid,
+        archive.trusted_devices.len()
+    );
+
+    Ok(())
+}
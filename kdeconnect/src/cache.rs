@@ -19,7 +19,7 @@ pub struct PayloadCache {
 
 impl PayloadCache {
     pub fn new() -> Result<Self> {
-        let cache_path = std::env::temp_dir().join("kdeconnect-rs");
+        let cache_path = crate::paths::get().cache_dir.join("payloads");
         if !cache_path.exists() {
             std::fs::create_dir_all(&cache_path)?;
         }
@@ -0,0 +1,118 @@
+//! Command line arguments, and commands that run without starting the daemon.
+//!
+//! Commands that act on devices are forwarded to the running daemon over the control socket,
+//! and the process exits once the daemon has handled them.
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use kdeconnect_cli::client::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{backup, crypto, instance, paths::Paths};
+
+/// Environment variable to read the archive passphrase from, instead of prompting for it.
+const PASSPHRASE_ENV: &str = "KDECONNECT_RS_PASSPHRASE";
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "An implementation of the KDE Connect protocol for Windows."
+)]
+pub struct Args {
+    /// Keep all files in this directory instead of the standard per-user ones.
+    #[arg(long, value_name = "DIR")]
+    pub home: Option<PathBuf>,
+
+    /// Send a ping to a device, same as the `ping` command.
+    #[arg(long, value_name = "DEVICE")]
+    pub ping: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Args {
+    /// The command to run instead of starting the daemon, if any.
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.command.take().or_else(|| {
+            self.ping.take().map(|device| Command::Ping {
+                device,
+                message: None,
+            })
+        })
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Export the device identity and trusted devices to a passphrase-protected file.
+    ExportIdentity { file: PathBuf },
+    /// Import the device identity and trusted devices, replacing the current ones.
+    ImportIdentity { file: PathBuf },
+    /// Send a ping to a device, given by ID or name.
+    Ping {
+        device: String,
+        /// Show this instead of the default message.
+        #[arg(long)]
+        message: Option<String>,
+    },
+    /// Share URLs or files with a device, given by ID or name. Files are sent as one batch.
+    Share {
+        device: String,
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceEntry {
+    id: String,
+    name: String,
+}
+
+/// ID of the device with this ID or name, as known by the daemon.
+fn find_device(client: &mut Client, device: &str) -> Result<String> {
+    let devices: Vec<DeviceEntry> =
+        serde_json::from_value(client.call("list_devices", Value::Null)?)
+            .context("Parse device list")?;
+    devices
+        .iter()
+        .find(|d| d.id == device)
+        .or_else(|| devices.iter().find(|d| d.name == device))
+        .map(|d| d.id.clone())
+        .with_context(|| format!("Unknown device: {}", device))
+}
+
+pub fn run(command: Command, paths: &Paths) -> Result<()> {
+    match command {
+        Command::ExportIdentity { file } => {
+            let passphrase = crypto::read_passphrase(PASSPHRASE_ENV, "Archive passphrase", true)?;
+            backup::export(paths, &file, &passphrase).context("Export identity")?;
+            println!("Identity exported to {}", file.display());
+        }
+        Command::ImportIdentity { file } => {
+            // The daemon would overwrite the imported files.
+            let _lock = instance::try_lock(paths)?
+                .context("KDEConnect.rs is running, quit it before importing")?;
+            let passphrase = crypto::read_passphrase(PASSPHRASE_ENV, "Archive passphrase", false)?;
+            backup::import(paths, &file, &passphrase).context("Import identity")?;
+            println!("Identity imported from {}", file.display());
+        }
+        Command::Ping { device, message } => {
+            let mut client = Client::connect(&paths.control_socket())?;
+            let device = find_device(&mut client, &device)?;
+            client.call("ping", json!({ "device": device, "message": message }))?;
+        }
+        Command::Share { device, targets } => {
+            let mut client = Client::connect(&paths.control_socket())?;
+            let device = find_device(&mut client, &device)?;
+            for shared in client.share(&device, &targets)? {
+                println!("Shared {}", shared);
+            }
+        }
+    }
+
+    Ok(())
+}
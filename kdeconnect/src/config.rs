@@ -1,21 +1,75 @@
 use std::{fs::File, io::BufReader, path::Path};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    crypto::{self, EncryptedData},
+    keystore, paths,
+    settings::{CertificateSettings, KeyStorage},
+};
+
+/// Environment variable to read the key passphrase from, instead of prompting for it.
+const KEY_PASSPHRASE_ENV: &str = "KDECONNECT_RS_KEY_PASSPHRASE";
+
 #[derive(Debug, Deserialize, Serialize)]
 struct EncodedConfig {
     uuid: String,
-    tls_key: String,
+    #[serde(flatten)]
+    tls_key: EncodedKey,
     tls_cert: String,
 }
 
-impl From<&Config> for EncodedConfig {
-    fn from(config: &Config) -> Self {
-        Self {
-            uuid: config.uuid.clone(),
-            tls_key: base64::encode(&config.tls_key),
-            tls_cert: base64::encode(&config.tls_cert),
+/// The private key as stored in the identity file.
+#[derive(Debug, Deserialize, Serialize)]
+enum EncodedKey {
+    /// Base64 encoded, this is what older versions use.
+    #[serde(rename = "tls_key")]
+    Plain(String),
+    #[serde(rename = "tls_key_encrypted")]
+    Encrypted(EncryptedData),
+    /// Name of the secret store that holds the key.
+    #[serde(rename = "tls_key_store")]
+    Store(String),
+}
+
+/// How the private key is protected when saved.
+#[derive(Clone)]
+pub enum KeyProtection {
+    None,
+    Passphrase(String),
+    Store(String),
+}
+
+impl std::fmt::Debug for KeyProtection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::Passphrase(_) => write!(f, "Passphrase"),
+            Self::Store(name) => f.debug_tuple("Store").field(name).finish(),
+        }
+    }
+}
+
+impl KeyProtection {
+    /// Set up protection for the given storage, asking for a new passphrase if needed.
+    pub fn new(storage: KeyStorage) -> Result<Self> {
+        Ok(match storage {
+            KeyStorage::File => Self::None,
+            KeyStorage::Passphrase => Self::Passphrase(crypto::read_passphrase(
+                KEY_PASSPHRASE_ENV,
+                "New key passphrase",
+                true,
+            )?),
+            KeyStorage::Keyring => Self::Store(keystore::KEYRING.into()),
+        })
+    }
+
+    pub fn storage(&self) -> KeyStorage {
+        match self {
+            Self::None => KeyStorage::File,
+            Self::Passphrase(_) => KeyStorage::Passphrase,
+            Self::Store(_) => KeyStorage::Keyring,
         }
     }
 }
@@ -25,16 +79,36 @@ pub struct Config {
     pub uuid: String,
     pub tls_key: Vec<u8>,
     pub tls_cert: Vec<u8>,
+    pub key_protection: KeyProtection,
 }
 
 impl Config {
     /// Loads config from a file, or creates a new one if it doesn't exist.
-    pub fn init_or_load(path: impl AsRef<Path>) -> Result<Self> {
+    ///
+    /// The key is moved to `storage` if it's kept somewhere else.
+    pub fn init_or_load(
+        path: impl AsRef<Path>,
+        storage: KeyStorage,
+        cert: &CertificateSettings,
+    ) -> Result<Self> {
         let path = path.as_ref();
         if path.exists() {
-            Self::load(path)
+            paths::make_private(path)?;
+
+            let mut r = Self::load(path)?;
+            if r.key_protection.storage() != storage {
+                log::info!(
+                    "Moving private key from {:?} to {:?} storage",
+                    r.key_protection.storage(),
+                    storage
+                );
+                let old = std::mem::replace(&mut r.key_protection, KeyProtection::new(storage)?);
+                r.save(path)?;
+                r.forget_key(&old)?;
+            }
+            Ok(r)
         } else {
-            let r = Self::init()?;
+            let r = Self::init(KeyProtection::new(storage)?, cert)?;
             r.save(path)?;
             Ok(r)
         }
@@ -47,22 +121,56 @@ impl Config {
     }
 
     /// Initialize new UUID and certificates.
-    pub fn init() -> Result<Self> {
+    pub fn init(key_protection: KeyProtection, cert: &CertificateSettings) -> Result<Self> {
         let uuid = uuid::Uuid::new_v4().to_string();
+        Self::with_uuid(uuid, key_protection, cert)
+    }
 
-        let (tls_cert, tls_key) = crate::tls::generate_certs(&uuid)?;
+    /// Initialize new certificates for an existing UUID.
+    pub fn with_uuid(
+        uuid: String,
+        key_protection: KeyProtection,
+        cert: &CertificateSettings,
+    ) -> Result<Self> {
+        let (tls_cert, tls_key) = crate::tls::generate_certs(&uuid, cert)?;
 
         Ok(Self {
             uuid,
             tls_key,
             tls_cert,
+            key_protection,
         })
     }
 
     pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
-        let config = EncodedConfig::from(self);
-        let f = File::create(path)?;
-        serde_json::to_writer(f, &config)?;
+        let tls_key = match &self.key_protection {
+            KeyProtection::None => EncodedKey::Plain(base64::encode(&self.tls_key)),
+            KeyProtection::Passphrase(passphrase) => {
+                EncodedKey::Encrypted(crypto::encrypt(&self.tls_key, passphrase)?)
+            }
+            KeyProtection::Store(name) => {
+                keystore::open(name)?
+                    .set(&self.uuid, &self.tls_key)
+                    .context("Save key to secret store")?;
+                EncodedKey::Store(name.clone())
+            }
+        };
+        let config = EncodedConfig {
+            uuid: self.uuid.clone(),
+            tls_key,
+            tls_cert: base64::encode(&self.tls_cert),
+        };
+
+        paths::write_private_file(path, &serde_json::to_vec(&config)?)
+    }
+
+    /// Remove our key from a secret store that is no longer used.
+    pub fn forget_key(&self, key_protection: &KeyProtection) -> Result<()> {
+        if let KeyProtection::Store(name) = key_protection {
+            keystore::open(name)?
+                .delete(&self.uuid)
+                .context("Remove key from secret store")?;
+        }
         Ok(())
     }
 }
@@ -71,12 +179,27 @@ impl TryFrom<EncodedConfig> for Config {
     type Error = anyhow::Error;
 
     fn try_from(encoded: EncodedConfig) -> Result<Self, Self::Error> {
-        let tls_key = base64::decode(&encoded.tls_key)?;
+        let (tls_key, key_protection) = match encoded.tls_key {
+            EncodedKey::Plain(key) => (base64::decode(&key)?, KeyProtection::None),
+            EncodedKey::Encrypted(encrypted) => {
+                let passphrase =
+                    crypto::read_passphrase(KEY_PASSPHRASE_ENV, "Key passphrase", false)?;
+                let key = crypto::decrypt(&encrypted, &passphrase).context("Decrypt key")?;
+                (key, KeyProtection::Passphrase(passphrase))
+            }
+            EncodedKey::Store(name) => {
+                let key = keystore::open(&name)?
+                    .get(&encoded.uuid)?
+                    .with_context(|| format!("Key not found in secret store {}", name))?;
+                (key, KeyProtection::Store(name))
+            }
+        };
         let tls_cert = base64::decode(&encoded.tls_cert)?;
         Ok(Self {
             uuid: encoded.uuid,
             tls_key,
             tls_cert,
+            key_protection,
         })
     }
 }
@@ -1,23 +1,51 @@
-use crate::{config::Config, device::DeviceManagerHandle, CustomWindowEvent};
-use anyhow::Result;
-use once_cell::sync::OnceCell;
-use std::{fmt::Debug, sync::Arc};
+#[cfg(windows)]
+use crate::CustomWindowEvent;
+use crate::{
+    config::Config,
+    device::{DeviceManagerHandle, SendError, SendOptions},
+    packet::NetworkPacket,
+    paths,
+    plugin::external::ExternalPlugins,
+    settings::Settings,
+    shutdown::Shutdown,
+    tls,
+    trust::TrustStore,
+    utils,
+};
+use anyhow::{Context, Result};
+use std::{
+    fmt::Debug,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+#[cfg(windows)]
 use tao::{event_loop::EventLoopProxy, global_shortcut::ShortcutManager};
+#[cfg(windows)]
+use tokio::sync::Mutex;
 use tokio::{
     net::{TcpStream, ToSocketAddrs},
-    sync::Mutex,
+    sync::watch,
 };
 use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
 
 pub type AppContextRef = Arc<ApplicationContext>;
 
+/// How long paired devices are given to receive the unpair request when rotating the identity.
+const UNPAIR_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub struct ApplicationContext {
     pub device_manager: DeviceManagerHandle,
-    pub config: Config,
-    pub tls_acceptor: OnceCell<TlsAcceptor>,
-    pub tls_connector: OnceCell<TlsConnector>,
+    config: watch::Sender<Arc<Config>>,
+    settings: watch::Sender<Arc<Settings>>,
+    pub trusted_devices: TrustStore,
+    /// Started once with the daemon, unlike other settings.
+    pub external_plugins: ExternalPlugins,
+    tls: RwLock<(TlsAcceptor, TlsConnector)>,
+    #[cfg(windows)]
     pub event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
+    #[cfg(windows)]
     pub hotkey_manager: Mutex<ShortcutManager>,
+    pub shutdown: Shutdown,
 }
 
 impl Debug for ApplicationContext {
@@ -29,18 +57,28 @@ impl Debug for ApplicationContext {
 impl ApplicationContext {
     pub async fn new(
         config: Config,
-        event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
-        hotkey_manager: ShortcutManager,
+        settings: Settings,
+        trusted_devices: TrustStore,
+        external_plugins: ExternalPlugins,
+        #[cfg(windows)] event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
+        #[cfg(windows)] hotkey_manager: ShortcutManager,
+        shutdown: Shutdown,
     ) -> Result<Arc<Self>> {
         let (device_manager_actor, device_manager) = crate::device::DeviceManagerActor::new();
+        let tls = tls::setup(&config).context("Set up TLS")?;
 
         let this = Arc::new(Self {
             device_manager,
-            config,
-            tls_acceptor: OnceCell::new(),
-            tls_connector: OnceCell::new(),
+            config: watch::channel(Arc::new(config)).0,
+            settings: watch::channel(Arc::new(settings)).0,
+            trusted_devices,
+            external_plugins,
+            tls: RwLock::new(tls),
+            #[cfg(windows)]
             event_loop_proxy,
+            #[cfg(windows)]
             hotkey_manager: Mutex::new(hotkey_manager),
+            shutdown,
         });
 
         device_manager_actor.run(this.clone());
@@ -48,17 +86,36 @@ impl ApplicationContext {
         Ok(this)
     }
 
-    pub fn setup_tls(&self, acceptor: TlsAcceptor, connector: TlsConnector) {
-        self.tls_acceptor.set(acceptor).ok();
-        self.tls_connector.set(connector).ok();
+    /// Our identity.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.borrow().clone()
+    }
+
+    /// Subscribe to identity changes.
+    pub fn watch_config(&self) -> watch::Receiver<Arc<Config>> {
+        self.config.subscribe()
+    }
+
+    /// Current user settings.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.borrow().clone()
+    }
+
+    pub fn set_settings(&self, settings: Arc<Settings>) {
+        self.settings.send_replace(settings);
+    }
+
+    /// Subscribe to settings changes.
+    pub fn watch_settings(&self) -> watch::Receiver<Arc<Settings>> {
+        self.settings.subscribe()
     }
 
     pub fn tls_acceptor(&self) -> TlsAcceptor {
-        self.tls_acceptor.get().unwrap().clone()
+        self.tls.read().unwrap().0.clone()
     }
 
     pub fn tls_connector(&self) -> TlsConnector {
-        self.tls_connector.get().unwrap().clone()
+        self.tls.read().unwrap().1.clone()
     }
 
     pub async fn tls_connect(
@@ -81,4 +138,122 @@ impl ApplicationContext {
     pub async fn update_tray(&self) {
         self.device_manager.update_tray().await;
     }
+
+    /// Ask a device to pair, it's trusted once the user accepts on the device.
+    pub async fn request_pair(&self, device_id: &str) -> Result<(), SendError> {
+        self.device_manager
+            .send_packet(
+                device_id,
+                NetworkPacket::new_pair(true),
+                SendOptions::default(),
+            )
+            .await
+    }
+
+    /// Forget the certificate of a device, and tell it if it's connected.
+    pub async fn unpair(&self, device_id: &str) -> Result<()> {
+        let sent = self
+            .device_manager
+            .send_packet(
+                device_id,
+                NetworkPacket::new_pair(false),
+                SendOptions::default(),
+            )
+            .await;
+        if sent != Err(SendError::DeviceOffline) {
+            utils::log_if_error("Failed to send unpair request", sent);
+        }
+
+        self.trusted_devices
+            .untrust(device_id)
+            .context("Remove trusted device")?;
+        self.device_manager.set_paired(device_id, false).await;
+        Ok(())
+    }
+
+    /// Replace our key and certificate with new ones, and optionally the device ID.
+    ///
+    /// Paired devices won't accept the new certificate, so they are asked to unpair and
+    /// disconnected. They can pair again once they have seen the new identity.
+    pub async fn rotate_identity(&self, new_device_id: bool) -> Result<()> {
+        let cert_settings = self.settings().certificate.clone();
+        let old_config = self.config();
+        let key_protection = old_config.key_protection.clone();
+        let config = if new_device_id {
+            Config::init(key_protection, &cert_settings)
+        } else {
+            Config::with_uuid(old_config.uuid.clone(), key_protection, &cert_settings)
+        }
+        .context("Generate identity")?;
+        let tls = tls::setup(&config).context("Set up TLS")?;
+
+        config
+            .save(paths::get().identity_file())
+            .context("Save identity")?;
+
+        if new_device_id {
+            utils::log_if_error(
+                "Failed to remove old key",
+                old_config.forget_key(&old_config.key_protection),
+            );
+        }
+
+        // Paired devices keep the pairing unless they get the request before being
+        // disconnected.
+        let unpair = self.trusted_devices.all().into_keys().map(|id| async move {
+            let sent = self.device_manager.send_packet(
+                &id,
+                NetworkPacket::new_pair(false),
+                SendOptions::default(),
+            );
+            match tokio::time::timeout(UNPAIR_TIMEOUT, sent).await {
+                Ok(Ok(())) | Ok(Err(SendError::DeviceOffline)) => {}
+                Ok(Err(e)) => log::warn!("Failed to send unpair request to {}: {}", id, e),
+                Err(_) => log::warn!("Unpair request to {} was not sent in time", id),
+            }
+        });
+        futures::future::join_all(unpair).await;
+
+        // New connections use the new certificate from now on.
+        let device_id = config.uuid.clone();
+        *self.tls.write().unwrap() = tls;
+        self.config.send_replace(Arc::new(config));
+
+        self.device_manager.disconnect_all().await;
+        for id in self.trusted_devices.all().into_keys() {
+            self.device_manager.set_paired(id, false).await;
+        }
+        self.trusted_devices
+            .clear()
+            .context("Remove trusted devices")?;
+
+        log::info!("Identity rotated, device ID is {}", device_id);
+
+        Ok(())
+    }
+}
+
+/// A context with default settings and a new identity, for tests.
+///
+/// Paths are global, so the files of all tests go to the same directory.
+#[cfg(test)]
+pub async fn test_context() -> AppContextRef {
+    static PATHS: std::sync::Once = std::sync::Once::new();
+    PATHS.call_once(|| {
+        paths::init(Some(utils::test_dir("context"))).unwrap();
+    });
+
+    let settings = Settings::default();
+    let config = Config::init(crate::config::KeyProtection::None, &settings.certificate).unwrap();
+    let trusted_devices = TrustStore::load(paths::get().trusted_devices_file()).unwrap();
+    let external_plugins = ExternalPlugins::start(&settings.external_plugins).await;
+    ApplicationContext::new(
+        config,
+        settings,
+        trusted_devices,
+        external_plugins,
+        Shutdown::new(),
+    )
+    .await
+    .unwrap()
 }
@@ -0,0 +1,383 @@
+//! Local control socket, for scripts and other programs to drive the daemon.
+//!
+//! Clients connect to [`crate::paths::Paths::control_socket`], a Unix domain socket only the
+//! user can access, or a named pipe on Windows, which other users can't write to. Requests
+//! and responses are JSON-RPC 2.0 objects, one per line. Device methods take the device ID
+//! as `device`:
+//!
+//! - `list_devices`: known devices and their state.
+//! - `pair`, `unpair`.
+//! - `ping`, with an optional `message`.
+//! - `ring`: make the device ring, to find it.
+//! - `share`, with either `text`, `url`, or the `paths` of files to send as one batch.
+//! - `battery`: request the battery status of the device and return it.
+//! - `media_players`, `media_action` with a `player` and an `action` like `PlayPause`.
+//! - `call_plugin`, with `plugin`, `method` and `params`, for any other plugin method.
+//! - `subscribe`: receive device events from now on, as `event` notifications.
+//!
+//! Requests are handled concurrently, so responses may come in a different order.
+use std::{path::Path, time::UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{broadcast, mpsc},
+};
+
+use crate::{context::AppContextRef, device::DeviceEvent, paths};
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// The method was called correctly but failed, e.g. because the device is offline.
+const SERVER_ERROR: i32 = -32000;
+
+/// Methods handled by a plugin of the device: method, plugin name and plugin method.
+const PLUGIN_METHODS: &[(&str, &str, &str)] = &[
+    ("ping", "ping", "ping"),
+    ("ring", "find-my-phone", "ring"),
+    ("share", "share", "share"),
+    ("battery", "battery", "request"),
+    ("media_players", "mpris-remote", "players"),
+    ("media_action", "mpris-remote", "action"),
+];
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// Not set for notifications, which get no response.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<T> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: T,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+/// A device as listed by `list_devices`.
+#[derive(Debug, Serialize)]
+struct DeviceState {
+    id: String,
+    name: String,
+    device_type: String,
+    ip: String,
+    connected: bool,
+    paired: bool,
+    /// Milliseconds since the Unix epoch.
+    last_seen: u64,
+    status: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceParams {
+    device: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CallPluginParams {
+    device: String,
+    plugin: String,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+async fn list_devices(ctx: &AppContextRef) -> Result<Value> {
+    let trusted = ctx.trusted_devices.all();
+    let devices = ctx
+        .device_manager
+        .list_devices()
+        .await?
+        .into_iter()
+        .map(|d| DeviceState {
+            paired: trusted.contains_key(&d.id),
+            last_seen: d
+                .last_seen
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_millis() as u64),
+            ip: d.ip.to_string(),
+            id: d.id,
+            name: d.name,
+            device_type: d.device_type,
+            connected: d.connected,
+            status: d.status,
+        })
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_value(devices)?)
+}
+
+/// Run a method other than `subscribe`.
+async fn call(ctx: &AppContextRef, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "list_devices" => Ok(list_devices(ctx).await?),
+        "pair" => {
+            let params: DeviceParams = parse_params(params)?;
+            ctx.request_pair(&params.device)
+                .await
+                .map_err(anyhow::Error::from)?;
+            Ok(Value::Null)
+        }
+        "unpair" => {
+            let params: DeviceParams = parse_params(params)?;
+            ctx.unpair(&params.device).await?;
+            Ok(Value::Null)
+        }
+        "call_plugin" => {
+            let params: CallPluginParams = parse_params(params)?;
+            let plugin_params = params
+                .params
+                .unwrap_or_else(|| Value::Object(Default::default()));
+            Ok(ctx
+                .device_manager
+                .call_plugin(
+                    &params.device,
+                    &params.plugin,
+                    &params.method,
+                    plugin_params,
+                )
+                .await?)
+        }
+        method => {
+            let (_, plugin, plugin_method) = PLUGIN_METHODS
+                .iter()
+                .find(|(name, _, _)| *name == method)
+                .ok_or_else(|| {
+                    RpcError::new(METHOD_NOT_FOUND, format!("Unknown method: {}", method))
+                })?;
+
+            // The rest of the params are for the plugin.
+            let mut plugin_params = match params {
+                Value::Object(params) => params,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "Params must be an object")),
+            };
+            let device = match plugin_params.remove("device") {
+                Some(Value::String(device)) => device,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "Missing device")),
+            };
+
+            Ok(ctx
+                .device_manager
+                .call_plugin(&device, plugin, plugin_method, Value::Object(plugin_params))
+                .await?)
+        }
+    }
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(writer: &mut W, message: &impl Serialize) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Wait for the next device event, forever if the client has not subscribed.
+async fn next_event(
+    events: &mut Option<broadcast::Receiver<DeviceEvent>>,
+) -> Result<DeviceEvent, broadcast::error::RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handle requests of a client until it disconnects.
+async fn serve_client<S>(stream: S, ctx: AppContextRef) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let (response_tx, mut response_rx) = mpsc::unbounded_channel::<Response>();
+    let mut events = None;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let request = match serde_json::from_str::<Request>(&line) {
+                    Ok(request) if request.jsonrpc == "2.0" => request,
+                    Ok(_) => {
+                        let error = RpcError::new(INVALID_REQUEST, "Expected JSON-RPC 2.0");
+                        write_line(&mut writer, &Response::new(Value::Null, Err(error))).await?;
+                        continue;
+                    }
+                    Err(e) => {
+                        let error = RpcError::new(PARSE_ERROR, e.to_string());
+                        write_line(&mut writer, &Response::new(Value::Null, Err(error))).await?;
+                        continue;
+                    }
+                };
+                let params = request
+                    .params
+                    .unwrap_or_else(|| Value::Object(Default::default()));
+
+                if request.method == "subscribe" {
+                    events = Some(ctx.device_manager.subscribe());
+                    if let Some(id) = request.id {
+                        write_line(&mut writer, &Response::new(id, Ok(Value::Bool(true)))).await?;
+                    }
+                    continue;
+                }
+
+                let ctx = ctx.clone();
+                let response_tx = response_tx.clone();
+                tokio::spawn(async move {
+                    let result = call(&ctx, &request.method, params).await;
+                    if let Some(id) = request.id {
+                        let _ = response_tx.send(Response::new(id, result));
+                    }
+                });
+            }
+            Some(response) = response_rx.recv() => {
+                write_line(&mut writer, &response).await?;
+            }
+            event = next_event(&mut events) => match event {
+                Ok(event) => {
+                    let notification = Notification {
+                        jsonrpc: "2.0",
+                        method: "event",
+                        params: event,
+                    };
+                    write_line(&mut writer, &notification).await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    let notification = Notification {
+                        jsonrpc: "2.0",
+                        method: "events_lost",
+                        params: serde_json::json!({ "count": count }),
+                    };
+                    write_line(&mut writer, &notification).await?;
+                }
+                Err(broadcast::error::RecvError::Closed) => events = None,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn_client<S>(stream: S, ctx: AppContextRef)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = serve_client(stream, ctx).await {
+            log::warn!("Control client disconnected: {:?}", e);
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn listen(path: &Path, ctx: AppContextRef) -> Result<()> {
+    // Left behind if the daemon didn't exit cleanly.
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Remove {:?}", path)),
+    }
+
+    let listener =
+        tokio::net::UnixListener::bind(path).with_context(|| format!("Bind {:?}", path))?;
+    paths::make_private(path)?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_client(stream, ctx.clone());
+    }
+}
+
+#[cfg(windows)]
+async fn listen(path: &Path, ctx: AppContextRef) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .reject_remote_clients(true)
+        .create(path)
+        .with_context(|| format!("Create {:?}", path))?;
+
+    loop {
+        server.connect().await?;
+        // Create the next instance before handing this one over, so that clients always
+        // find the pipe.
+        let next = ServerOptions::new()
+            .reject_remote_clients(true)
+            .create(path)
+            .with_context(|| format!("Create {:?}", path))?;
+        spawn_client(std::mem::replace(&mut server, next), ctx.clone());
+    }
+}
+
+/// Accept clients on the control socket.
+pub async fn serve(ctx: AppContextRef) -> Result<()> {
+    let path = paths::get().control_socket();
+    log::info!("Control socket listening on {:?}", path);
+    listen(&path, ctx).await
+}
@@ -0,0 +1,115 @@
+//! Passphrase-based encryption, used for exported identities and the private key.
+//!
+//! The key is derived from the passphrase with PBKDF2-HMAC-SHA256 and the data is
+//! encrypted with AES-256-GCM.
+use std::num::NonZeroU32;
+
+use anyhow::Result;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+const KDF_PBKDF2_SHA256: &str = "pbkdf2-sha256";
+const CIPHER_AES_256_GCM: &str = "aes-256-gcm";
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptedData {
+    kdf: String,
+    iterations: u32,
+    cipher: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations =
+        NonZeroU32::new(iterations).ok_or_else(|| anyhow::anyhow!("Invalid iteration count"))?;
+
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+
+    let key = UnboundKey::new(&aead::AES_256_GCM, &key)
+        .map_err(|_| anyhow::anyhow!("Failed to create encryption key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Read a passphrase from the environment variable `env`, or prompt for it.
+///
+/// With `confirm`, the passphrase has to be entered twice, for setting a new one.
+pub fn read_passphrase(env: &str, prompt: &str, confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password(format!("{}: ", prompt))?;
+    if confirm
+        && rpassword::prompt_password(format!("Confirm {}: ", prompt.to_lowercase()))? != passphrase
+    {
+        anyhow::bail!("Passphrases do not match");
+    }
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase must not be empty");
+    }
+
+    Ok(passphrase)
+}
+
+pub fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<EncryptedData> {
+    let rng = SystemRandom::new();
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    rng.fill(&mut salt)
+        .and_then(|_| rng.fill(&mut nonce))
+        .map_err(|_| anyhow::anyhow!("Failed to generate random bytes"))?;
+
+    let key = derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+
+    let mut data = plaintext.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+
+    Ok(EncryptedData {
+        kdf: KDF_PBKDF2_SHA256.into(),
+        iterations: PBKDF2_ITERATIONS,
+        cipher: CIPHER_AES_256_GCM.into(),
+        salt: base64::encode(salt),
+        nonce: base64::encode(nonce),
+        ciphertext: base64::encode(data),
+    })
+}
+
+pub fn decrypt(encrypted: &EncryptedData, passphrase: &str) -> Result<Vec<u8>> {
+    if encrypted.kdf != KDF_PBKDF2_SHA256 || encrypted.cipher != CIPHER_AES_256_GCM {
+        anyhow::bail!(
+            "Unsupported encryption: {} with {}",
+            encrypted.cipher,
+            encrypted.kdf
+        );
+    }
+
+    let salt = base64::decode(&encrypted.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&base64::decode(&encrypted.nonce)?)
+        .map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
+    let mut data = base64::decode(&encrypted.ciphertext)?;
+
+    let key = derive_key(passphrase, &salt, encrypted.iterations)?;
+    let plaintext = key
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase or corrupted data"))?;
+
+    Ok(plaintext.to_vec())
+}
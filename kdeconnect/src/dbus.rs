@@ -0,0 +1,1020 @@
+//! D-Bus service compatible with the one of KDE Connect, so that its applets, scripts and
+//! `kdeconnect-cli` can drive the daemon on desktops with a session bus.
+//!
+//! The service is `org.kde.kdeconnect` on the session bus, found through
+//! `DBUS_SESSION_BUS_ADDRESS`. Objects:
+//!
+//! - `/modules/kdeconnect`: `org.kde.kdeconnect.daemon`, the list of devices.
+//! - `/modules/kdeconnect/devices/<id>`: `org.kde.kdeconnect.device`, one per known device.
+//! - `.../<id>/ping`, `.../<id>/battery`, `.../<id>/share`, `.../<id>/mprisremote` and
+//!   `.../<id>/notifications`, for plugins included in the build.
+//! - `.../<id>/notifications/<public id>`: one per notification shown on the device.
+//!
+//! Only the members used by common clients are implemented. Methods fail if the plugin is
+//! not enabled for the device, or the device is offline.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock as SyncRwLock},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, RwLock};
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder, SignalContext};
+
+use crate::{context::AppContextRef, device::DeviceEvent, utils};
+
+const SERVICE_NAME: &str = "org.kde.kdeconnect";
+const DAEMON_PATH: &str = "/modules/kdeconnect";
+
+/// Values of `pairState`, as in KDE Connect.
+const PAIR_STATE_NOT_PAIRED: i32 = 0;
+const PAIR_STATE_PAIRED: i32 = 3;
+
+fn failed(e: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{:#}", e))
+}
+
+/// Object path of a device, with its ID as the last element like in KDE Connect.
+///
+/// IDs come from remote devices and may contain characters that are not allowed in object
+/// paths. These are replaced, and a hash of the ID is appended so that two devices never get
+/// the same path.
+fn device_path(device_id: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if !device_id.is_empty() && device_id.chars().all(is_safe) {
+        return format!("{}/devices/{}", DAEMON_PATH, device_id);
+    }
+
+    let id = device_id
+        .chars()
+        .map(|c| if is_safe(c) { c } else { '_' })
+        .collect::<String>();
+    format!(
+        "{}/devices/{}_{:x}",
+        DAEMON_PATH,
+        id,
+        md5::compute(device_id)
+    )
+}
+
+/// Object name of a notification, its ID is chosen by the device and can be anything.
+fn notification_public_id(id: &str) -> String {
+    format!("n{:x}", md5::compute(id))
+}
+
+async fn call_plugin(
+    ctx: &AppContextRef,
+    device_id: &str,
+    plugin: &str,
+    method: &str,
+    params: Value,
+) -> fdo::Result<Value> {
+    ctx.device_manager
+        .call_plugin(device_id, plugin, method, params)
+        .await
+        .map_err(failed)
+}
+
+/// State of a device as the daemon knows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DeviceState {
+    name: String,
+    device_type: String,
+    connected: bool,
+    paired: bool,
+}
+
+/// State of all known devices, kept up to date from device events by [`Service`] so that
+/// reading a property doesn't need a round trip to the device manager.
+#[derive(Debug, Clone, Default)]
+struct DeviceStates(Arc<SyncRwLock<HashMap<String, DeviceState>>>);
+
+impl DeviceStates {
+    fn get(&self, id: &str) -> fdo::Result<DeviceState> {
+        self.0
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| fdo::Error::Failed(format!("Unknown device: {}", id)))
+    }
+
+    fn filter(&self, only_reachable: bool, only_paired: bool) -> HashMap<String, DeviceState> {
+        let mut devices = self.0.read().unwrap().clone();
+        devices.retain(|_, d| (!only_reachable || d.connected) && (!only_paired || d.paired));
+        devices
+    }
+
+    fn insert(&self, id: &str, state: DeviceState) {
+        self.0.write().unwrap().insert(id.to_string(), state);
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut DeviceState)) {
+        if let Some(state) = self.0.write().unwrap().get_mut(id) {
+            f(state);
+        }
+    }
+
+    /// Replace the state of all devices with the one of the device manager.
+    async fn load(&self, ctx: &AppContextRef) -> Result<Vec<String>> {
+        let trusted = ctx.trusted_devices.all();
+        let devices = ctx
+            .device_manager
+            .list_devices()
+            .await?
+            .into_iter()
+            .map(|d| {
+                let state = DeviceState {
+                    paired: trusted.contains_key(&d.id),
+                    name: d.name,
+                    device_type: d.device_type,
+                    connected: d.connected,
+                };
+                (d.id, state)
+            })
+            .collect::<HashMap<_, _>>();
+        let ids = devices.keys().cloned().collect();
+        *self.0.write().unwrap() = devices;
+        Ok(ids)
+    }
+}
+
+struct Daemon {
+    devices: DeviceStates,
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.daemon")]
+impl Daemon {
+    /// IDs of known devices.
+    #[dbus_interface(name = "devices")]
+    async fn devices(&self, only_reachable: bool, only_paired: bool) -> fdo::Result<Vec<String>> {
+        let mut ids = self
+            .devices
+            .filter(only_reachable, only_paired)
+            .into_keys()
+            .collect::<Vec<_>>();
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Names of known devices, keyed by ID.
+    #[dbus_interface(name = "deviceNames")]
+    async fn device_names(
+        &self,
+        only_reachable: bool,
+        only_paired: bool,
+    ) -> fdo::Result<HashMap<String, String>> {
+        Ok(self
+            .devices
+            .filter(only_reachable, only_paired)
+            .into_iter()
+            .map(|(id, d)| (id, d.name))
+            .collect())
+    }
+
+    #[dbus_interface(signal, name = "deviceAdded")]
+    async fn device_added(ctxt: &SignalContext<'_>, id: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "deviceVisibilityChanged")]
+    async fn device_visibility_changed(
+        ctxt: &SignalContext<'_>,
+        id: &str,
+        is_visible: bool,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "deviceListChanged")]
+    async fn device_list_changed(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+struct Device {
+    ctx: AppContextRef,
+    devices: DeviceStates,
+    id: String,
+}
+
+impl Device {
+    fn state(&self) -> fdo::Result<DeviceState> {
+        self.devices.get(&self.id)
+    }
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device")]
+impl Device {
+    #[dbus_interface(property, name = "id")]
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[dbus_interface(property, name = "name")]
+    fn name(&self) -> fdo::Result<String> {
+        Ok(self.state()?.name)
+    }
+
+    #[dbus_interface(property, name = "type")]
+    fn device_type(&self) -> fdo::Result<String> {
+        Ok(self.state()?.device_type)
+    }
+
+    #[dbus_interface(property, name = "isReachable")]
+    fn is_reachable(&self) -> fdo::Result<bool> {
+        Ok(self.state()?.connected)
+    }
+
+    #[dbus_interface(property, name = "isPaired")]
+    fn is_paired(&self) -> fdo::Result<bool> {
+        Ok(self.state()?.paired)
+    }
+
+    #[dbus_interface(property, name = "pairState")]
+    fn pair_state(&self) -> fdo::Result<i32> {
+        Ok(match self.state()?.paired {
+            true => PAIR_STATE_PAIRED,
+            false => PAIR_STATE_NOT_PAIRED,
+        })
+    }
+
+    #[dbus_interface(name = "requestPairing")]
+    async fn request_pairing(&self) -> fdo::Result<()> {
+        self.ctx
+            .request_pair(&self.id)
+            .await
+            .map_err(|e| failed(e.into()))
+    }
+
+    #[dbus_interface(name = "unpair")]
+    async fn unpair(&self) -> fdo::Result<()> {
+        self.ctx.unpair(&self.id).await.map_err(failed)
+    }
+
+    #[dbus_interface(signal, name = "reachableChanged")]
+    async fn reachable_changed(ctxt: &SignalContext<'_>, reachable: bool) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "pairStateChanged")]
+    async fn pair_state_changed_signal(ctxt: &SignalContext<'_>, state: i32) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "nameChanged")]
+    async fn name_changed_signal(ctxt: &SignalContext<'_>, name: &str) -> zbus::Result<()>;
+}
+
+struct Ping {
+    ctx: AppContextRef,
+    id: String,
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device.ping")]
+impl Ping {
+    #[dbus_interface(name = "sendPing")]
+    async fn send_ping(&self) -> fdo::Result<()> {
+        call_plugin(&self.ctx, &self.id, "ping", "ping", json!({})).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatteryStatus {
+    charge: i32,
+    is_charging: bool,
+}
+
+struct Battery {
+    ctx: AppContextRef,
+    id: String,
+}
+
+impl Battery {
+    /// Last known status, without asking the device.
+    async fn status(&self) -> fdo::Result<Option<BatteryStatus>> {
+        let status = call_plugin(&self.ctx, &self.id, "battery", "status", json!({})).await?;
+        serde_json::from_value(status).map_err(|e| failed(e.into()))
+    }
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device.battery")]
+impl Battery {
+    /// Charge in percent, or -1 if unknown.
+    #[dbus_interface(property, name = "charge")]
+    async fn charge(&self) -> fdo::Result<i32> {
+        Ok(self.status().await?.map_or(-1, |s| s.charge))
+    }
+
+    #[dbus_interface(property, name = "isCharging")]
+    async fn is_charging(&self) -> fdo::Result<bool> {
+        Ok(self.status().await?.is_some_and(|s| s.is_charging))
+    }
+
+    #[dbus_interface(signal, name = "refreshed")]
+    async fn refreshed(
+        ctxt: &SignalContext<'_>,
+        is_charging: bool,
+        charge: i32,
+    ) -> zbus::Result<()>;
+}
+
+struct Share {
+    ctx: AppContextRef,
+    id: String,
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device.share")]
+impl Share {
+    /// File URLs are sent as files.
+    #[dbus_interface(name = "shareUrl")]
+    async fn share_url(&self, url: String) -> fdo::Result<()> {
+        call_plugin(&self.ctx, &self.id, "share", "share", json!({ "url": url })).await?;
+        Ok(())
+    }
+
+    /// File URLs are sent as one batch of files.
+    #[dbus_interface(name = "shareUrls")]
+    async fn share_urls(&self, urls: Vec<String>) -> fdo::Result<()> {
+        let (files, urls): (Vec<_>, Vec<_>) =
+            urls.into_iter().partition(|url| url.starts_with("file:"));
+        let paths = files
+            .iter()
+            .map(|file| {
+                url::Url::parse(file)
+                    .ok()
+                    .and_then(|url| url.to_file_path().ok())
+                    .ok_or_else(|| fdo::Error::InvalidArgs(format!("Not a local file: {}", file)))
+            })
+            .collect::<fdo::Result<Vec<_>>>()?;
+
+        for url in urls {
+            call_plugin(&self.ctx, &self.id, "share", "share", json!({ "url": url })).await?;
+        }
+        if !paths.is_empty() {
+            call_plugin(
+                &self.ctx,
+                &self.id,
+                "share",
+                "share",
+                json!({ "paths": paths }),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[dbus_interface(name = "shareText")]
+    async fn share_text(&self, text: String) -> fdo::Result<()> {
+        call_plugin(
+            &self.ctx,
+            &self.id,
+            "share",
+            "share",
+            json!({ "text": text }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaPlayer {
+    id: String,
+    #[serde(default)]
+    is_playing: bool,
+    #[serde(default)]
+    now_playing: Option<String>,
+}
+
+struct MprisRemote {
+    ctx: AppContextRef,
+    id: String,
+    /// The player controlled by `sendAction`, chosen by the client.
+    player: RwLock<String>,
+}
+
+impl MprisRemote {
+    async fn players(&self) -> fdo::Result<Vec<MediaPlayer>> {
+        let players =
+            call_plugin(&self.ctx, &self.id, "mpris-remote", "players", json!({})).await?;
+        serde_json::from_value(players).map_err(|e| failed(e.into()))
+    }
+
+    /// The chosen player, or the first one if it's gone.
+    async fn current(&self) -> fdo::Result<Option<MediaPlayer>> {
+        let mut players = self.players().await?;
+        let chosen = self.player.read().await;
+        Ok(match players.iter().position(|p| p.id == *chosen) {
+            Some(i) => Some(players.swap_remove(i)),
+            None => players.into_iter().next(),
+        })
+    }
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device.mprisremote")]
+impl MprisRemote {
+    #[dbus_interface(property, name = "playerList")]
+    async fn player_list(&self) -> fdo::Result<Vec<String>> {
+        Ok(self.players().await?.into_iter().map(|p| p.id).collect())
+    }
+
+    #[dbus_interface(property, name = "player")]
+    async fn player(&self) -> fdo::Result<String> {
+        Ok(self.current().await?.map(|p| p.id).unwrap_or_default())
+    }
+
+    #[dbus_interface(property, name = "player")]
+    async fn set_player(&self, player: String) {
+        *self.player.write().await = player;
+    }
+
+    #[dbus_interface(property, name = "isPlaying")]
+    async fn is_playing(&self) -> fdo::Result<bool> {
+        Ok(self.current().await?.is_some_and(|p| p.is_playing))
+    }
+
+    #[dbus_interface(property, name = "title")]
+    async fn title(&self) -> fdo::Result<String> {
+        Ok(self
+            .current()
+            .await?
+            .and_then(|p| p.now_playing)
+            .unwrap_or_default())
+    }
+
+    /// An action like `PlayPause` for the current player.
+    #[dbus_interface(name = "sendAction")]
+    async fn send_action(&self, action: String) -> fdo::Result<()> {
+        let player = self
+            .current()
+            .await?
+            .ok_or_else(|| fdo::Error::Failed("No player".into()))?;
+        let params = json!({ "player": player.id, "action": action });
+        call_plugin(&self.ctx, &self.id, "mpris-remote", "action", params).await?;
+        Ok(())
+    }
+}
+
+/// A notification as listed by the notification-receive plugin.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NotificationInfo {
+    id: String,
+    app_name: String,
+    is_clearable: bool,
+    #[serde(default)]
+    ticker: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+struct Notifications {
+    ctx: AppContextRef,
+    id: String,
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device.notifications")]
+impl Notifications {
+    /// Public IDs of the notifications shown on the device.
+    #[dbus_interface(name = "activeNotifications")]
+    async fn active_notifications(&self) -> fdo::Result<Vec<String>> {
+        Ok(list_notifications(&self.ctx, &self.id)
+            .await
+            .map_err(failed)?
+            .iter()
+            .map(|n| notification_public_id(&n.id))
+            .collect())
+    }
+
+    #[dbus_interface(signal, name = "notificationPosted")]
+    async fn notification_posted(ctxt: &SignalContext<'_>, public_id: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "notificationRemoved")]
+    async fn notification_removed(ctxt: &SignalContext<'_>, public_id: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal, name = "allNotificationsRemoved")]
+    async fn all_notifications_removed(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+struct Notification {
+    ctx: AppContextRef,
+    device_id: String,
+    info: NotificationInfo,
+}
+
+#[dbus_interface(name = "org.kde.kdeconnect.device.notifications.notification")]
+impl Notification {
+    #[dbus_interface(property, name = "internalId")]
+    fn internal_id(&self) -> String {
+        self.info.id.clone()
+    }
+
+    #[dbus_interface(property, name = "appName")]
+    fn app_name(&self) -> String {
+        self.info.app_name.clone()
+    }
+
+    #[dbus_interface(property, name = "ticker")]
+    fn ticker(&self) -> String {
+        self.info.ticker.clone().unwrap_or_default()
+    }
+
+    #[dbus_interface(property, name = "title")]
+    fn title(&self) -> String {
+        self.info.title.clone().unwrap_or_default()
+    }
+
+    #[dbus_interface(property, name = "text")]
+    fn text(&self) -> String {
+        self.info.text.clone().unwrap_or_default()
+    }
+
+    #[dbus_interface(property, name = "dismissable")]
+    fn dismissable(&self) -> bool {
+        self.info.is_clearable
+    }
+
+    #[dbus_interface(name = "dismiss")]
+    async fn dismiss(&self) -> fdo::Result<()> {
+        let params = json!({ "id": self.info.id });
+        call_plugin(
+            &self.ctx,
+            &self.device_id,
+            "notification-receive",
+            "dismiss",
+            params,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+async fn list_notifications(ctx: &AppContextRef, device_id: &str) -> Result<Vec<NotificationInfo>> {
+    let list = ctx
+        .device_manager
+        .call_plugin(device_id, "notification-receive", "list", json!({}))
+        .await?;
+    Ok(serde_json::from_value(list)?)
+}
+
+/// Keeps the objects on the bus in sync with the devices of the daemon.
+struct Service {
+    ctx: AppContextRef,
+    conn: Connection,
+    states: DeviceStates,
+    /// Devices with objects on the bus.
+    devices: HashSet<String>,
+    /// Public IDs of the notifications with an object, by device ID.
+    notifications: HashMap<String, HashSet<String>>,
+}
+
+impl Service {
+    async fn add_device(&mut self, id: &str) -> Result<()> {
+        if self.devices.contains(id) {
+            return Ok(());
+        }
+
+        let path = device_path(id);
+        let server = self.conn.object_server();
+        // A device may have chosen its ID to match the path of another one.
+        if server.interface::<_, Device>(path.as_str()).await.is_ok() {
+            anyhow::bail!(
+                "Device {} has the object path of another device: {}",
+                id,
+                path
+            );
+        }
+        self.devices.insert(id.to_string());
+
+        let ctx = || self.ctx.clone();
+
+        let device = Device {
+            ctx: ctx(),
+            devices: self.states.clone(),
+            id: id.to_string(),
+        };
+        server.at(path.as_str(), device).await?;
+        if cfg!(feature = "plugin-ping") {
+            let ping = Ping {
+                ctx: ctx(),
+                id: id.to_string(),
+            };
+            server.at(format!("{}/ping", path), ping).await?;
+        }
+        if cfg!(feature = "plugin-battery") {
+            let battery = Battery {
+                ctx: ctx(),
+                id: id.to_string(),
+            };
+            server.at(format!("{}/battery", path), battery).await?;
+        }
+        if cfg!(feature = "plugin-share") {
+            let share = Share {
+                ctx: ctx(),
+                id: id.to_string(),
+            };
+            server.at(format!("{}/share", path), share).await?;
+        }
+        if cfg!(feature = "plugin-mpris-remote") {
+            let remote = MprisRemote {
+                ctx: ctx(),
+                id: id.to_string(),
+                player: RwLock::new(String::new()),
+            };
+            server.at(format!("{}/mprisremote", path), remote).await?;
+        }
+        if cfg!(feature = "plugin-notification-receive") {
+            let notifications = Notifications {
+                ctx: ctx(),
+                id: id.to_string(),
+            };
+            server
+                .at(format!("{}/notifications", path), notifications)
+                .await?;
+        }
+
+        let ctxt = SignalContext::new(&self.conn, DAEMON_PATH)?;
+        Daemon::device_added(&ctxt, id).await?;
+        Daemon::device_list_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    async fn set_reachable(&self, id: &str, reachable: bool) -> Result<()> {
+        self.states.update(id, |d| d.connected = reachable);
+        let daemon = SignalContext::new(&self.conn, DAEMON_PATH)?;
+        Daemon::device_visibility_changed(&daemon, id, reachable).await?;
+
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, Device>(device_path(id))
+            .await?;
+        Device::reachable_changed(iface.signal_context(), reachable).await?;
+        iface
+            .get()
+            .await
+            .is_reachable_changed(iface.signal_context())
+            .await?;
+        Ok(())
+    }
+
+    async fn set_paired(&self, id: &str, paired: bool) -> Result<()> {
+        self.states.update(id, |d| d.paired = paired);
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, Device>(device_path(id))
+            .await?;
+        let state = match paired {
+            true => PAIR_STATE_PAIRED,
+            false => PAIR_STATE_NOT_PAIRED,
+        };
+        Device::pair_state_changed_signal(iface.signal_context(), state).await?;
+        let dev = iface.get().await;
+        dev.is_paired_changed(iface.signal_context()).await?;
+        dev.pair_state_changed(iface.signal_context()).await?;
+        Ok(())
+    }
+
+    async fn set_name(&self, id: &str, name: &str) -> Result<()> {
+        self.states.update(id, |d| d.name = name.to_string());
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, Device>(device_path(id))
+            .await?;
+        Device::name_changed_signal(iface.signal_context(), name).await?;
+        iface
+            .get()
+            .await
+            .name_changed(iface.signal_context())
+            .await?;
+        Ok(())
+    }
+
+    async fn plugin_changed(&mut self, id: &str, plugin: &str) -> Result<()> {
+        let path = device_path(id);
+        match plugin {
+            "battery" if cfg!(feature = "plugin-battery") => {
+                let iface = self
+                    .conn
+                    .object_server()
+                    .interface::<_, Battery>(format!("{}/battery", path))
+                    .await?;
+                let battery = iface.get().await;
+                if let Some(status) = battery.status().await? {
+                    Battery::refreshed(iface.signal_context(), status.is_charging, status.charge)
+                        .await?;
+                }
+                battery.charge_changed(iface.signal_context()).await?;
+                battery.is_charging_changed(iface.signal_context()).await?;
+            }
+            "mpris-remote" if cfg!(feature = "plugin-mpris-remote") => {
+                let iface = self
+                    .conn
+                    .object_server()
+                    .interface::<_, MprisRemote>(format!("{}/mprisremote", path))
+                    .await?;
+                let remote = iface.get().await;
+                remote.player_list_changed(iface.signal_context()).await?;
+                remote.player_changed(iface.signal_context()).await?;
+                remote.is_playing_changed(iface.signal_context()).await?;
+                remote.title_changed(iface.signal_context()).await?;
+            }
+            "notification-receive" if cfg!(feature = "plugin-notification-receive") => {
+                self.sync_notifications(id).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Add and remove notification objects to match the ones shown on the device.
+    async fn sync_notifications(&mut self, id: &str) -> Result<()> {
+        let path = format!("{}/notifications", device_path(id));
+        let server = self.conn.object_server();
+        let ctxt = SignalContext::new(&self.conn, path.as_str())?;
+
+        // Fails if the plugin is disabled, then there are no notifications.
+        let list = list_notifications(&self.ctx, id).await.unwrap_or_default();
+        let current = list
+            .iter()
+            .map(|n| notification_public_id(&n.id))
+            .collect::<HashSet<_>>();
+        let known = self.notifications.entry(id.to_string()).or_default();
+
+        for public_id in known.difference(&current) {
+            server
+                .remove::<Notification, _>(format!("{}/{}", path, public_id))
+                .await?;
+            Notifications::notification_removed(&ctxt, public_id).await?;
+        }
+        if current.is_empty() && !known.is_empty() {
+            Notifications::all_notifications_removed(&ctxt).await?;
+        }
+
+        for info in list {
+            let public_id = notification_public_id(&info.id);
+            let object_path = format!("{}/{}", path, public_id);
+            // Updated notifications are replaced.
+            let updated = known.contains(&public_id);
+            if updated {
+                server
+                    .remove::<Notification, _>(object_path.as_str())
+                    .await?;
+            }
+            let notification = Notification {
+                ctx: self.ctx.clone(),
+                device_id: id.to_string(),
+                info,
+            };
+            server.at(object_path, notification).await?;
+            if !updated {
+                Notifications::notification_posted(&ctxt, &public_id).await?;
+            }
+        }
+
+        *known = current;
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: DeviceEvent) -> Result<()> {
+        let is_connected = matches!(event, DeviceEvent::Connected { .. });
+        if !is_connected && !self.devices.contains(event.device_id()) {
+            // Its objects couldn't be added.
+            return Ok(());
+        }
+
+        match event {
+            DeviceEvent::Connected {
+                id,
+                name,
+                device_type,
+                ..
+            } => {
+                let state = DeviceState {
+                    name,
+                    device_type,
+                    connected: true,
+                    paired: self.ctx.trusted_devices.is_trusted(&id),
+                };
+                self.states.insert(&id, state);
+                self.add_device(&id).await?;
+                self.set_reachable(&id, true).await?;
+            }
+            DeviceEvent::Disconnected { id } => self.set_reachable(&id, false).await?,
+            DeviceEvent::Paired { id } => self.set_paired(&id, true).await?,
+            DeviceEvent::Unpaired { id } => self.set_paired(&id, false).await?,
+            DeviceEvent::Renamed { id, name } => self.set_name(&id, &name).await?,
+            DeviceEvent::PluginChanged { id, plugin } => self.plugin_changed(&id, &plugin).await?,
+        }
+        Ok(())
+    }
+
+    /// Reload the state of all devices, after some events were missed.
+    async fn reload(&mut self) -> Result<()> {
+        for id in self.states.load(&self.ctx).await? {
+            self.add_device(&id).await?;
+        }
+        let ctxt = SignalContext::new(&self.conn, DAEMON_PATH)?;
+        Daemon::device_list_changed(&ctxt).await?;
+        Ok(())
+    }
+}
+
+/// Export the daemon on the session bus, until the connection is lost.
+pub async fn serve(ctx: AppContextRef) -> Result<()> {
+    // Subscribe before listing devices so that no change is missed.
+    let mut events = ctx.device_manager.subscribe();
+    let states = DeviceStates::default();
+    let ids = states.load(&ctx).await?;
+
+    let daemon = Daemon {
+        devices: states.clone(),
+    };
+    let conn = ConnectionBuilder::session()?
+        .name(SERVICE_NAME)?
+        .serve_at(DAEMON_PATH, daemon)?
+        .build()
+        .await
+        .context("Connect to the session bus")?;
+    log::info!("D-Bus service {} started", SERVICE_NAME);
+
+    let mut service = Service {
+        ctx: ctx.clone(),
+        conn,
+        states,
+        devices: HashSet::new(),
+        notifications: HashMap::new(),
+    };
+    for id in ids {
+        service.add_device(&id).await?;
+        if service.states.get(&id).is_ok_and(|d| d.connected) {
+            service.sync_notifications(&id).await?;
+        }
+    }
+
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let result = service.handle_event(event).await;
+                utils::log_if_error("Failed to update D-Bus objects", result);
+            }
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                log::warn!("D-Bus service missed {} device events", count);
+                let result = service.reload().await;
+                utils::log_if_error("Failed to reload D-Bus objects", result);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    use super::*;
+
+    /// A private session bus, stopped when dropped.
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Start a bus, `dbus-daemon` must be installed.
+    fn start_bus() -> Bus {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Start dbus-daemon, is it installed?");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+        Bus { daemon, address }
+    }
+
+    fn state(name: &str, connected: bool, paired: bool) -> DeviceState {
+        DeviceState {
+            name: name.to_string(),
+            device_type: "phone".to_string(),
+            connected,
+            paired,
+        }
+    }
+
+    #[test]
+    fn states_follow_updates() {
+        let states = DeviceStates::default();
+        states.insert("a", state("Phone", true, false));
+        states.update("a", |d| d.paired = true);
+        states.update("b", |d| d.paired = true);
+
+        assert_eq!(states.get("a").unwrap(), state("Phone", true, true));
+        assert!(states.get("b").is_err());
+    }
+
+    #[test]
+    fn device_paths_are_unique_and_valid() {
+        assert_eq!(
+            device_path("_a8f2c1e0_4b3d_"),
+            "/modules/kdeconnect/devices/_a8f2c1e0_4b3d_"
+        );
+
+        let paths = [
+            "a8f2c1e0_4b3d",
+            "a8f2c1e0-4b3d",
+            "a8f2c1e0.4b3d",
+            "Ä.b/c d",
+            "",
+        ]
+        .map(device_path);
+        for (i, path) in paths.iter().enumerate() {
+            assert!(
+                zbus::zvariant::ObjectPath::try_from(path.as_str()).is_ok(),
+                "{}",
+                path
+            );
+            assert!(!paths[..i].contains(path), "{}", path);
+        }
+        assert_ne!(
+            notification_public_id("0|a|1"),
+            notification_public_id("0|a|2")
+        );
+    }
+
+    #[tokio::test]
+    async fn devices_are_listed_from_states() {
+        let bus = start_bus();
+        let states = DeviceStates::default();
+        states.insert("a", state("Phone", true, true));
+        states.insert("b", state("Tablet", true, false));
+        states.insert("c", state("Laptop", false, true));
+        let daemon = Daemon {
+            devices: states.clone(),
+        };
+        let _service = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .name(SERVICE_NAME)
+            .unwrap()
+            .serve_at(DAEMON_PATH, daemon)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let client = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let devices = |only_reachable: bool, only_paired: bool| {
+            let client = client.clone();
+            async move {
+                let reply = client
+                    .call_method(
+                        Some(SERVICE_NAME),
+                        DAEMON_PATH,
+                        Some("org.kde.kdeconnect.daemon"),
+                        "devices",
+                        &(only_reachable, only_paired),
+                    )
+                    .await
+                    .unwrap();
+                reply.body::<Vec<String>>().unwrap()
+            }
+        };
+        assert_eq!(devices(false, false).await, ["a", "b", "c"]);
+        assert_eq!(devices(true, false).await, ["a", "b"]);
+        assert_eq!(devices(false, true).await, ["a", "c"]);
+        assert_eq!(devices(true, true).await, ["a"]);
+
+        // Changes are seen without restarting the service.
+        states.update("c", |d| d.connected = true);
+        assert_eq!(devices(true, true).await, ["a", "c"]);
+
+        let reply = client
+            .call_method(
+                Some(SERVICE_NAME),
+                DAEMON_PATH,
+                Some("org.kde.kdeconnect.daemon"),
+                "deviceNames",
+                &(false, true),
+            )
+            .await
+            .unwrap();
+        let names = reply.body::<HashMap<String, String>>().unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names["a"], "Phone");
+        assert_eq!(names["c"], "Laptop");
+    }
+}
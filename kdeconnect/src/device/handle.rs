@@ -1,15 +1,25 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::oneshot;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::oneshot};
+use tokio_rustls::client::TlsStream;
 
-use crate::packet::{NetworkPacket, NetworkPacketWithPayload};
+use crate::{
+    packet::{NetworkPacket, NetworkPacketWithPayload},
+    store::{self, PluginStore},
+};
 
-use super::{DeviceManagerHandle, Message};
+use super::{
+    manager::PacketWaiter, DeviceEvent, DeviceManagerHandle, Message, RequestError, SendError,
+    SendOptions,
+};
 
 #[derive(Clone)]
 pub struct DeviceHandle {
     pub(super) device_id: Arc<String>,
-    pub(super) device_name: Arc<String>,
+    pub(super) device_name: Arc<RwLock<String>>,
     pub(super) manager_handle: DeviceManagerHandle,
 }
 
@@ -26,15 +36,82 @@ impl DeviceHandle {
         &self.device_id
     }
 
-    pub fn device_name(&self) -> &str {
-        &self.device_name
+    /// Name of the device, updated when it connects again with a different one.
+    pub fn device_name(&self) -> String {
+        self.device_name.read().unwrap().clone()
     }
 
-    /// Send packet to device
-    pub async fn send_packet(&self, packet: impl Into<NetworkPacketWithPayload>) {
+    pub(super) fn set_device_name(&self, name: &str) {
+        *self.device_name.write().unwrap() = name.to_string();
+    }
+
+    /// Persistent values of a plugin for this device, `plugin` is the name used in settings.
+    pub fn store(&self, plugin: &str) -> Result<PluginStore> {
+        store::open(self.device_id(), plugin)
+    }
+
+    /// Tell subscribers that the state of a plugin has changed, `plugin` is the name used in
+    /// settings.
+    pub fn plugin_changed(&self, plugin: &str) {
+        self.manager_handle.send_event(DeviceEvent::PluginChanged {
+            id: self.device_id.to_string(),
+            plugin: plugin.to_string(),
+        });
+    }
+
+    /// Send packet to device, returning once it's written to the connection.
+    pub async fn send_packet(
+        &self,
+        packet: impl Into<NetworkPacketWithPayload>,
+    ) -> Result<(), SendError> {
+        self.send_packet_with(packet, SendOptions::default()).await
+    }
+
+    /// Send packet to device with a custom priority, or keep it until the device connects
+    /// again if it's offline.
+    ///
+    /// A buffered packet is only reported as failed once it expires.
+    pub async fn send_packet_with(
+        &self,
+        packet: impl Into<NetworkPacketWithPayload>,
+        options: SendOptions,
+    ) -> Result<(), SendError> {
         self.manager_handle
-            .send_packet(self.device_id(), packet)
+            .send_packet(self.device_id(), packet, options)
+            .await
+    }
+
+    /// Send a packet and wait for the next packet of type `response_type` that matches
+    /// `predicate`, e.g. `kdeconnect.battery.request` and then `kdeconnect.battery`.
+    ///
+    /// The response is also handled by plugins as usual. `timeout` covers both sending the
+    /// request and waiting for the response.
+    pub async fn request(
+        &self,
+        packet: impl Into<NetworkPacketWithPayload>,
+        response_type: &str,
+        predicate: impl Fn(&NetworkPacket) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> Result<NetworkPacket, RequestError> {
+        let (tx, rx) = oneshot::channel();
+
+        // Wait before sending, so that a quick response is not missed.
+        self.manager_handle
+            .send_message(Message::AwaitPacket {
+                device_id: self.device_id.to_string(),
+                waiter: PacketWaiter::new(response_type, predicate, tx),
+            })
             .await;
+
+        let exchange = async {
+            self.send_packet(packet).await?;
+            // The waiter is dropped if the device disconnects.
+            rx.await
+                .map_err(|_| RequestError::from(SendError::LinkClosed))
+        };
+        tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| RequestError::Timeout(timeout))?
     }
 
     /// Dispatch received packet from the device to plugins
@@ -61,4 +138,19 @@ impl DeviceHandle {
 
         rx.await?
     }
+
+    /// Connect to a payload, for payloads too large to fetch into memory.
+    pub async fn open_payload(&self, port: u16) -> Result<TlsStream<TcpStream>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.manager_handle
+            .send_message(Message::OpenPayload {
+                device_id: self.device_id.to_string(),
+                port,
+                reply: tx,
+            })
+            .await;
+
+        rx.await?
+    }
 }
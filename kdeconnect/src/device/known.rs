@@ -0,0 +1,89 @@
+//! Devices we have seen before, persisted so that they are still listed after a restart.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KnownDevice {
+    pub name: String,
+    pub device_type: String,
+    pub last_ip: IpAddr,
+    /// Milliseconds since the Unix epoch.
+    pub last_seen: u64,
+    /// Plugin state saved on disconnect, keyed by plugin name.
+    #[serde(default)]
+    pub plugin_state: HashMap<String, serde_json::Value>,
+}
+
+impl KnownDevice {
+    pub fn last_seen_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.last_seen)
+    }
+}
+
+pub type KnownDevices = HashMap<String, KnownDevice>;
+
+/// Loads known devices from a file, starting with none if it doesn't exist.
+pub fn load(path: impl AsRef<Path>) -> Result<KnownDevices> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(KnownDevices::new());
+    }
+
+    let f = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(f)?)
+}
+
+/// Write to a temporary file first, then replace the known devices with it.
+pub async fn save(path: &Path, devices: &KnownDevices) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(devices)?)
+        .await
+        .with_context(|| format!("Write {:?}", tmp_path))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Replace {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    #[tokio::test]
+    async fn saved_devices_are_loaded() {
+        let path = utils::test_dir("known-devices").join("known_devices.json");
+        assert!(load(&path).unwrap().is_empty());
+
+        let device = KnownDevice {
+            name: "Phone".into(),
+            device_type: "phone".into(),
+            last_ip: [192, 168, 1, 2].into(),
+            last_seen: 1_700_000_000_000,
+            plugin_state: HashMap::from([("battery".into(), serde_json::json!(42))]),
+        };
+        let devices = KnownDevices::from([("id".into(), device)]);
+        save(&path, &devices).await.unwrap();
+        save(&path, &devices).await.unwrap();
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded["id"].name, "Phone");
+        assert_eq!(loaded["id"].last_ip, IpAddr::from([192, 168, 1, 2]));
+        assert_eq!(
+            loaded["id"].last_seen_time(),
+            devices["id"].last_seen_time()
+        );
+        assert_eq!(loaded["id"].plugin_state["battery"], 42);
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+}
@@ -1,29 +1,52 @@
 use anyhow::Result;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     net::IpAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, RwLock,
     },
+    time::{Duration, SystemTime},
 };
-use tao::menu::{ContextMenu, MenuItem, MenuItemAttributes};
-use tracing::{Instrument, Span};
+use tracing::Span;
 
 use tokio::{
     io::AsyncReadExt,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot, watch},
 };
 
 use crate::{
-    context::AppContextRef, device::DeviceHandle, event::SystemEvent,
-    packet::NetworkPacketWithPayload, plugin::PluginRepository, CustomWindowEvent,
+    context::AppContextRef,
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::{NetworkPacket, NetworkPacketWithPayload},
+    paths,
+    plugin::PluginRepository,
+    settings::OutboundSettings,
+    tray::MenuId,
+    utils,
+};
+#[cfg(windows)]
+use crate::{
+    tray::{ContextMenu, MenuItem, MenuItemAttributes},
+    CustomWindowEvent,
 };
 
-use super::Message;
+use super::{
+    known::{self, KnownDevice, KnownDevices},
+    queue::{OutboundQueue, PushError, QueuedPacket, SendOptions},
+    DeviceEvent, Message, SendError,
+};
 
 static NEXT_CONN_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// Events that can be buffered for a subscriber before it starts missing them.
+const EVENT_CAPACITY: usize = 64;
+
+/// How often packets kept for offline devices are checked for expiry.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[cfg(windows)]
 fn load_png_icon(buf: &[u8]) -> tao::system_tray::Icon {
     let (icon_rgba, icon_width, icon_height) = {
         let image = image::load_from_memory(buf).unwrap().into_rgba8();
@@ -34,6 +57,7 @@ fn load_png_icon(buf: &[u8]) -> tao::system_tray::Icon {
     tao::system_tray::Icon::from_rgba(icon_rgba, icon_width, icon_height).unwrap()
 }
 
+#[cfg(windows)]
 lazy_static::lazy_static! {
     static ref ICON_CELLPHONE: tao::system_tray::Icon = {
         load_png_icon(include_bytes!("../icons/cellphone.png"))
@@ -43,12 +67,65 @@ lazy_static::lazy_static! {
     };
 }
 
+lazy_static::lazy_static! {
+    static ref ROTATE_CERT_MENU_ID: MenuId = MenuId::new("identity:rotate-cert");
+    static ref ROTATE_ID_MENU_ID: MenuId = MenuId::new("identity:rotate-id");
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ConnectionId(usize);
 
+/// Someone waiting for a packet from a device, see [`DeviceHandle::request`].
+pub struct PacketWaiter {
+    typ: String,
+    predicate: Box<dyn Fn(&NetworkPacket) -> bool + Send>,
+    reply: oneshot::Sender<NetworkPacket>,
+}
+
+impl std::fmt::Debug for PacketWaiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketWaiter")
+            .field("typ", &self.typ)
+            .finish()
+    }
+}
+
+impl PacketWaiter {
+    pub(super) fn new(
+        typ: &str,
+        predicate: impl Fn(&NetworkPacket) -> bool + Send + 'static,
+        reply: oneshot::Sender<NetworkPacket>,
+    ) -> Self {
+        Self {
+            typ: typ.to_string(),
+            predicate: Box::new(predicate),
+            reply,
+        }
+    }
+
+    fn matches(&self, packet: &NetworkPacket) -> bool {
+        self.typ == packet.typ && (self.predicate)(packet)
+    }
+}
+
+/// A device as shown to the user, whether it's connected or not.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub device_type: String,
+    /// Current IP, or the last one if offline.
+    pub ip: IpAddr,
+    pub connected: bool,
+    pub last_seen: SystemTime,
+    /// Short status from plugins, e.g. the battery level.
+    pub status: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct DeviceManagerHandle {
     sender: mpsc::Sender<(Message, Span)>,
+    events: broadcast::Sender<DeviceEvent>,
     active_device_count: Arc<AtomicUsize>,
 }
 
@@ -57,13 +134,9 @@ impl DeviceManagerHandle {
         &self,
         id: impl Into<String>,
         name: impl Into<String>,
+        device_type: impl Into<String>,
         ip: IpAddr,
-    ) -> Result<(
-        ConnectionId,
-        mpsc::Receiver<NetworkPacketWithPayload>,
-        DeviceHandle,
-    )> {
-        let (tx, rx) = mpsc::channel(1);
+    ) -> Result<(ConnectionId, Arc<OutboundQueue>, DeviceHandle)> {
         let conn_id = ConnectionId(NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed));
 
         let (reply_tx, reply_rx) = oneshot::channel();
@@ -71,20 +144,17 @@ impl DeviceManagerHandle {
         let msg = Message::AddDevice {
             id: id.into(),
             name: name.into(),
+            device_type: device_type.into(),
             ip,
             conn_id,
-            tx,
             reply: reply_tx,
         };
         self.send_message(msg).await;
 
-        Ok((
-            conn_id,
-            rx,
-            reply_rx
-                .await
-                .map_err(|_| anyhow::anyhow!("Failed to get device handle"))?,
-        ))
+        let (queue, dh) = reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get device handle"))?;
+        Ok((conn_id, queue, dh))
     }
 
     pub async fn query_device(&self, id: impl Into<String>) -> Result<bool> {
@@ -102,6 +172,33 @@ impl DeviceManagerHandle {
         Ok(result)
     }
 
+    /// All known devices, connected ones first.
+    pub async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send_message(Message::ListDevices { reply: reply_tx })
+            .await;
+
+        reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get response"))
+    }
+
+    /// Receive device events from now on.
+    ///
+    /// Events are delivered in the order they happened. A subscriber that falls behind by
+    /// more than a few dozen events gets [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn set_paired(&self, id: impl Into<String>, paired: bool) {
+        let msg = Message::SetPaired {
+            id: id.into(),
+            paired,
+        };
+        self.send_message(msg).await;
+    }
+
     pub async fn remove_device(&self, id: impl Into<String>, conn_id: ConnectionId) {
         let msg = Message::RemoveDevice {
             id: id.into(),
@@ -110,6 +207,11 @@ impl DeviceManagerHandle {
         self.send_message(msg).await;
     }
 
+    pub(super) fn send_event(&self, event: DeviceEvent) {
+        // Nobody may be subscribed.
+        let _ = self.events.send(event);
+    }
+
     pub(super) async fn send_message(&self, msg: Message) {
         self.sender
             .send((msg, tracing::Span::current()))
@@ -131,41 +233,297 @@ impl DeviceManagerHandle {
         self.send_message(Message::UpdateTray).await;
     }
 
-    pub async fn send_packet(&self, device_id: &str, packet: impl Into<NetworkPacketWithPayload>) {
+    pub async fn reload_settings(&self) {
+        self.send_message(Message::ReloadSettings).await;
+    }
+
+    pub async fn disconnect_all(&self) {
+        self.send_message(Message::DisconnectAll).await;
+    }
+
+    /// Save the state of all devices and dispose their plugins, once connections are closed.
+    pub async fn shutdown(&self) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send_message(Message::Shutdown { reply: reply_tx })
+            .await;
+        let _ = reply_rx.await;
+    }
+
+    /// Call a method of a plugin of the device, see [`crate::plugin::KdeConnectPlugin::call`].
+    pub async fn call_plugin(
+        &self,
+        device_id: &str,
+        plugin: &str,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let msg = Message::CallPlugin {
+            device_id: device_id.to_string(),
+            plugin: plugin.to_string(),
+            method: method.to_string(),
+            params,
+            reply: reply_tx,
+        };
+        self.send_message(msg).await;
+
+        reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get response"))?
+    }
+
+    pub async fn send_packet(
+        &self,
+        device_id: &str,
+        packet: impl Into<NetworkPacketWithPayload>,
+        options: SendOptions,
+    ) -> Result<(), SendError> {
         let packet: NetworkPacketWithPayload = packet.into();
+        let (delivery_tx, delivery_rx) = oneshot::channel();
 
         let msg = Message::SendPacket {
             device_id: Some(device_id.into()),
             packet,
+            options,
+            delivery: Some(delivery_tx),
         };
         self.send_message(msg).await;
+
+        delivery_rx.await.unwrap_or(Err(SendError::LinkClosed))
     }
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
+struct Connection {
+    conn_id: ConnectionId,
+    /// Never blocks, so that a stalled connection doesn't hold up the manager.
+    queue: Arc<OutboundQueue>,
+}
+
+#[derive(Debug)]
 struct Device {
+    /// Shared with plugins, see [`DeviceHandle::device_name`].
+    handle: DeviceHandle,
     name: String,
+    device_type: String,
+    /// Current IP, or the last one if offline.
     remote_ip: IpAddr,
-    conn_id: ConnectionId,
-    tx: mpsc::Sender<NetworkPacketWithPayload>,
+    /// When the device connected or disconnected.
+    last_seen: SystemTime,
+    /// `None` if the device is offline.
+    connection: Option<Connection>,
+    plugin_repo: Arc<PluginRepository>,
+    /// Connection changes to pass on to plugins.
+    link_tx: mpsc::UnboundedSender<bool>,
+    /// Packets to send when the device connects again, oldest first.
+    offline: VecDeque<QueuedPacket>,
+    /// Requests waiting for a response on the current connection.
+    waiters: Vec<PacketWaiter>,
+}
+
+impl Device {
+    fn new(
+        handle: DeviceHandle,
+        name: String,
+        device_type: String,
+        remote_ip: IpAddr,
+        last_seen: SystemTime,
+        plugin_repo: Arc<PluginRepository>,
+    ) -> Self {
+        // Plugin hooks run in a separate task so that they can use the manager, in the same
+        // order as the connection changes.
+        let (link_tx, mut link_rx) = mpsc::unbounded_channel();
+        let pr = plugin_repo.clone();
+        tokio::spawn(async move {
+            while let Some(connected) = link_rx.recv().await {
+                pr.set_connected(connected).await;
+            }
+        });
+
+        Self {
+            handle,
+            name,
+            device_type,
+            remote_ip,
+            last_seen,
+            connection: None,
+            plugin_repo,
+            link_tx,
+            offline: VecDeque::new(),
+            waiters: vec![],
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    fn set_connection(&mut self, connection: Option<Connection>, outbound: &OutboundSettings) {
+        if let Some(old) = self.connection.take() {
+            // Also for a new connection replacing the old one, so that plugins refresh.
+            let _ = self.link_tx.send(false);
+
+            // Responses can't arrive on another connection.
+            self.waiters.clear();
+
+            // Closing the queue also ends the connection task.
+            for packet in old.queue.close() {
+                if packet.buffer_offline {
+                    self.offline.push_back(packet);
+                } else {
+                    packet.complete(Err(SendError::LinkClosed));
+                }
+            }
+        }
+        if connection.is_some() {
+            let _ = self.link_tx.send(true);
+        }
+        self.connection = connection;
+        self.last_seen = SystemTime::now();
+
+        if self.is_connected() {
+            self.expire_offline(outbound.offline_ttl());
+            for packet in std::mem::take(&mut self.offline) {
+                self.send(packet, outbound);
+            }
+        }
+    }
+
+    /// Drop packets that have been kept for too long, telling their senders that the device
+    /// is offline.
+    fn expire_offline(&mut self, ttl: Duration) {
+        let (expired, kept) = std::mem::take(&mut self.offline)
+            .into_iter()
+            .partition::<Vec<_>, _>(|p| p.is_expired(ttl));
+        self.offline = kept.into();
+
+        for packet in expired {
+            log::debug!(
+                "Dropping expired packet for {}: {:?}",
+                self.name,
+                packet.packet
+            );
+            packet.complete(Err(SendError::DeviceOffline));
+        }
+    }
+
+    fn send(&mut self, packet: QueuedPacket, outbound: &OutboundSettings) {
+        let conn = match &self.connection {
+            Some(conn) => conn,
+            None => return self.buffer(packet, outbound),
+        };
+
+        match conn.queue.push(packet) {
+            Ok(None) => {}
+            Ok(Some(p)) | Err(PushError::Full(p)) => {
+                log::warn!(
+                    "Outbound queue of {} is full, packet dropped: {:?}",
+                    self.name,
+                    p.packet
+                );
+                p.complete(Err(SendError::QueueFull));
+            }
+            Err(PushError::Closed(p)) => self.buffer(p, outbound),
+        }
+    }
+
+    /// Keep a packet for when the device connects again, if the sender asked for it.
+    fn buffer(&mut self, packet: QueuedPacket, outbound: &OutboundSettings) {
+        if !packet.buffer_offline || outbound.offline_ttl_secs == 0 {
+            log::debug!("Device {} is offline, packet dropped", self.name);
+            packet.complete(Err(SendError::DeviceOffline));
+            return;
+        }
+
+        self.expire_offline(outbound.offline_ttl());
+        if self.offline.len() >= outbound.queue_depth {
+            if let Some(p) = self.offline.pop_front() {
+                log::warn!(
+                    "Too many packets for offline device {}, dropped: {:?}",
+                    self.name,
+                    p.packet
+                );
+                p.complete(Err(SendError::QueueFull));
+            }
+        }
+
+        log::debug!(
+            "Device {} is offline, packet kept until it connects",
+            self.name
+        );
+        self.offline.push_back(packet);
+    }
+
+    fn snapshot(&self, id: &str) -> DeviceSnapshot {
+        DeviceSnapshot {
+            id: id.to_string(),
+            name: self.name.clone(),
+            device_type: self.device_type.clone(),
+            remote_ip: self.remote_ip,
+            connected: self.is_connected(),
+            last_seen: self.last_seen,
+            plugin_repo: self.plugin_repo.clone(),
+        }
+    }
+}
+
+/// State of a device at some point, for work that happens outside the actor.
+#[derive(Debug, Clone)]
+struct DeviceSnapshot {
+    id: String,
+    name: String,
+    device_type: String,
+    remote_ip: IpAddr,
+    connected: bool,
+    last_seen: SystemTime,
     plugin_repo: Arc<PluginRepository>,
 }
 
+impl DeviceSnapshot {
+    async fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            device_type: self.device_type.clone(),
+            ip: self.remote_ip,
+            connected: self.connected,
+            last_seen: self.last_seen,
+            status: self.plugin_repo.status().await,
+        }
+    }
+}
+
+/// Snapshot of all devices, connected ones first.
+type Snapshot = Arc<Vec<DeviceSnapshot>>;
+
+/// Owns all devices and routes messages between connections and plugins.
+///
+/// Nothing in the message loop waits on a device or a plugin: packets are queued to
+/// connections (or kept while a device is offline, if asked to), plugins are loaded and
+/// called in their own tasks, and the tray menu and known devices are updated in background
+/// from snapshots.
 pub struct DeviceManagerActor {
     receiver: mpsc::Receiver<(Message, Span)>,
     devices: HashMap<String, Device>,
     active_device_count: Arc<AtomicUsize>,
     handle: DeviceManagerHandle,
+    tray_tx: watch::Sender<Snapshot>,
+    save_tx: watch::Sender<Snapshot>,
+    /// Asks `save_task` to write the latest snapshot and reply once it's done.
+    flush_tx: mpsc::Sender<oneshot::Sender<()>>,
+    flush_rx: Option<mpsc::Receiver<oneshot::Sender<()>>>,
 }
 
 impl DeviceManagerActor {
     pub fn new() -> (Self, DeviceManagerHandle) {
         let (sender, receiver) = mpsc::channel(100);
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
         let active_device_count = Arc::new(AtomicUsize::new(0));
+        let (flush_tx, flush_rx) = mpsc::channel(1);
 
         let handle = DeviceManagerHandle {
             sender,
+            events,
             active_device_count: active_device_count.clone(),
         };
 
@@ -174,93 +532,161 @@ impl DeviceManagerActor {
             devices: HashMap::new(),
             active_device_count,
             handle: handle.clone(),
+            tray_tx: watch::channel(Snapshot::default()).0,
+            save_tx: watch::channel(Snapshot::default()).0,
+            flush_tx,
+            flush_rx: Some(flush_rx),
         };
 
         (actor, handle)
     }
 
-    async fn handle_message(&mut self, msg: Message, ctx: &AppContextRef) {
+    fn handle_message(&mut self, msg: Message, ctx: &AppContextRef) {
         let mut tray_updated = false;
 
         match msg {
             Message::AddDevice {
                 id,
                 name,
+                device_type,
                 ip,
                 conn_id,
-                tx,
                 reply,
             } => {
-                let dh = DeviceHandle {
-                    device_id: Arc::new(id.clone()),
-                    device_name: Arc::new(name.clone()),
-                    manager_handle: self.handle.clone(),
-                };
+                let settings = ctx.settings();
+                let outbound = &settings.outbound;
 
                 log::info!("Adding device: {}", id);
 
-                if let Some(device) = self.devices.get_mut(&id) {
+                let connected = DeviceEvent::Connected {
+                    id: id.clone(),
+                    name: name.clone(),
+                    device_type: device_type.clone(),
+                    ip,
+                };
+                let queue = Arc::new(OutboundQueue::new(outbound.queue_depth));
+                let connection = Some(Connection {
+                    conn_id,
+                    queue: queue.clone(),
+                });
+                let dh = if let Some(device) = self.devices.get_mut(&id) {
+                    if device.name != name {
+                        device.handle.set_device_name(&name);
+                        let _ = self.handle.events.send(DeviceEvent::Renamed {
+                            id: id.clone(),
+                            name: name.clone(),
+                        });
+                    }
+                    device.name = name;
+                    device.device_type = device_type;
                     device.remote_ip = ip;
-                    device.conn_id = conn_id;
-                    device.tx = tx;
+                    device.set_connection(connection, outbound);
+                    device.handle.clone()
                 } else {
-                    let plugin_repo = PluginRepository::new(dh.clone(), ctx.clone()).await;
-                    self.devices.insert(
-                        id,
-                        Device {
-                            name,
-                            remote_ip: ip,
-                            conn_id,
-                            tx,
-                            plugin_repo: Arc::new(plugin_repo),
-                        },
+                    let dh = self.device_handle(&id, &name);
+                    let plugin_repo = PluginRepository::new(dh.clone(), ctx.clone());
+                    let mut device = Device::new(
+                        dh.clone(),
+                        name,
+                        device_type,
+                        ip,
+                        SystemTime::now(),
+                        plugin_repo,
                     );
-                }
+                    device.set_connection(connection, outbound);
+                    self.devices.insert(id, device);
+                    dh
+                };
 
-                let _ = reply.send(dh);
+                let _ = reply.send((queue, dh));
+                let _ = self.handle.events.send(connected);
 
                 self.update_active_device_count();
+                self.save_known_devices();
 
                 tray_updated = true;
             }
             Message::RemoveDevice { id, conn_id } => {
                 if let Some(device) = self.devices.get_mut(&id) {
-                    if device.conn_id == conn_id {
-                        // We are still on the same connection, so we can remove the device
-                        log::info!("Removed device: {}", id);
+                    if device.connection.as_ref().map(|c| c.conn_id) == Some(conn_id) {
+                        // We are still on the same connection, so the device is now offline
+                        log::info!("Device disconnected: {}", id);
 
-                        device.plugin_repo.dispose().await;
-                        self.devices.remove(&id);
+                        device.set_connection(None, &ctx.settings().outbound);
+                        let _ = self.handle.events.send(DeviceEvent::Disconnected { id });
                         self.update_active_device_count();
+                        self.save_known_devices();
                     }
                 }
 
                 tray_updated = true;
             }
+            Message::SetPaired { id, paired } => {
+                let event = if paired {
+                    DeviceEvent::Paired { id }
+                } else {
+                    DeviceEvent::Unpaired { id }
+                };
+                let _ = self.handle.events.send(event);
+            }
             Message::QueryDevice { id, reply } => {
-                let _ = reply.send(self.devices.contains_key(&id));
+                let _ = reply.send(self.devices.get(&id).is_some_and(Device::is_connected));
+            }
+            Message::ListDevices { reply } => {
+                let snapshot = self.snapshot();
+                tokio::spawn(async move {
+                    let mut devices = vec![];
+                    for device in snapshot.iter() {
+                        devices.push(device.info().await);
+                    }
+                    let _ = reply.send(devices);
+                });
             }
-            Message::SendPacket { packet, device_id } => {
+            Message::SendPacket {
+                packet,
+                device_id,
+                options,
+                delivery,
+            } => {
+                let settings = ctx.settings();
+                let outbound = &settings.outbound;
+
                 if let Some(device_id) = device_id {
                     log::debug!("Sending {:?} to {}", packet, device_id);
 
-                    if let Some(device) = self.devices.get(&device_id) {
-                        if let Err(e) = device.tx.send(packet).await {
-                            log::error!("Failed to send packet to device {}: {}", device.name, e);
-                        }
+                    let packet = QueuedPacket::new(packet, options, delivery);
+                    match self.devices.get_mut(&device_id) {
+                        Some(device) => device.send(packet, outbound),
+                        None => packet.complete(Err(SendError::DeviceOffline)),
                     }
                 } else {
                     log::debug!("Broadcasting {:?}", packet);
 
-                    for device in self.devices.values() {
-                        if let Err(e) = device.tx.send(packet.clone()).await {
-                            log::error!("Failed to send packet to device {}: {}", device.name, e);
-                        };
+                    for device in self.devices.values_mut().filter(|d| d.is_connected()) {
+                        device.send(QueuedPacket::new(packet.clone(), options, None), outbound);
                     }
                 }
             }
             Message::Event(event) => {
-                for device in self.devices.values() {
+                let rotate = if event.is_menu_clicked(*ROTATE_CERT_MENU_ID) {
+                    Some(false)
+                } else if event.is_menu_clicked(*ROTATE_ID_MENU_ID) {
+                    Some(true)
+                } else {
+                    None
+                };
+                if let Some(new_device_id) = rotate {
+                    let ctx = ctx.clone();
+                    tokio::spawn(async move {
+                        utils::log_if_error(
+                            "Failed to rotate identity",
+                            ctx.rotate_identity(new_device_id).await,
+                        );
+                    });
+                    return;
+                }
+
+                for device in self.devices.values().filter(|d| d.is_connected()) {
                     let pr = device.plugin_repo.clone();
 
                     tokio::spawn(async move {
@@ -283,16 +709,25 @@ impl DeviceManagerActor {
                     tracing::warn!("Device {} not found", device_id);
                     return;
                 };
-                let pr = device.plugin_repo.clone();
+                let (matched, waiting) = std::mem::take(&mut device.waiters)
+                    .into_iter()
+                    .filter(|w| !w.reply.is_closed())
+                    .partition::<Vec<_>, _>(|w| w.matches(&packet));
+                device.waiters = waiting;
+                for waiter in matched {
+                    let _ = waiter.reply.send(packet.clone());
+                }
 
-                tokio::spawn(
-                    async move {
-                        if let Err(e) = pr.handle_packet(packet).await {
-                            tracing::error!("Failed to handle packet: {:?}", e);
-                        }
+                // Queued rather than spawned, so that plugins see packets in order.
+                device.plugin_repo.dispatch_packet(packet, span.clone());
+            }
+            Message::AwaitPacket { device_id, waiter } => {
+                // Dropping the waiter tells it that the device is offline.
+                if let Some(device) = self.devices.get_mut(&device_id) {
+                    if device.is_connected() {
+                        device.waiters.push(waiter);
                     }
-                    .instrument(span.clone()),
-                );
+                }
             }
             Message::FetchPayload {
                 device_id,
@@ -310,9 +745,9 @@ impl DeviceManagerActor {
                 let ctx = ctx.clone();
 
                 tokio::spawn(async move {
-                    let task = async {
+                    let fetch = async {
                         let mut conn = ctx.tls_connect((remote_ip, port)).await?;
-                        let mut buf = Vec::with_capacity(size as usize);
+                        let mut buf = Vec::with_capacity(size);
                         conn.read_to_end(&mut buf).await?;
 
                         if buf.len() == size {
@@ -325,68 +760,425 @@ impl DeviceManagerActor {
                             ))
                         }
                     };
-                    let _ = reply.send(task.await);
+                    let result = tokio::select! {
+                        result = fetch => result,
+                        _ = ctx.shutdown.triggered() => Err(anyhow::anyhow!("Shutting down")),
+                    };
+                    let _ = reply.send(result);
+                });
+            }
+            Message::OpenPayload {
+                device_id,
+                port,
+                reply,
+            } => {
+                let remote_ip = match self.devices.get(&device_id) {
+                    Some(device) => device.remote_ip,
+                    None => {
+                        let _ = reply.send(Err(anyhow::anyhow!("Device {} not found", device_id)));
+                        return;
+                    }
+                };
+                let ctx = ctx.clone();
+
+                tokio::spawn(async move {
+                    let result = tokio::select! {
+                        result = ctx.tls_connect((remote_ip, port)) => result.map_err(Into::into),
+                        _ = ctx.shutdown.triggered() => Err(anyhow::anyhow!("Shutting down")),
+                    };
+                    let _ = reply.send(result);
+                });
+            }
+            Message::CallPlugin {
+                device_id,
+                plugin,
+                method,
+                params,
+                reply,
+            } => {
+                let pr = match self.devices.get(&device_id) {
+                    Some(device) => device.plugin_repo.clone(),
+                    None => {
+                        let _ = reply.send(Err(anyhow::anyhow!("Device {} not found", device_id)));
+                        return;
+                    }
+                };
+
+                tokio::spawn(async move {
+                    let _ = reply.send(pr.call(&plugin, &method, params).await);
                 });
             }
             Message::UpdateTray => {
                 tray_updated = true;
             }
+            Message::ReloadSettings => {
+                let depth = ctx.settings().outbound.queue_depth;
+                for conn in self.devices.values().filter_map(|d| d.connection.as_ref()) {
+                    conn.queue.set_depth(depth);
+                }
+
+                let repos = self
+                    .devices
+                    .values()
+                    .map(|d| d.plugin_repo.clone())
+                    .collect::<Vec<_>>();
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    for pr in repos {
+                        pr.apply_settings().await;
+                    }
+                    ctx.update_tray().await;
+                });
+            }
+            Message::DisconnectAll => {
+                let settings = ctx.settings();
+                let outbound = &settings.outbound;
+                for (id, device) in self.devices.iter_mut() {
+                    if device.is_connected() {
+                        log::info!("Disconnecting device: {}", id);
+                        device.set_connection(None, outbound);
+                        let _ = self
+                            .handle
+                            .events
+                            .send(DeviceEvent::Disconnected { id: id.clone() });
+                    }
+                }
+                self.update_active_device_count();
+                self.save_known_devices();
+
+                tray_updated = true;
+            }
+            Message::Shutdown { reply } => {
+                let snapshot = self.snapshot();
+                self.save_tx.send_replace(snapshot.clone());
+                let flush_tx = self.flush_tx.clone();
+                tokio::spawn(async move {
+                    let (done_tx, done_rx) = oneshot::channel();
+                    if flush_tx.send(done_tx).await.is_ok() {
+                        let _ = done_rx.await;
+                    }
+                    for device in snapshot.iter() {
+                        device.plugin_repo.dispose().await;
+                    }
+                    let _ = reply.send(());
+                });
+            }
         }
 
         if tray_updated {
-            self.update_tray(ctx).await;
+            self.tray_tx.send_replace(self.snapshot());
+        }
+    }
+
+    fn device_handle(&self, id: &str, name: &str) -> DeviceHandle {
+        DeviceHandle {
+            device_id: Arc::new(id.to_string()),
+            device_name: Arc::new(RwLock::new(name.to_string())),
+            manager_handle: self.handle.clone(),
+        }
+    }
+
+    /// Connected devices first, then by name.
+    fn snapshot(&self) -> Snapshot {
+        let mut devices = self
+            .devices
+            .iter()
+            .map(|(id, d)| d.snapshot(id))
+            .collect::<Vec<_>>();
+        devices.sort_by_key(|d| (!d.connected, d.name.to_lowercase()));
+        Arc::new(devices)
+    }
+
+    /// Restore devices seen in previous runs, as offline.
+    fn load_known_devices(&mut self, ctx: &AppContextRef) {
+        let known = match known::load(paths::get().known_devices_file()) {
+            Ok(known) => known,
+            Err(e) => {
+                log::error!("Failed to load known devices: {:?}", e);
+                return;
+            }
+        };
+
+        for (id, device) in known {
+            let last_seen = device.last_seen_time();
+            let dh = self.device_handle(&id, &device.name);
+            let plugin_repo = PluginRepository::new(dh.clone(), ctx.clone());
+            let pr = plugin_repo.clone();
+            let plugin_state = device.plugin_state;
+            tokio::spawn(async move {
+                pr.restore_state(&plugin_state).await;
+            });
+
+            self.devices.insert(
+                id,
+                Device::new(
+                    dh,
+                    device.name,
+                    device.device_type,
+                    device.last_ip,
+                    last_seen,
+                    plugin_repo,
+                ),
+            );
         }
     }
 
+    fn save_known_devices(&self) {
+        self.save_tx.send_replace(self.snapshot());
+    }
+
     fn update_active_device_count(&self) {
-        let count = self.devices.len();
+        let count = self.devices.values().filter(|d| d.is_connected()).count();
         self.active_device_count
             .store(count, std::sync::atomic::Ordering::Relaxed);
     }
 
-    async fn update_tray(&self, ctx: &AppContextRef) {
-        let mut menu = ContextMenu::new();
+    /// Spawn the actor to a background task.
+    pub fn run(mut self, ctx: AppContextRef) {
+        #[cfg(windows)]
+        tokio::spawn(tray_task(self.tray_tx.subscribe(), ctx.clone()));
+        let flush_rx = self.flush_rx.take().expect("Actor is already running");
+        tokio::spawn(save_task(self.save_tx.subscribe(), flush_rx));
 
-        if self.devices.is_empty() {
-            menu.add_item(MenuItemAttributes::new("No device connected").with_enabled(false));
-            menu.add_native_item(MenuItem::Separator);
+        tokio::spawn(async move {
+            self.load_known_devices(&ctx);
+            self.tray_tx.send_replace(self.snapshot());
+
+            let mut expire = tokio::time::interval(EXPIRE_INTERVAL);
+            loop {
+                tokio::select! {
+                    msg = self.receiver.recv() => {
+                        let (msg, span) = match msg {
+                            Some(msg) => msg,
+                            None => break,
+                        };
+                        let _enter = span.enter();
+                        self.handle_message(msg, &ctx);
+                    }
+                    _ = expire.tick() => {
+                        let ttl = ctx.settings().outbound.offline_ttl();
+                        for device in self.devices.values_mut() {
+                            device.expire_offline(ttl);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Rebuild the tray menu whenever devices change, skipping intermediate snapshots if
+/// plugins are slow to respond.
+#[cfg(windows)]
+async fn tray_task(mut rx: watch::Receiver<Snapshot>, ctx: AppContextRef) {
+    while rx.changed().await.is_ok() {
+        let snapshot = rx.borrow().clone();
+        update_tray(&snapshot, &ctx).await;
+    }
+}
+
+#[cfg(windows)]
+async fn update_tray(devices: &[DeviceSnapshot], ctx: &AppContextRef) {
+    let connected = devices.iter().any(|d| d.connected);
+    let mut menu = ContextMenu::new();
+
+    if !connected {
+        menu.add_item(MenuItemAttributes::new("No device connected").with_enabled(false));
+        menu.add_native_item(MenuItem::Separator);
+    }
+
+    for device in devices {
+        if device.connected {
+            menu.add_item(MenuItemAttributes::new(&format!(
+                "{}\t\t\t  {}",
+                device.name, device.remote_ip
+            )));
+
+            device.plugin_repo.create_tray_menu(&mut menu).await;
         } else {
-            for device in self.devices.values() {
-                menu.add_item(MenuItemAttributes::new(&format!(
-                    "{}\t\t\t  {}",
-                    device.name, device.remote_ip
-                )));
+            menu.add_item(
+                MenuItemAttributes::new(&format!("{}\t\t\t  offline", device.name))
+                    .with_enabled(false),
+            );
+
+            let mut status = vec![format!(
+                "Last seen {}",
+                utils::format_time_ago(device.last_seen)
+            )];
+            status.extend(device.plugin_repo.status().await);
+            menu.add_item(MenuItemAttributes::new(&status.join(", ")).with_enabled(false));
+        }
+
+        menu.add_native_item(MenuItem::Separator);
+    }
+
+    let mut identity_menu = ContextMenu::new();
+    identity_menu
+        .add_item(MenuItemAttributes::new("Rotate certificate").with_id(*ROTATE_CERT_MENU_ID));
+    identity_menu.add_item(
+        MenuItemAttributes::new("Rotate certificate and device ID").with_id(*ROTATE_ID_MENU_ID),
+    );
+    menu.add_submenu("Identity", true, identity_menu);
+
+    menu.add_native_item(MenuItem::Quit);
 
-                device.plugin_repo.create_tray_menu(&mut menu).await;
+    ctx.event_loop_proxy
+        .send_event(CustomWindowEvent::SetTrayMenu(menu))
+        .ok();
 
-                menu.add_native_item(MenuItem::Separator);
+    let icon = if connected {
+        ICON_CELLPHONE.clone()
+    } else {
+        ICON_CELLPHONE_OFF.clone()
+    };
+    ctx.event_loop_proxy
+        .send_event(CustomWindowEvent::SetTrayIcon(icon))
+        .ok();
+}
+
+/// Write known devices to disk whenever they change, or when asked to flush them.
+///
+/// This is the only writer of the file, so writes never overlap.
+async fn save_task(
+    mut rx: watch::Receiver<Snapshot>,
+    mut flush_rx: mpsc::Receiver<oneshot::Sender<()>>,
+) {
+    loop {
+        tokio::select! {
+            changed = rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let snapshot = rx.borrow_and_update().clone();
+                write_known_devices(&snapshot).await;
+            }
+            Some(done) = flush_rx.recv() => {
+                if rx.has_changed().unwrap_or(false) {
+                    let snapshot = rx.borrow_and_update().clone();
+                    write_known_devices(&snapshot).await;
+                }
+                let _ = done.send(());
             }
         }
+    }
+}
 
-        menu.add_native_item(MenuItem::Quit);
-
-        ctx.event_loop_proxy
-            .send_event(CustomWindowEvent::SetTrayMenu(menu))
-            .ok();
+async fn write_known_devices(snapshot: &[DeviceSnapshot]) {
+    let mut known = KnownDevices::new();
+    for device in snapshot {
+        let last_seen = device
+            .last_seen
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
 
-        let icon = if self.devices.is_empty() {
-            ICON_CELLPHONE_OFF.clone()
-        } else {
-            ICON_CELLPHONE.clone()
-        };
-        ctx.event_loop_proxy
-            .send_event(CustomWindowEvent::SetTrayIcon(icon))
-            .ok();
+        known.insert(
+            device.id.clone(),
+            KnownDevice {
+                name: device.name.clone(),
+                device_type: device.device_type.clone(),
+                last_ip: device.remote_ip,
+                last_seen: last_seen.as_millis() as u64,
+                plugin_state: device.plugin_repo.save_state().await,
+            },
+        );
     }
 
-    /// Spawn the actor to a background task.
-    pub fn run(mut self, ctx: AppContextRef) {
-        tokio::spawn(async move {
-            self.update_tray(&ctx).await;
+    utils::log_if_error(
+        "Failed to save known devices",
+        known::save(&paths::get().known_devices_file(), &known).await,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::context::test_context;
 
-            while let Some((msg, span)) = self.receiver.recv().await {
-                self.handle_message(msg, &ctx).instrument(span).await;
+    use super::*;
+
+    #[tokio::test]
+    async fn stuck_connection_never_blocks_manager() {
+        let ctx = test_context().await;
+        let manager = ctx.device_manager.clone();
+        let mut events = manager.subscribe();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        // The peer never reads, so the connection task gets stuck writing the first packet.
+        let (_, stuck_queue, _) = manager
+            .add_device("stuck", "Stuck", "phone", ip)
+            .await
+            .unwrap();
+        let (mut conn, _peer) = tokio::io::duplex(16);
+        let stuck = tokio::spawn(async move {
+            while let Some(packet) = stuck_queue.pop().await {
+                let data = serde_json::to_vec(&packet.packet.packet).unwrap();
+                let result = conn
+                    .write_all(&data)
+                    .await
+                    .map_err(|_| SendError::LinkClosed);
+                packet.complete(result);
+            }
+        });
+
+        // More than the queue holds, so some are rejected or evicted along the way.
+        let depth = ctx.settings().outbound.queue_depth;
+        for _ in 0..depth * 2 {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                let packet = NetworkPacket::new("kdeconnect.ping", serde_json::json!({}));
+                let _ = manager
+                    .send_packet("stuck", packet, SendOptions::default())
+                    .await;
+            });
+        }
+        tokio::task::yield_now().await;
+
+        let (_, other_queue, _) = manager
+            .add_device("other", "Other", "desktop", ip)
+            .await
+            .unwrap();
+        let other = tokio::spawn(async move {
+            while let Some(packet) = other_queue.pop().await {
+                packet.complete(Ok(()));
             }
         });
+
+        let timeout = Duration::from_secs(5);
+        let packet = NetworkPacket::new("kdeconnect.ping", serde_json::json!({}));
+        let sent = tokio::time::timeout(
+            timeout,
+            manager.send_packet("other", packet, SendOptions::default()),
+        )
+        .await
+        .expect("Sending to another device timed out");
+        assert_eq!(sent, Ok(()));
+
+        let devices = tokio::time::timeout(timeout, manager.list_devices())
+            .await
+            .expect("Listing devices timed out")
+            .unwrap();
+        let mut ids: Vec<_> = devices
+            .iter()
+            .filter(|d| d.connected)
+            .map(|d| d.id.as_str())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["other", "stuck"]);
+
+        let connected = tokio::time::timeout(timeout, async {
+            loop {
+                match events.recv().await {
+                    Ok(DeviceEvent::Connected { id, .. }) if id == "other" => break,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(e) => panic!("Events closed: {}", e),
+                }
+            }
+        })
+        .await;
+        assert!(connected.is_ok(), "No event for the other device");
+
+        stuck.abort();
+        other.abort();
     }
 }
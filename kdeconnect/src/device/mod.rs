@@ -1,53 +1,164 @@
 pub mod handle;
+mod known;
 pub mod manager;
+pub mod queue;
 
 use anyhow::Result;
-use std::net::IpAddr;
-use tokio::sync::{mpsc, oneshot};
+use serde::Serialize;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{net::TcpStream, sync::oneshot};
+use tokio_rustls::client::TlsStream;
 
 pub use handle::DeviceHandle;
-pub use manager::{DeviceManagerActor, DeviceManagerHandle};
+pub use manager::{DeviceInfo, DeviceManagerActor, DeviceManagerHandle};
+pub use queue::SendOptions;
 
 use crate::{
     event::SystemEvent,
     packet::{NetworkPacket, NetworkPacketWithPayload},
 };
 
-use self::manager::ConnectionId;
+use self::{
+    manager::{ConnectionId, PacketWaiter},
+    queue::{Delivery, OutboundQueue},
+};
+
+/// Changes in the state of a device, see [`DeviceManagerHandle::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    Connected {
+        id: String,
+        name: String,
+        device_type: String,
+        ip: IpAddr,
+    },
+    Disconnected {
+        id: String,
+    },
+    Paired {
+        id: String,
+    },
+    Unpaired {
+        id: String,
+    },
+    Renamed {
+        id: String,
+        name: String,
+    },
+    /// The state kept by a plugin has changed, e.g. the battery level of the device.
+    PluginChanged {
+        id: String,
+        plugin: String,
+    },
+}
+
+/// Why a packet was not delivered, see [`DeviceHandle::send_packet`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SendError {
+    #[error("Device is offline")]
+    DeviceOffline,
+    #[error("Too many packets are waiting to be sent")]
+    QueueFull,
+    #[error("Connection closed before the packet was sent")]
+    LinkClosed,
+    #[error("Failed to serve payload: {0}")]
+    PayloadServer(String),
+}
+
+/// Why a request got no response, see [`DeviceHandle::request`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RequestError {
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error("No response within {0:?}")]
+    Timeout(Duration),
+}
+
+impl DeviceEvent {
+    pub fn device_id(&self) -> &str {
+        match self {
+            DeviceEvent::Connected { id, .. }
+            | DeviceEvent::Disconnected { id }
+            | DeviceEvent::Paired { id }
+            | DeviceEvent::Unpaired { id }
+            | DeviceEvent::Renamed { id, .. }
+            | DeviceEvent::PluginChanged { id, .. } => id,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Message {
     AddDevice {
         id: String,
         name: String,
+        device_type: String,
         ip: IpAddr,
         conn_id: ConnectionId,
-        tx: mpsc::Sender<NetworkPacketWithPayload>,
-        reply: oneshot::Sender<DeviceHandle>,
+        reply: oneshot::Sender<(Arc<OutboundQueue>, DeviceHandle)>,
     },
     /// Whether the device is connected
     QueryDevice {
         id: String,
         reply: oneshot::Sender<bool>,
     },
+    ListDevices {
+        reply: oneshot::Sender<Vec<DeviceInfo>>,
+    },
     RemoveDevice {
         id: String,
         conn_id: ConnectionId,
     },
+    /// The device has been paired or unpaired.
+    SetPaired {
+        id: String,
+        paired: bool,
+    },
     SendPacket {
         device_id: Option<String>,
         packet: NetworkPacketWithPayload,
+        options: SendOptions,
+        /// Completed once the packet is written to the connection, or dropped.
+        delivery: Option<Delivery>,
     },
     Event(SystemEvent),
     UpdateTray,
+    /// Settings have been changed, apply them to all devices.
+    ReloadSettings,
+    /// Drop all connections, devices will connect again when they discover us.
+    DisconnectAll,
+    /// Save the state of all devices and dispose their plugins, before exiting.
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
     Packet {
         device_id: String,
         packet: NetworkPacket,
     },
+    /// Pass the next matching packet from the device to the waiter, as well as to plugins.
+    AwaitPacket {
+        device_id: String,
+        waiter: PacketWaiter,
+    },
     FetchPayload {
         device_id: String,
         port: u16,
         size: usize,
         reply: oneshot::Sender<Result<Vec<u8>>>,
     },
+    /// Connect to a payload offered by a device, to read it as it arrives.
+    OpenPayload {
+        device_id: String,
+        port: u16,
+        reply: oneshot::Sender<Result<TlsStream<TcpStream>>>,
+    },
+    /// Call a plugin method for a local client, see [`crate::plugin::KdeConnectPlugin::call`].
+    CallPlugin {
+        device_id: String,
+        plugin: String,
+        method: String,
+        params: serde_json::Value,
+        reply: oneshot::Sender<Result<serde_json::Value>>,
+    },
 }
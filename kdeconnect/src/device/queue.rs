@@ -0,0 +1,364 @@
+//! Outgoing packets of a connection, waiting to be written to the socket.
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{oneshot, Notify};
+
+use crate::packet::{NetworkPacketWithPayload, PACKET_TYPE_PAIR};
+
+use super::SendError;
+
+/// Where to report whether a packet was sent.
+pub type Delivery = oneshot::Sender<Result<(), SendError>>;
+
+/// Packets of a higher priority are sent before any packet of a lower one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Packets with a payload, e.g. album art or shared files.
+    Bulk,
+    Normal,
+    /// Pairing and other packets that should not wait behind a transfer.
+    Control,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Control, Priority::Normal, Priority::Bulk];
+
+    /// Default priority of a packet.
+    pub fn of(packet: &NetworkPacketWithPayload) -> Self {
+        if packet.packet.typ == PACKET_TYPE_PAIR {
+            Priority::Control
+        } else if packet.payload.is_some() {
+            Priority::Bulk
+        } else {
+            Priority::Normal
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Priority::Control => 0,
+            Priority::Normal => 1,
+            Priority::Bulk => 2,
+        }
+    }
+}
+
+/// How a packet is sent, see [`crate::device::DeviceHandle::send_packet_with`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendOptions {
+    /// Derived from the packet if not set, see [`Priority::of`].
+    pub priority: Option<Priority>,
+    /// Keep the packet if the device is offline, and send it when it connects again.
+    ///
+    /// Buffered packets are dropped after `offline-ttl-secs` from the settings.
+    pub buffer_offline: bool,
+}
+
+impl SendOptions {
+    /// Options for a packet that is still useful if delivered a bit later, like a shared
+    /// file.
+    pub fn buffered() -> Self {
+        Self {
+            buffer_offline: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueuedPacket {
+    pub packet: NetworkPacketWithPayload,
+    pub priority: Priority,
+    pub buffer_offline: bool,
+    pub queued_at: Instant,
+    delivery: Option<Delivery>,
+}
+
+impl QueuedPacket {
+    pub fn new(
+        packet: NetworkPacketWithPayload,
+        options: SendOptions,
+        delivery: Option<Delivery>,
+    ) -> Self {
+        Self {
+            priority: options.priority.unwrap_or_else(|| Priority::of(&packet)),
+            buffer_offline: options.buffer_offline,
+            packet,
+            queued_at: Instant::now(),
+            delivery,
+        }
+    }
+
+    /// Tell the sender whether the packet was sent.
+    pub fn complete(self, result: Result<(), SendError>) {
+        if let Some(delivery) = self.delivery {
+            let _ = delivery.send(result);
+        }
+    }
+
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.queued_at.elapsed() > ttl
+    }
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// The queue is full of packets of the same or a higher priority.
+    Full(QueuedPacket),
+    /// The connection is gone.
+    Closed(QueuedPacket),
+}
+
+#[derive(Debug)]
+struct State {
+    /// One queue per priority, highest first.
+    queues: [VecDeque<QueuedPacket>; 3],
+    depth: usize,
+    closed: bool,
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// Packets waiting to be written to a connection, filled by the device manager and drained
+/// by the connection task.
+///
+/// Pushing never waits: once `depth` packets are queued, a new packet replaces the oldest
+/// packet of a lower priority, or is rejected if there is none.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl OutboundQueue {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                queues: Default::default(),
+                depth: depth.max(1),
+                closed: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn set_depth(&self, depth: usize) {
+        self.state.lock().unwrap().depth = depth.max(1);
+    }
+
+    /// Queue a packet, returning the packet it replaced if the queue was full.
+    // The packet is handed back on errors, like `mpsc::error::TrySendError` does.
+    #[allow(clippy::result_large_err)]
+    pub fn push(&self, packet: QueuedPacket) -> Result<Option<QueuedPacket>, PushError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(PushError::Closed(packet));
+        }
+
+        let mut evicted = None;
+        if state.len() >= state.depth {
+            evicted = Priority::ALL
+                .iter()
+                .rev()
+                .take_while(|p| **p < packet.priority)
+                .find_map(|p| state.queues[p.index()].pop_front());
+            if evicted.is_none() {
+                return Err(PushError::Full(packet));
+            }
+        }
+
+        state.queues[packet.priority.index()].push_back(packet);
+        drop(state);
+
+        self.notify.notify_one();
+        Ok(evicted)
+    }
+
+    /// Wait for the next packet to send, `None` once the queue is closed.
+    ///
+    /// Cancel safe, so it can be used in `tokio::select!`.
+    pub async fn pop(&self) -> Option<QueuedPacket> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(packet) = state.queues.iter_mut().find_map(VecDeque::pop_front) {
+                    return Some(packet);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Stop accepting packets and wake up the connection task, returning packets that were
+    /// not sent yet.
+    pub fn close(&self) -> Vec<QueuedPacket> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let unsent = state.queues.iter_mut().flat_map(|q| q.drain(..)).collect();
+        drop(state);
+
+        self.notify.notify_one();
+        unsent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::packet::NetworkPacket;
+
+    fn packet(typ: &str, delivery: Option<Delivery>) -> QueuedPacket {
+        let packet = NetworkPacket::new(typ, serde_json::json!({}));
+        QueuedPacket::new(packet.into(), SendOptions::default(), delivery)
+    }
+
+    fn with_priority(typ: &str, priority: Priority) -> QueuedPacket {
+        let options = SendOptions {
+            priority: Some(priority),
+            ..Default::default()
+        };
+        QueuedPacket::new(NetworkPacket::new(typ, ()).into(), options, None)
+    }
+
+    fn types(packets: Vec<QueuedPacket>) -> Vec<String> {
+        packets.into_iter().map(|p| p.packet.packet.typ).collect()
+    }
+
+    #[test]
+    fn priority_follows_packet() {
+        let pair = NetworkPacket::new_pair(true).into();
+        assert_eq!(Priority::of(&pair), Priority::Control);
+
+        let ping = NetworkPacket::new("kdeconnect.ping", ()).into();
+        assert_eq!(Priority::of(&ping), Priority::Normal);
+
+        let share = NetworkPacketWithPayload::new(
+            NetworkPacket::new("kdeconnect.share.request", ()),
+            Arc::new(vec![0; 4]),
+        );
+        assert_eq!(Priority::of(&share), Priority::Bulk);
+    }
+
+    #[tokio::test]
+    async fn higher_priorities_are_sent_first() {
+        let queue = OutboundQueue::new(8);
+        for (typ, priority) in [
+            ("bulk1", Priority::Bulk),
+            ("normal1", Priority::Normal),
+            ("bulk2", Priority::Bulk),
+            ("control", Priority::Control),
+            ("normal2", Priority::Normal),
+        ] {
+            queue.push(with_priority(typ, priority)).unwrap();
+        }
+
+        let mut sent = vec![];
+        for _ in 0..5 {
+            sent.push(queue.pop().await.unwrap().packet.packet.typ);
+        }
+        assert_eq!(sent, ["control", "normal1", "normal2", "bulk1", "bulk2"]);
+    }
+
+    #[test]
+    fn full_queue_evicts_oldest_lower_priority() {
+        let queue = OutboundQueue::new(3);
+        queue.push(with_priority("bulk1", Priority::Bulk)).unwrap();
+        queue
+            .push(with_priority("normal1", Priority::Normal))
+            .unwrap();
+        queue.push(with_priority("bulk2", Priority::Bulk)).unwrap();
+
+        let evicted = queue.push(with_priority("control1", Priority::Control));
+        assert_eq!(types(evicted.unwrap().into_iter().collect()), ["bulk1"]);
+        let evicted = queue.push(with_priority("normal2", Priority::Normal));
+        assert_eq!(types(evicted.unwrap().into_iter().collect()), ["bulk2"]);
+
+        // Nothing of a lower priority is left to make room.
+        match queue.push(with_priority("normal3", Priority::Normal)) {
+            Err(PushError::Full(p)) => assert_eq!(p.packet.packet.typ, "normal3"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let evicted = queue.push(with_priority("control2", Priority::Control));
+        assert_eq!(types(evicted.unwrap().into_iter().collect()), ["normal1"]);
+
+        assert_eq!(types(queue.close()), ["control1", "control2", "normal2"]);
+    }
+
+    #[tokio::test]
+    async fn closed_queue_rejects_packets() {
+        let queue = OutboundQueue::new(2);
+        queue
+            .push(with_priority("normal", Priority::Normal))
+            .unwrap();
+
+        assert_eq!(types(queue.close()), ["normal"]);
+        assert!(matches!(
+            queue.push(with_priority("late", Priority::Control)),
+            Err(PushError::Closed(_))
+        ));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stuck_peer_never_blocks_senders() {
+        let queue = Arc::new(OutboundQueue::new(2));
+
+        // The peer never reads, so the connection task gets stuck writing the first packet.
+        let (mut conn, _peer) = tokio::io::duplex(16);
+        let q = queue.clone();
+        let connection = tokio::spawn(async move {
+            while let Some(packet) = q.pop().await {
+                let data = serde_json::to_vec(&packet.packet.packet).unwrap();
+                let result = conn
+                    .write_all(&data)
+                    .await
+                    .map_err(|_| SendError::LinkClosed);
+                packet.complete(result);
+            }
+        });
+
+        let (delivery, mut delivered) = oneshot::channel();
+        queue
+            .push(packet("kdeconnect.first", Some(delivery)))
+            .unwrap();
+        tokio::task::yield_now().await;
+
+        // Pushing returns right away, even once the queue is full.
+        for _ in 0..2 {
+            assert!(queue
+                .push(packet("kdeconnect.queued", None))
+                .unwrap()
+                .is_none());
+        }
+        assert!(matches!(
+            queue.push(packet("kdeconnect.rejected", None)),
+            Err(PushError::Full(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(delivered.try_recv().is_err());
+
+        let unsent = queue.close();
+        assert_eq!(unsent.len(), 2);
+        assert!(unsent
+            .iter()
+            .all(|p| p.packet.packet.typ == "kdeconnect.queued"));
+        connection.abort();
+    }
+}
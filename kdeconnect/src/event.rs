@@ -1,4 +1,4 @@
-use tao::menu::MenuId;
+use crate::tray::MenuId;
 use tokio::sync::mpsc;
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
@@ -0,0 +1,31 @@
+//! Only one daemon may run for a data directory, as several would fight over the ports, the
+//! identity and the tray. Commands given to later processes are forwarded to it instead, see
+//! [`crate::cli`].
+use std::fs::{File, OpenOptions, TryLockError};
+
+use anyhow::{Context, Result};
+
+use crate::paths::Paths;
+
+/// Held for as long as the daemon runs, the lock is released when the process exits.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Take the lock, or return `None` if another process holds it.
+pub fn try_lock(paths: &Paths) -> Result<Option<InstanceLock>> {
+    let path = paths.instance_lock_file();
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Open {:?}", path))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(InstanceLock { _file: file })),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e).with_context(|| format!("Lock {:?}", path)),
+    }
+}
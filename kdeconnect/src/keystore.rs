@@ -0,0 +1,57 @@
+//! Secret stores that can hold the private key instead of the identity file.
+//!
+//! Stores are looked up by name, which is written to the identity file so that the key can
+//! be found again.
+use anyhow::{Context, Result};
+
+/// Service name used for entries in the system keyring.
+const KEYRING_SERVICE: &str = "kde-connect-rs";
+
+pub const KEYRING: &str = "keyring";
+
+pub trait SecretStore {
+    /// Get the secret stored under `account`, if any.
+    fn get(&self, account: &str) -> Result<Option<Vec<u8>>>;
+    fn set(&self, account: &str, secret: &[u8]) -> Result<()>;
+    fn delete(&self, account: &str) -> Result<()>;
+}
+
+/// The system keyring: Credential Manager on Windows, the Secret Service API (e.g. GNOME
+/// Keyring or KWallet) on Linux.
+pub struct KeyringStore;
+
+impl KeyringStore {
+    fn entry(account: &str) -> Result<keyring::Entry> {
+        keyring::Entry::new(KEYRING_SERVICE, account).context("Open keyring entry")
+    }
+}
+
+impl SecretStore for KeyringStore {
+    fn get(&self, account: &str) -> Result<Option<Vec<u8>>> {
+        match Self::entry(account)?.get_password() {
+            Ok(secret) => Ok(Some(base64::decode(secret)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&self, account: &str, secret: &[u8]) -> Result<()> {
+        Self::entry(account)?.set_password(&base64::encode(secret))?;
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        match Self::entry(account)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Get a secret store by name.
+pub fn open(name: &str) -> Result<Box<dyn SecretStore>> {
+    match name {
+        KEYRING => Ok(Box::new(KeyringStore)),
+        _ => anyhow::bail!("Unknown secret store: {}", name),
+    }
+}
@@ -1,15 +1,14 @@
 #![allow(clippy::single_match, dead_code)]
 
 use std::{
-    io::Write,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
     time::Duration,
 };
 
 use anyhow::{bail, Context, Result};
 use context::AppContextRef;
 use socket2::{Domain, Socket};
+#[cfg(windows)]
 use tao::{
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop, EventLoopProxy},
@@ -23,30 +22,47 @@ use tokio::{
     net::{TcpListener, TcpStream, UdpSocket},
     sync::mpsc,
 };
-use tokio_rustls::{
-    rustls::{ClientConfig, ServerConfig, ServerName},
-    TlsAcceptor, TlsConnector,
-};
+use tokio_rustls::rustls::ServerName;
 
 mod packet;
-use packet::{IdentityPacket, NetworkPacket, NetworkPacketWithPayload};
+use device::SendError;
+use packet::{IdentityPacket, NetworkPacket, NetworkPacketWithPayload, PairPacket, Payload};
 
+mod backup;
 mod cache;
+mod cli;
 mod config;
 mod context;
+mod control;
+mod crypto;
+#[cfg(feature = "dbus")]
+mod dbus;
 mod device;
 mod event;
+mod instance;
+mod keystore;
 mod logging;
+mod paths;
+#[cfg(windows)]
 mod platform_listener;
 mod plugin;
+mod settings;
+mod shutdown;
+mod store;
+mod systemd;
 mod tls;
+mod tray;
+mod trust;
 mod utils;
 
+#[cfg(windows)]
 pub enum CustomWindowEvent {
     ClipboardUpdated,
     PowerStatusUpdated,
     SetTrayMenu(ContextMenu),
     SetTrayIcon(Icon),
+    /// The daemon has shut down, exit the event loop.
+    Quit,
 }
 
 pub const AUM_ID: &str = "Midori.KDEConnectRS";
@@ -82,21 +98,48 @@ async fn udp_server(tcp_port: u16, ctx: AppContextRef) -> Result<()> {
 
     log::info!("UDP server started");
 
-    let mut identity_packet = NetworkPacket::new_identity(
-        tcp_port,
-        plugin::ALL_CAPS.0.clone(),
-        plugin::ALL_CAPS.1.clone(),
-        &ctx.config,
-    );
+    let (incoming_caps, outgoing_caps) = plugin::capabilities(&ctx);
+    let identity = |config: &config::Config, settings: &settings::Settings| {
+        NetworkPacket::new_identity(
+            tcp_port,
+            incoming_caps.clone(),
+            outgoing_caps.clone(),
+            config,
+            settings,
+        )
+    };
+
+    let mut config_rx = ctx.watch_config();
+    let mut settings_rx = ctx.watch_settings();
+    let mut identity_packet = identity(&config_rx.borrow(), &settings_rx.borrow());
+    let mut identity_changed = false;
 
     loop {
-        if ctx.device_manager.active_device_count() == 0 {
-            // Advertise our presence to all devices on the network if we have no active devices.
+        if identity_changed || ctx.device_manager.active_device_count() == 0 {
+            // Advertise our presence to all devices on the network if we have no active devices,
+            // or let them know that our name or type has changed.
             identity_packet.reset_ts();
             let buf = serde_json::to_vec(&identity_packet)?;
             udp_socket.send_to(&buf, broadcast_addr).await?;
+            identity_changed = false;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(5)) => {}
+            Ok(()) = async {
+                tokio::select! {
+                    r = config_rx.changed() => r,
+                    r = settings_rx.changed() => r,
+                }
+            } => {
+                let new_identity_packet = identity(&config_rx.borrow(), &settings_rx.borrow());
+                if new_identity_packet.body != identity_packet.body {
+                    log::info!("Identity changed, announcing again");
+                    identity_packet = new_identity_packet;
+                    identity_changed = true;
+                }
+            }
         }
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
     }
 }
 
@@ -109,7 +152,7 @@ async fn handle_udp_packet(buf: &[u8], addr: SocketAddr, ctx: &AppContextRef) ->
 
     let remote_identity = remote_identity_packet.into_body::<IdentityPacket>()?;
 
-    if remote_identity.device_id == ctx.config.uuid {
+    if remote_identity.device_id == ctx.config().uuid {
         // Don't connect to ourself.
         return Ok(());
     }
@@ -125,7 +168,7 @@ async fn handle_udp_packet(buf: &[u8], addr: SocketAddr, ctx: &AppContextRef) ->
     let stream = TcpStream::connect((addr.ip(), tcp_port)).await?;
 
     let ctx = ctx.clone();
-    tokio::spawn(async move {
+    ctx.shutdown.clone().spawn(async move {
         let r = handle_conn(Role::Client { remote_identity }, stream, addr.ip(), ctx).await;
         match r {
             Ok(_) => {
@@ -140,22 +183,28 @@ async fn handle_udp_packet(buf: &[u8], addr: SocketAddr, ctx: &AppContextRef) ->
     Ok(())
 }
 
-/// Listen to incoming discovery packets.
-async fn udp_listener(ctx: AppContextRef) -> Result<()> {
-    let socket = Socket::new(
-        Domain::IPV4,
-        socket2::Type::DGRAM,
-        Some(socket2::Protocol::UDP),
-    )?;
-    socket.set_broadcast(true)?;
-    socket.set_reuse_address(true)?;
+/// Listen to incoming discovery packets, on `activated` if it's given by systemd.
+async fn udp_listener(activated: Option<std::net::UdpSocket>, ctx: AppContextRef) -> Result<()> {
+    let socket = match activated {
+        Some(socket) => socket,
+        None => {
+            let socket = Socket::new(
+                Domain::IPV4,
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            socket.set_broadcast(true)?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&socket2::SockAddr::from(SocketAddr::new(
+                Ipv4Addr::UNSPECIFIED.into(),
+                1716u16,
+            )))?;
+            socket.into()
+        }
+    };
     socket.set_nonblocking(true)?;
-    socket.bind(&socket2::SockAddr::from(SocketAddr::new(
-        Ipv4Addr::UNSPECIFIED.into(),
-        1716u16,
-    )))?;
 
-    let udp_socket = UdpSocket::from_std(socket.into())?;
+    let udp_socket = UdpSocket::from_std(socket)?;
 
     log::info!("UDP listener started");
 
@@ -169,11 +218,17 @@ async fn udp_listener(ctx: AppContextRef) -> Result<()> {
     }
 }
 
-/// Opens a TCP listener on an empty port.
-async fn open_tcp_server() -> Result<(TcpListener, u16)> {
+/// Opens a TCP listener on an empty port, unless systemd has given us one.
+async fn open_tcp_server(activated: Option<std::net::TcpListener>) -> Result<(TcpListener, u16)> {
     const MIN_PORT: u16 = 1716;
     const MAX_PORT: u16 = 1764;
 
+    if let Some(listener) = activated {
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        return Ok((TcpListener::from_std(listener)?, port));
+    }
+
     let mut last_error = None;
 
     for port in MIN_PORT..=MAX_PORT {
@@ -205,7 +260,8 @@ async fn open_payload_tcp_server() -> Result<(TcpListener, u16)> {
 }
 
 /// Serve payload data on the given listener.
-async fn serve_payload(server: TcpListener, data: Arc<Vec<u8>>, ctx: AppContextRef) {
+async fn serve_payload(server: TcpListener, data: Payload, ctx: AppContextRef) {
+    let shutdown = ctx.shutdown.clone();
     let task = async move {
         loop {
             let (stream, addr) = match server.accept().await {
@@ -229,7 +285,7 @@ async fn serve_payload(server: TcpListener, data: Arc<Vec<u8>>, ctx: AppContextR
                     }
                 };
 
-                if let Err(err) = stream.write_all(&data).await {
+                if let Err(err) = data.write_to(&mut stream).await {
                     log::error!("Error writing payload to {}: {:?}", addr, err);
                     return;
                 }
@@ -241,48 +297,51 @@ async fn serve_payload(server: TcpListener, data: Arc<Vec<u8>>, ctx: AppContextR
         }
     };
 
-    tokio::time::timeout(Duration::from_secs(60), task)
-        .await
-        .ok();
+    tokio::select! {
+        _ = tokio::time::timeout(Duration::from_secs(60), task) => {}
+        _ = shutdown.triggered() => {}
+    }
 }
 
+/// Write a packet to the connection, serving its payload if it has one.
+///
+/// The packet is not sent if its payload can't be served.
 async fn send_packet<W: AsyncWrite + Unpin>(
     mut stream: W,
-    mut packet: NetworkPacketWithPayload,
+    packet: &NetworkPacketWithPayload,
     ctx: AppContextRef,
-) -> Result<()> {
-    if let Some(payload) = packet.payload {
-        match open_payload_tcp_server().await {
-            Ok((payload_server, payload_port)) => {
-                packet.packet.set_payload(payload.len() as _, payload_port);
-
-                log::info!(
-                    "Serving a payload of {} bytes on {}",
-                    payload.len(),
-                    payload_port
-                );
-
-                let ctx = ctx.clone();
-                tokio::spawn(async move {
-                    serve_payload(payload_server, payload, ctx).await;
-                });
-            }
-            Err(e) => {
-                log::error!("Failed to start payload server: {:?}", e);
-            }
-        }
+) -> Result<(), SendError> {
+    let mut header = packet.packet.clone();
+
+    if let Some(payload) = &packet.payload {
+        let (payload_server, payload_port) = open_payload_tcp_server()
+            .await
+            .map_err(|e| SendError::PayloadServer(format!("{:#}", e)))?;
+        header.set_payload(payload.size(), payload_port);
+
+        log::info!(
+            "Serving a payload of {} bytes on {}",
+            payload.size(),
+            payload_port
+        );
+
+        let payload = payload.clone();
+        ctx.shutdown.clone().spawn(async move {
+            serve_payload(payload_server, payload, ctx).await;
+        });
     }
 
-    let mut bytes = packet.packet.to_vec();
+    let mut bytes = header.to_vec();
     bytes.push(0x0A);
 
-    stream
-        .write_all(&bytes)
-        .await
-        .context("Write to connection")?;
-    stream.flush().await.context("Flush connection")?;
-
-    Ok(())
+    let write = async {
+        stream.write_all(&bytes).await?;
+        stream.flush().await
+    };
+    write.await.map_err(|e| {
+        log::error!("Failed to write to connection: {:?}", e);
+        SendError::LinkClosed
+    })
 }
 
 async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextRef) -> Result<()> {
@@ -328,11 +387,13 @@ async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextR
             )
         }
         Role::Client { remote_identity } => {
+            let (incoming_caps, outgoing_caps) = plugin::capabilities(&ctx);
             let local_identity_packet = NetworkPacket::new_identity(
                 None,
-                plugin::ALL_CAPS.0.clone(),
-                plugin::ALL_CAPS.1.clone(),
-                &ctx.config,
+                incoming_caps,
+                outgoing_caps,
+                &ctx.config(),
+                &ctx.settings(),
             );
             stream.write_all(&local_identity_packet.to_vec()).await?;
             stream.write_all(b"\n").await?;
@@ -350,11 +411,15 @@ async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextR
     };
 
     let device_id = remote_identity.device_id.as_str();
-    let _peer_cert = stream
+    let peer_cert = stream
         .get_ref()
         .1
         .peer_certificates()
-        .and_then(|c| c.first());
+        .and_then(|c| c.first())
+        .map(|c| c.0.clone());
+    ctx.trusted_devices
+        .verify(device_id, peer_cert.as_deref())
+        .context("Reject connection")?;
 
     let mut stream = BufStream::new(stream);
 
@@ -366,20 +431,36 @@ async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextR
         role_text
     );
 
-    let (conn_id, mut packet_rx, device_handle) = ctx
+    let (conn_id, queue, device_handle) = ctx
         .device_manager
-        .add_device(device_id, &remote_identity.device_name, ip)
+        .add_device(
+            device_id,
+            &remote_identity.device_name,
+            &remote_identity.device_type,
+            ip,
+        )
         .await?;
 
     loop {
         let mut line = String::new();
 
         tokio::select! {
-            packet = packet_rx.recv() => {
+            _ = ctx.shutdown.triggered() => {
+                log::info!("Closing connection to {}", device_id);
+                break;
+            }
+
+            packet = queue.pop() => {
                 // Send packet
                 if let Some(packet) = packet {
-                    if let Err(e) = send_packet(&mut stream, packet, ctx.clone()).await {
-                        log::error!("Error sending packet to {}: {:?}", ip, e);
+                    let result = send_packet(&mut stream, &packet.packet, ctx.clone()).await;
+                    if let Err(e) = &result {
+                        log::error!("Error sending packet to {}: {}", ip, e);
+                    }
+
+                    let closed = result == Err(SendError::LinkClosed);
+                    packet.complete(result);
+                    if closed {
                         break;
                     }
                 } else {
@@ -408,10 +489,32 @@ async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextR
                     Ok(packet) => match packet.typ.as_str() {
                         packet::PACKET_TYPE_PAIR => {
                             // Directly handle pairing requests
-                            NetworkPacket::new_pair(true)
-                                .write_to_conn(&mut stream)
-                                .await?;
-                            log::info!("Accepted pairing request");
+                            let body: PairPacket = packet.into_body()?;
+                            if body.pair {
+                                NetworkPacket::new_pair(true)
+                                    .write_to_conn(&mut stream)
+                                    .await?;
+                                log::info!("Accepted pairing request");
+
+                                if let Some(cert) = &peer_cert {
+                                    utils::log_if_error(
+                                        "Failed to save trusted device",
+                                        ctx.trusted_devices.trust(
+                                            device_id,
+                                            &remote_identity.device_name,
+                                            cert,
+                                        ),
+                                    );
+                                }
+                                ctx.device_manager.set_paired(device_id, true).await;
+                            } else {
+                                log::info!("Unpaired by {}", device_id);
+                                utils::log_if_error(
+                                    "Failed to remove trusted device",
+                                    ctx.trusted_devices.untrust(device_id),
+                                );
+                                ctx.device_manager.set_paired(device_id, false).await;
+                            }
                         }
                         _ => {
                             device_handle.dispatch_packet(packet).await;
@@ -430,8 +533,15 @@ async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextR
         }
     }
 
-    // Wait for some time before removing device and notify the user.
-    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    if ctx.shutdown.is_triggered() {
+        // Let the device know that we're leaving, rather than waiting for a timeout.
+        if let Err(e) = stream.shutdown().await {
+            log::warn!("Failed to close connection to {}: {:?}", ip, e);
+        }
+    } else {
+        // Wait for some time before removing device and notify the user.
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
 
     ctx.device_manager.remove_device(device_id, conn_id).await;
 
@@ -446,7 +556,7 @@ async fn tcp_server(listener: TcpListener, ctx: AppContextRef) -> Result<()> {
 
         let ctx = ctx.clone();
 
-        tokio::spawn(async move {
+        ctx.shutdown.clone().spawn(async move {
             let r = handle_conn(Role::Server, stream, addr.ip(), ctx).await;
             match r {
                 Ok(_) => {
@@ -495,88 +605,161 @@ async fn event_handler(mut rx: event::EventReceiver, ctx: AppContextRef) {
 #[tokio::main]
 async fn server_main(
     event_channel: (event::EventSender, event::EventReceiver),
-    event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
-    hotkey_manager: ShortcutManager,
+    #[cfg(windows)] event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
+    #[cfg(windows)] hotkey_manager: ShortcutManager,
+    shutdown: shutdown::Shutdown,
+    sockets: systemd::ActivatedSockets,
 ) -> Result<()> {
     let (_, event_rx) = event_channel;
-    let (tcp_listener, tcp_port) = open_tcp_server().await?;
+    let (tcp_listener, tcp_port) = open_tcp_server(sockets.tcp).await?;
 
     log::info!("TCP port: {}", tcp_port);
 
-    let config = config::Config::init_or_load("./config.json")?;
+    let paths = paths::get();
+
+    let settings_path = paths.settings_file();
+    let settings = settings::Settings::load_or_default(&settings_path).context("Load settings")?;
+    let config = config::Config::init_or_load(
+        paths.identity_file(),
+        settings.key_storage,
+        &settings.certificate,
+    )
+    .context("Load identity")?;
+    let trusted_devices =
+        trust::TrustStore::load(paths.trusted_devices_file()).context("Load trusted devices")?;
+
+    match tls::time_to_expiration(&config.tls_cert) {
+        Ok(Some(left)) if left.whole_days() < 30 => {
+            log::warn!(
+                "Certificate expires in {} days, rotate the identity to renew it",
+                left.whole_days()
+            );
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => log::warn!("Certificate has expired, rotate the identity to renew it"),
+        Err(e) => log::error!("Failed to check certificate expiration: {:?}", e),
+    }
 
-    let ctx = context::ApplicationContext::new(config, event_loop_proxy, hotkey_manager)
-        .await
-        .context("Initialize context")?;
-
-    // Use the same certificate when we are acting as client and server.
-
-    let client_config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(Arc::new(tls::ServerVerifier::AlwaysOk))
-        .with_single_cert(
-            vec![tokio_rustls::rustls::Certificate(
-                ctx.config.tls_cert.clone(),
-            )],
-            tokio_rustls::rustls::PrivateKey(ctx.config.tls_key.clone()),
-        )?;
-
-    let server_config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_client_cert_verifier(Arc::new(tls::ClientVerifier::AlwaysOk))
-        .with_single_cert(
-            vec![tokio_rustls::rustls::Certificate(
-                ctx.config.tls_cert.clone(),
-            )],
-            tokio_rustls::rustls::PrivateKey(ctx.config.tls_key.clone()),
-        )?;
-
-    let tls_connector = TlsConnector::from(Arc::new(client_config));
-    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
-    ctx.setup_tls(tls_acceptor, tls_connector);
-
-    let uctx = ctx.clone();
-    let udp_task = tokio::spawn(async move {
-        let e = udp_server(tcp_port, uctx).await;
-        log::warn!("UDP server exited with {:?}", e);
+    // Capabilities of external plugins must be known before announcing ourselves.
+    let external_plugins =
+        plugin::external::ExternalPlugins::start(&settings.external_plugins).await;
+
+    let ctx = context::ApplicationContext::new(
+        config,
+        settings,
+        trusted_devices,
+        external_plugins,
+        #[cfg(windows)]
+        event_loop_proxy,
+        #[cfg(windows)]
+        hotkey_manager,
+        shutdown.clone(),
+    )
+    .await
+    .context("Initialize context")?;
+
+    // Services stop as soon as shutdown is triggered, so no new connections are made.
+    shutdown.spawn_service("UDP server", udp_server(tcp_port, ctx.clone()));
+    shutdown.spawn_service("UDP listener", udp_listener(sockets.udp, ctx.clone()));
+    shutdown.spawn_service("Settings watcher", {
+        let ctx = ctx.clone();
+        async move {
+            settings::watch(settings_path, ctx).await;
+            Ok::<_, anyhow::Error>(())
+        }
     });
-
-    let uctx = ctx.clone();
-    let udp_listener_task = tokio::spawn(async move {
-        let e = udp_listener(uctx).await;
-        log::warn!("UDP listener exited with {:?}", e);
+    shutdown.spawn_service("Control socket", control::serve(ctx.clone()));
+    #[cfg(feature = "dbus")]
+    shutdown.spawn_service("D-Bus service", dbus::serve(ctx.clone()));
+    shutdown.spawn_service("Event handler", {
+        let ctx = ctx.clone();
+        async move {
+            event_handler(event_rx, ctx).await;
+            Ok::<_, anyhow::Error>(())
+        }
     });
+    shutdown.spawn_service("TCP server", tcp_server(tcp_listener, ctx.clone()));
+    shutdown.spawn_service("Watchdog", systemd::watchdog(ctx.clone()));
+    utils::log_if_error("Failed to notify systemd", systemd::notify("READY=1"));
 
-    let ectx = ctx.clone();
-    let event_task = tokio::spawn(async move {
-        event_handler(event_rx, ectx).await;
-        log::warn!("Event handler exited");
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        match shutdown::signal().await {
+            Ok(()) => signal_shutdown.trigger(),
+            Err(e) => log::error!("Failed to listen for signals: {:?}", e),
+        }
     });
 
-    let tcp_task = tokio::spawn(async move {
-        let e = tcp_server(tcp_listener, ctx).await;
-        log::warn!("TCP server exited with {:?}", e);
-    });
+    shutdown.triggered().await;
+    log::info!("Shutting down");
+    utils::log_if_error("Failed to notify systemd", systemd::notify("STOPPING=1"));
 
-    udp_task.await?;
-    udp_listener_task.await?;
-    tcp_task.await?;
-    event_task.await?;
+    // Connections close and transfers are cancelled, then plugins can be disposed.
+    if !shutdown.drain(shutdown::DRAIN_TIMEOUT).await {
+        log::warn!("Connections were not closed in time");
+    }
+    let dispose = ctx.device_manager.shutdown();
+    if tokio::time::timeout(shutdown::DRAIN_TIMEOUT, dispose)
+        .await
+        .is_err()
+    {
+        log::warn!("Plugins were not disposed in time");
+    }
+
+    #[cfg(windows)]
+    ctx.event_loop_proxy
+        .send_event(CustomWindowEvent::Quit)
+        .ok();
+    log::info!("Shut down");
 
     Ok(())
 }
 
 fn main() -> Result<()> {
+    let mut args = <cli::Args as clap::Parser>::parse();
+
     logging::setup_logger().expect("Failed to set up logger");
 
+    let paths = paths::init(args.home.take()).context("Initialize directories")?;
+
+    if let Some(command) = args.take_command() {
+        return cli::run(command, paths);
+    }
+
+    // Kept until the process exits.
+    let _instance_lock = match instance::try_lock(paths)? {
+        Some(lock) => lock,
+        None => {
+            log::info!("Another instance is running, exiting");
+            return Ok(());
+        }
+    };
+    let sockets = systemd::take_sockets();
+
+    run(paths, sockets)
+}
+
+/// Run the daemon headless, until it's stopped by a signal.
+#[cfg(not(windows))]
+fn run(_paths: &paths::Paths, sockets: systemd::ActivatedSockets) -> Result<()> {
+    // There are no platform listeners, but the event handler runs until all senders are gone.
     let (event_tx, event_rx) = mpsc::channel(10);
+    server_main(
+        (event_tx.clone(), event_rx),
+        shutdown::Shutdown::new(),
+        sockets,
+    )
+}
 
-    let base_dirs = directories::BaseDirs::new().expect("Failed to get base dirs");
-    let data_dir = base_dirs.data_dir().join("kde-connect-rs");
-    std::fs::create_dir_all(&data_dir)?;
+/// Run the daemon on a separate thread, as the tray's event loop must run on the main thread.
+#[cfg(windows)]
+fn run(paths: &paths::Paths, sockets: systemd::ActivatedSockets) -> Result<()> {
+    use std::io::Write;
+
+    let (event_tx, event_rx) = mpsc::channel(10);
 
     {
-        let icon_path = data_dir.join("notification.ico");
+        let icon_path = paths.data_dir.join("notification.ico");
         if !icon_path.exists() {
             // Extract icon from executable
             let mut icon_file = std::fs::File::create(&icon_path)?;
@@ -607,12 +790,20 @@ fn main() -> Result<()> {
 
     let event_tx_main = event_tx.clone();
     let proxy = event_loop.create_proxy();
-    std::thread::spawn(|| {
-        let r = server_main((event_tx_main, event_rx), proxy, hotkey_manager);
+    let shutdown = shutdown::Shutdown::new();
+    let server_shutdown = shutdown.clone();
+    let mut server_thread = Some(std::thread::spawn(|| {
+        let r = server_main(
+            (event_tx_main, event_rx),
+            proxy,
+            hotkey_manager,
+            server_shutdown,
+            sockets,
+        );
         if let Err(e) = r {
             log::error!("Server exited with error: {}", e);
         }
-    });
+    }));
 
     event_loop.run(move |event, _, control_flow| {
         let _ = windows_listener;
@@ -620,6 +811,13 @@ fn main() -> Result<()> {
         *control_flow = ControlFlow::Wait;
 
         match event {
+            // E.g. Quit in the tray menu, let the server close connections before exiting.
+            Event::LoopDestroyed => {
+                shutdown.trigger();
+                if let Some(thread) = server_thread.take() {
+                    let _ = thread.join();
+                }
+            }
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 window_id,
@@ -676,6 +874,7 @@ fn main() -> Result<()> {
                 CustomWindowEvent::SetTrayIcon(icon) => {
                     system_tray.set_icon(icon);
                 }
+                CustomWindowEvent::Quit => *control_flow = ControlFlow::Exit,
             },
             _ => {}
         }
@@ -1,10 +1,10 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::{config::Config, utils};
+use crate::{config::Config, settings::Settings, utils};
 
 pub const PACKET_TYPE_IDENTITY: &str = "kdeconnect.identity";
 pub const PACKET_TYPE_PAIR: &str = "kdeconnect.pair";
@@ -12,7 +12,7 @@ pub const PACKET_TYPE_PAIR: &str = "kdeconnect.pair";
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PairPacket {
-    pair: bool,
+    pub pair: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -56,7 +56,13 @@ impl NetworkPacket {
         }
     }
 
-    pub fn new_identity<P, I, O>(tcp_port: P, in_caps: I, out_caps: O, config: &Config) -> Self
+    pub fn new_identity<P, I, O>(
+        tcp_port: P,
+        in_caps: I,
+        out_caps: O,
+        config: &Config,
+        settings: &Settings,
+    ) -> Self
     where
         P: Into<Option<u16>>,
         I: IntoIterator<Item = String>,
@@ -66,9 +72,9 @@ impl NetworkPacket {
             PACKET_TYPE_IDENTITY,
             IdentityPacket {
                 device_id: config.uuid.clone(),
-                device_name: gethostname::gethostname().to_string_lossy().to_string(),
+                device_name: settings.device_name(),
                 protocol_version: 7,
-                device_type: "desktop".into(),
+                device_type: settings.device_type().into(),
                 incoming_capabilities: in_caps.into_iter().collect(),
                 outgoing_capabilities: out_caps.into_iter().collect(),
                 tcp_port: tcp_port.into(),
@@ -117,16 +123,59 @@ pub struct PayloadTransferInfo {
     pub port: u16,
 }
 
+/// Data served to the device along with a packet.
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Bytes(Arc<Vec<u8>>),
+    /// Read from disk each time the device fetches it, for shared files.
+    File {
+        path: Arc<PathBuf>,
+        size: u64,
+    },
+}
+
+impl Payload {
+    pub fn size(&self) -> u64 {
+        match self {
+            Payload::Bytes(data) => data.len() as u64,
+            Payload::File { size, .. } => *size,
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            Payload::Bytes(data) => writer.write_all(data).await,
+            Payload::File { path, size } => {
+                let mut file = tokio::fs::File::open(path.as_path()).await?.take(*size);
+                let written = tokio::io::copy(&mut file, writer).await?;
+                if written != *size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("{:?} is shorter than announced", path),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Arc<Vec<u8>>> for Payload {
+    fn from(data: Arc<Vec<u8>>) -> Self {
+        Payload::Bytes(data)
+    }
+}
+
 #[derive(Clone)]
 pub struct NetworkPacketWithPayload {
     pub packet: NetworkPacket,
-    pub payload: Option<Arc<Vec<u8>>>,
+    pub payload: Option<Payload>,
 }
 
 impl Debug for NetworkPacketWithPayload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let payload_desc = match &self.payload {
-            Some(p) => format!("Some({} bytes)", p.len()),
+            Some(p) => format!("Some({} bytes)", p.size()),
             None => "None".to_string(),
         };
 
@@ -147,10 +196,10 @@ impl From<NetworkPacket> for NetworkPacketWithPayload {
 }
 
 impl NetworkPacketWithPayload {
-    pub fn new(packet: NetworkPacket, payload: Arc<Vec<u8>>) -> Self {
+    pub fn new(packet: NetworkPacket, payload: impl Into<Payload>) -> Self {
         Self {
             packet,
-            payload: Some(payload),
+            payload: Some(payload.into()),
         }
     }
 }
@@ -0,0 +1,265 @@
+//! Locations of configuration, data and cache files.
+//!
+//! Files are placed in the standard per-user directories unless a custom directory is
+//! given with `--home <DIR>` or the `KDECONNECT_RS_HOME` environment variable.
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use kdeconnect_cli::client::{self, APP_DIR_NAME};
+use once_cell::sync::OnceCell;
+
+/// Identity file used by older versions, relative to the working directory.
+const LEGACY_IDENTITY_FILE: &str = "./config.json";
+/// Settings file used by older versions, relative to the working directory.
+const LEGACY_SETTINGS_FILE: &str = "./config.toml";
+
+static PATHS: OnceCell<Paths> = OnceCell::new();
+
+#[derive(Debug)]
+pub struct Paths {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    /// Where received files are stored.
+    pub downloads_dir: PathBuf,
+}
+
+impl Paths {
+    fn standard() -> Result<Self> {
+        let base_dirs = directories::BaseDirs::new().context("Failed to get base dirs")?;
+        let data_dir = client::data_dir(None)?;
+
+        let downloads_dir = directories::UserDirs::new()
+            .and_then(|d| d.download_dir().map(Path::to_path_buf))
+            .unwrap_or_else(|| data_dir.join("downloads"));
+
+        Ok(Self {
+            config_dir: base_dirs.config_dir().join(APP_DIR_NAME),
+            data_dir,
+            cache_dir: base_dirs.cache_dir().join(APP_DIR_NAME),
+            downloads_dir,
+        })
+    }
+
+    fn in_dir(home: &Path) -> Result<Self> {
+        Ok(Self {
+            config_dir: home.to_path_buf(),
+            data_dir: client::data_dir(Some(home))?,
+            cache_dir: home.join("cache"),
+            downloads_dir: home.join("downloads"),
+        })
+    }
+
+    /// Device ID and certificates.
+    pub fn identity_file(&self) -> PathBuf {
+        self.config_dir.join("identity.json")
+    }
+
+    /// User settings, see [`crate::settings`].
+    pub fn settings_file(&self) -> PathBuf {
+        self.config_dir.join("config.toml")
+    }
+
+    pub fn trusted_devices_file(&self) -> PathBuf {
+        self.data_dir.join("trusted_devices.json")
+    }
+
+    /// Devices seen before, with their last known state.
+    pub fn known_devices_file(&self) -> PathBuf {
+        self.data_dir.join("known_devices.json")
+    }
+
+    /// Values stored by plugins, one file per device, see [`crate::store`].
+    pub fn device_stores_dir(&self) -> PathBuf {
+        self.data_dir.join("devices")
+    }
+
+    /// Held by the running daemon, see [`crate::instance`].
+    pub fn instance_lock_file(&self) -> PathBuf {
+        self.data_dir.join("daemon.lock")
+    }
+
+    /// Where the daemon accepts local clients, see [`crate::control`].
+    ///
+    /// Shared with clients, see [`kdeconnect_cli::client::control_socket`].
+    pub fn control_socket(&self) -> PathBuf {
+        client::control_socket(&self.data_dir)
+    }
+
+    fn create_dirs(&self) -> Result<()> {
+        for dir in [
+            &self.config_dir,
+            &self.data_dir,
+            &self.cache_dir,
+            &self.downloads_dir,
+        ] {
+            std::fs::create_dir_all(dir).with_context(|| format!("Create {:?}", dir))?;
+        }
+        Ok(())
+    }
+
+    /// Move the identity file from the working directory, where older versions kept it.
+    fn migrate_legacy_identity(&self) -> Result<()> {
+        let legacy = Path::new(LEGACY_IDENTITY_FILE);
+        let current = self.identity_file();
+
+        if current.exists() || !legacy.exists() {
+            return Ok(());
+        }
+
+        log::info!("Migrating identity from {:?} to {:?}", legacy, current);
+        std::fs::copy(legacy, &current).context("Copy legacy identity")?;
+        std::fs::remove_file(legacy).context("Remove legacy identity")?;
+
+        Ok(())
+    }
+
+    /// Copy the settings file from the working directory, where older versions read it.
+    ///
+    /// The old file is left in place, it may be the example of a source checkout.
+    fn migrate_legacy_settings(&self) -> Result<()> {
+        let legacy = Path::new(LEGACY_SETTINGS_FILE);
+        let current = self.settings_file();
+
+        if current.exists() || !legacy.exists() {
+            return Ok(());
+        }
+
+        log::info!(
+            "Copying settings from {:?} to {:?}, the old file is no longer read",
+            legacy,
+            current
+        );
+        std::fs::copy(legacy, &current).context("Copy legacy settings")?;
+
+        Ok(())
+    }
+}
+
+/// Determine the directories to use, create them and migrate files from older versions.
+///
+/// `home` overrides the standard directories, then the `KDECONNECT_RS_HOME` environment
+/// variable is checked.
+pub fn init(home: Option<PathBuf>) -> Result<&'static Paths> {
+    let paths = match client::home_dir(home) {
+        Some(home) => Paths::in_dir(&home)?,
+        None => Paths::standard()?,
+    };
+
+    paths.create_dirs()?;
+    paths.migrate_legacy_identity()?;
+    paths.migrate_legacy_settings()?;
+
+    log::debug!("Using {:?}", paths);
+
+    PATHS
+        .set(paths)
+        .map_err(|_| anyhow::anyhow!("Paths are already initialized"))?;
+
+    Ok(get())
+}
+
+/// Make a file readable and writable only by the current user.
+///
+/// Files in the per-user directories are already private on Windows, so this only changes
+/// the permissions on Unix.
+pub fn make_private(path: impl AsRef<Path>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path.as_ref(), std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Set permissions of {:?}", path.as_ref()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Create or truncate a file that only the current user can access, for storing secrets.
+pub fn create_private_file(path: impl AsRef<Path>) -> Result<File> {
+    let path = path.as_ref();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let f = options
+        .open(path)
+        .with_context(|| format!("Create {:?}", path))?;
+    // The mode only applies to new files.
+    make_private(path)?;
+    Ok(f)
+}
+
+/// Replace a file that only the current user can access, so that it's never left
+/// half-written.
+///
+/// The contents are written to a temporary file next to it and flushed to disk, then the
+/// temporary file is renamed over the original.
+pub fn write_private_file(path: impl AsRef<Path>, contents: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let write = || {
+        let mut f = create_private_file(&tmp_path)?;
+        f.write_all(contents)
+            .and_then(|_| f.sync_all())
+            .with_context(|| format!("Write {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, path).with_context(|| format!("Replace {:?}", path))
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Get the paths determined by [`init`].
+pub fn get() -> &'static Paths {
+    PATHS.get().expect("Paths are not initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_private_file_replaces_contents() {
+        let path = crate::utils::test_dir("paths-write").join("identity.json");
+
+        write_private_file(&path, b"first").unwrap();
+        write_private_file(&path, b"second").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert!(!path.with_extension("json.tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn write_private_file_keeps_original_on_failure() {
+        let dir = crate::utils::test_dir("paths-write-failure");
+        let path = dir.join("identity.json");
+        write_private_file(&path, b"original").unwrap();
+
+        // The temporary file can't be created where a directory is in the way.
+        std::fs::create_dir(dir.join("identity.json.tmp")).unwrap();
+        assert!(write_private_file(&path, b"new").is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"original");
+    }
+}
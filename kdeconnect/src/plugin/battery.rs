@@ -24,20 +24,28 @@ also answer this same kind of packages with its own information.
 
 If the battery is low and discharging, it will notify the user.
  */
-use std::{mem::MaybeUninit, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tao::menu::{ContextMenu, MenuItemAttributes};
 use tokio::sync::Mutex;
-use windows::Win32::System::Power::GetSystemPowerStatus;
 
 use crate::{
-    context::AppContextRef, device::DeviceHandle, event::SystemEvent, packet::NetworkPacket,
+    context::AppContextRef,
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::{ContextMenu, MenuItemAttributes},
 };
 
 use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
 
+/// How long to wait for the remote device to report its battery.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name of the plugin in settings, see [`DeviceHandle::plugin_changed`].
+const PLUGIN_NAME: &str = "battery";
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct BatteryReport {
@@ -64,25 +72,46 @@ impl BatteryPlugin {
         }
     }
 
-    pub async fn send_battery_status(&self) -> Result<()> {
-        let power_status = unsafe {
-            let mut power_status = MaybeUninit::uninit();
-            GetSystemPowerStatus(power_status.as_mut_ptr()).ok()?;
-            power_status.assume_init()
-        };
+    /// Ask the remote device for its battery status now, rather than waiting for it to
+    /// report a change.
+    pub async fn request_remote_status(&self) -> Result<()> {
+        let response = self
+            .device
+            .request(
+                NetworkPacket::new(
+                    "kdeconnect.battery.request",
+                    serde_json::json!({ "request": true }),
+                ),
+                "kdeconnect.battery",
+                |_| true,
+                REQUEST_TIMEOUT,
+            )
+            .await?;
+
+        let report: BatteryReport = response.into_body()?;
+        *self.battery_status.lock().await = Some(report);
+        self.ctx.update_tray().await;
+        self.device.plugin_changed(PLUGIN_NAME);
+        Ok(())
+    }
 
-        if power_status.ACLineStatus == 255 /* Unknown status */
-            || power_status.BatteryFlag & 128 != 0 /* No system battery */
-            || power_status.BatteryFlag == 255
-        /* Unknown status—unable to read the battery flag information */
-        {
-            return Ok(());
+    /// Last known status of the remote device, for local clients.
+    async fn status_json(&self) -> serde_json::Value {
+        let status = self.battery_status.lock().await;
+        match status.as_ref() {
+            Some(x) => serde_json::json!({
+                "charge": x.current_charge,
+                "is_charging": x.is_charging,
+                "is_low": x.threshold_event == 1,
+            }),
+            None => serde_json::Value::Null,
         }
+    }
 
-        let battery_status = BatteryReport {
-            current_charge: power_status.BatteryLifePercent,
-            is_charging: power_status.ACLineStatus == 1,
-            threshold_event: power_status.SystemStatusFlag, /* 1 if battery saver is on */
+    pub async fn send_battery_status(&self) -> Result<()> {
+        let battery_status = match local_battery_status()? {
+            Some(status) => status,
+            None => return Ok(()),
         };
 
         self.device
@@ -90,12 +119,45 @@ impl BatteryPlugin {
                 "kdeconnect.battery",
                 battery_status.clone(),
             ))
-            .await;
+            .await?;
 
         Ok(())
     }
 }
 
+/// Status of the system battery, if there's one.
+#[cfg(windows)]
+fn local_battery_status() -> Result<Option<BatteryReport>> {
+    use std::mem::MaybeUninit;
+    use windows::Win32::System::Power::GetSystemPowerStatus;
+
+    let power_status = unsafe {
+        let mut power_status = MaybeUninit::uninit();
+        GetSystemPowerStatus(power_status.as_mut_ptr()).ok()?;
+        power_status.assume_init()
+    };
+
+    if power_status.ACLineStatus == 255 /* Unknown status */
+        || power_status.BatteryFlag & 128 != 0 /* No system battery */
+        || power_status.BatteryFlag == 255
+    /* Unknown status—unable to read the battery flag information */
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(BatteryReport {
+        current_charge: power_status.BatteryLifePercent,
+        is_charging: power_status.ACLineStatus == 1,
+        threshold_event: power_status.SystemStatusFlag, /* 1 if battery saver is on */
+    }))
+}
+
+/// The local battery is only read on Windows, elsewhere only the remote one is shown.
+#[cfg(not(windows))]
+fn local_battery_status() -> Result<Option<BatteryReport>> {
+    Ok(None)
+}
+
 #[async_trait::async_trait]
 impl KdeConnectPlugin for BatteryPlugin {
     async fn handle(&self, packet: NetworkPacket) -> Result<()> {
@@ -104,6 +166,7 @@ impl KdeConnectPlugin for BatteryPlugin {
                 let report: BatteryReport = packet.into_body()?;
                 *self.battery_status.lock().await = Some(report);
                 self.ctx.update_tray().await;
+                self.device.plugin_changed(PLUGIN_NAME);
             }
             "kdeconnect.battery.request" => {
                 self.send_battery_status().await?;
@@ -113,6 +176,12 @@ impl KdeConnectPlugin for BatteryPlugin {
         Ok(())
     }
 
+    async fn on_connected(self: Arc<Self>) -> Result<()> {
+        self.send_battery_status().await?;
+        // What we have may be left from an earlier connection.
+        self.request_remote_status().await
+    }
+
     async fn tray_menu(&self, menu: &mut ContextMenu) {
         let status = self.battery_status.lock().await;
         if let Some(x) = status.as_ref() {
@@ -125,6 +194,27 @@ impl KdeConnectPlugin for BatteryPlugin {
         }
     }
 
+    async fn status(&self) -> Option<String> {
+        let status = self.battery_status.lock().await;
+        status.as_ref().map(|x| {
+            format!(
+                "{}%{}",
+                x.current_charge,
+                if x.is_charging { "+" } else { "" }
+            )
+        })
+    }
+
+    async fn save_state(&self) -> Option<serde_json::Value> {
+        let status = self.battery_status.lock().await;
+        status.as_ref().and_then(|x| serde_json::to_value(x).ok())
+    }
+
+    async fn restore_state(&self, state: serde_json::Value) -> Result<()> {
+        *self.battery_status.lock().await = Some(serde_json::from_value(state)?);
+        Ok(())
+    }
+
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
         match event {
             SystemEvent::PowerStatusUpdated => {
@@ -134,6 +224,18 @@ impl KdeConnectPlugin for BatteryPlugin {
         }
         Ok(())
     }
+
+    async fn call(&self, method: &str, _params: serde_json::Value) -> Result<serde_json::Value> {
+        match method {
+            "status" => Ok(self.status_json().await),
+            // Ask the device for its current status, and return it.
+            "request" => {
+                self.request_remote_status().await?;
+                Ok(self.status_json().await)
+            }
+            _ => anyhow::bail!("Unknown method: {}", method),
+        }
+    }
 }
 
 impl KdeConnectPluginMetadata for BatteryPlugin {
@@ -8,15 +8,19 @@ have the same content.
 
 This plugin is symmetric to its counterpart in the other device: both have the
 same behaviour.
+
+Received content is applied unless `remote-to-local` is "off". Local changes are only
+sent if `local-to-remote` is "auto". Syncing on a shortcut, `{ hotkey = "..." }`, is not
+supported and rejected.
  */
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 
 use crate::{
-    device::DeviceHandle,
+    device::{DeviceHandle, SendError},
     event::SystemEvent,
     packet::NetworkPacket,
     utils::{self, clipboard::ClipboardContent},
@@ -47,9 +51,70 @@ struct ClipboardPacket {
     content: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "SyncModeSetting", rename_all = "kebab-case")]
+pub enum SyncMode {
+    Auto,
+    Off,
+}
+
+/// How [`SyncMode`] is written in settings, either a name or a table with a `hotkey`.
+///
+/// Shortcuts are not supported, the table is only recognized to reject it with a clear error
+/// instead of ignoring it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SyncModeSetting {
+    Mode(SyncModeName),
+    Hotkey { hotkey: String },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SyncModeName {
+    Auto,
+    Off,
+}
+
+impl TryFrom<SyncModeSetting> for SyncMode {
+    type Error = String;
+
+    fn try_from(setting: SyncModeSetting) -> Result<Self, Self::Error> {
+        match setting {
+            SyncModeSetting::Mode(SyncModeName::Auto) => Ok(SyncMode::Auto),
+            SyncModeSetting::Mode(SyncModeName::Off) => Ok(SyncMode::Off),
+            SyncModeSetting::Hotkey { hotkey } => Err(format!(
+                "Clipboard shortcuts are not supported, use \"auto\" or \"off\" instead of {:?}",
+                hotkey
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ClipboardOptions {
+    /// Whether to apply clipboard content received from the remote device.
+    remote_to_local: SyncMode,
+    /// Whether to send local clipboard changes to the remote device.
+    local_to_remote: SyncMode,
+}
+
+impl Default for ClipboardOptions {
+    fn default() -> Self {
+        Self {
+            remote_to_local: SyncMode::Auto,
+            local_to_remote: SyncMode::Off,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ClipboardPlugin {
     content: Mutex<Option<CurrentClipboardContent>>,
+    /// Last content received from the remote device, so that it's not sent back.
+    remote_content: Mutex<Option<String>>,
+    options: RwLock<ClipboardOptions>,
     device: DeviceHandle,
 }
 
@@ -57,6 +122,8 @@ impl ClipboardPlugin {
     pub fn new(dev: DeviceHandle) -> Self {
         Self {
             content: Mutex::new(None),
+            remote_content: Mutex::new(None),
+            options: RwLock::new(ClipboardOptions::default()),
             device: dev,
         }
     }
@@ -79,21 +146,26 @@ impl ClipboardPlugin {
         Ok(())
     }
 
-    async fn send_clipboard(&self) {
-        let content = self.content.lock().await;
-        if let Some(content) = content.as_ref() {
-            match &content.content {
-                ClipboardContent::Text(s) => {
-                    let packet = NetworkPacket::new(
+    async fn send_clipboard(&self) -> Result<(), SendError> {
+        let packet = {
+            let content = self.content.lock().await;
+            match content.as_ref().map(|c| &c.content) {
+                Some(ClipboardContent::Text(s)) => {
+                    if self.remote_content.lock().await.as_ref() == Some(s) {
+                        // Don't echo back what we have just received.
+                        return Ok(());
+                    }
+
+                    NetworkPacket::new(
                         PACKET_TYPE_CLIPBOARD,
                         ClipboardPacket { content: s.clone() },
-                    );
-                    self.device.send_packet(packet).await;
+                    )
                 }
-                ClipboardContent::Files(_) => {}
-                ClipboardContent::Unsupported => {}
+                _ => return Ok(()),
             }
-        }
+        };
+
+        self.device.send_packet(packet).await
     }
 }
 
@@ -102,7 +174,12 @@ impl KdeConnectPlugin for ClipboardPlugin {
     async fn handle(&self, packet: NetworkPacket) -> Result<()> {
         match packet.typ.as_str() {
             PACKET_TYPE_CLIPBOARD => {
+                if self.options.read().await.remote_to_local != SyncMode::Auto {
+                    return Ok(());
+                }
+
                 let body: ClipboardPacket = packet.into_body()?;
+                *self.remote_content.lock().await = Some(body.content.clone());
                 self.write_clipboard(body.content)
                     .await
                     .context("Write clipboard")?;
@@ -117,12 +194,20 @@ impl KdeConnectPlugin for ClipboardPlugin {
         match event {
             SystemEvent::ClipboardUpdated => {
                 self.read_clipboard().await.context("Read clipboard")?;
-                // self.send_clipboard().await;
+
+                if self.options.read().await.local_to_remote == SyncMode::Auto {
+                    self.send_clipboard().await.context("Send clipboard")?;
+                }
             }
             _ => {}
         }
         Ok(())
     }
+
+    async fn apply_options(&self, options: Option<&toml::Value>) -> Result<()> {
+        *self.options.write().await = super::parse_options(options)?;
+        Ok(())
+    }
 }
 
 impl KdeConnectPluginMetadata for ClipboardPlugin {
@@ -138,4 +223,44 @@ impl KdeConnectPluginMetadata for ClipboardPlugin {
             PACKET_TYPE_CLIPBOARD_CONNECT.into(),
         ]
     }
+    fn validate_options(options: &toml::Value) -> Result<()> {
+        ClipboardOptions::deserialize(options.clone())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(options: &str) -> ClipboardOptions {
+        super::super::parse_options(Some(&toml::from_str(options).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn options_default_to_receiving_only() {
+        let options = parse("");
+        assert_eq!(options.remote_to_local, SyncMode::Auto);
+        assert_eq!(options.local_to_remote, SyncMode::Off);
+    }
+
+    #[test]
+    fn options_reject_hotkeys() {
+        let options = parse(r#"remote-to-local = "off""#);
+        assert_eq!(options.remote_to_local, SyncMode::Off);
+
+        let options = toml::from_str(r#"local-to-remote = { hotkey = "ctrl+shift+c" }"#).unwrap();
+        let error = ClipboardPlugin::validate_options(&options).unwrap_err();
+        assert!(
+            error.to_string().contains("shortcuts are not supported"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn options_reject_unknown_modes() {
+        let options = toml::from_str("local-to-remote = \"always\"").unwrap();
+        assert!(ClipboardPlugin::validate_options(&options).is_err());
+    }
 }
@@ -0,0 +1,788 @@
+//! Plugins running as separate processes, configured under `external-plugins` in settings.
+//!
+//! Each plugin is a single process shared by all devices it's enabled for. It's started with
+//! the daemon and restarted if it exits, or if it stops reading its stdin for a while. The
+//! daemon and the plugin exchange JSON objects, one per line, over the stdin and stdout of the
+//! plugin, while its stderr goes to the daemon's. Each message has a `type`, and messages
+//! about a device have its ID as `device`. See `examples/notify_log.py` for a small plugin.
+//!
+//! From the plugin:
+//! - `capabilities`, with the `incoming` and `outgoing` packet types of the plugin. This must
+//!   be the first message, it's only read once after the plugin has started.
+//! - `send`, with a `packet` with `type` and `body`, and optionally a base64 `payload`. If an
+//!   `id` is given, the result is reported with `sent`.
+//! - `fetch_payload`, with an `id`, and the `port` and `size` of the payload of a received
+//!   packet. The payload is returned with `payload`, up to 16 MiB.
+//! - `log`, with a `level` (`error`, `warn`, `info` or `debug`) and a `message`.
+//!
+//! To the plugin:
+//! - `connected`, with the `name` of the device. Also sent again after a restart.
+//! - `disconnected`.
+//! - `options`, with the `options` of the plugin from the device settings, or `null`.
+//! - `packet`, with a received `packet` of one of the incoming types.
+//! - `sent`, with the `id` from `send`, and an `error` if the packet was not sent.
+//! - `payload`, with the `id` from `fetch_payload`, and either the base64 `data` or an `error`.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::mpsc,
+};
+
+use crate::{
+    device::DeviceHandle,
+    packet::{NetworkPacket, NetworkPacketWithPayload},
+    settings::ExternalPluginSettings,
+};
+
+use super::KdeConnectPlugin;
+
+/// How long a plugin has to declare its capabilities after it's started.
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before restarting a plugin, doubled each time it fails to start again.
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// Messages waiting to be written to a plugin, further ones are dropped until it catches up.
+const INBOX_CAPACITY: usize = 256;
+/// How long writing a message may take before the plugin is considered hung and restarted.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest payload a plugin can fetch, as it's held in memory to be encoded.
+const MAX_FETCH_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+struct Capabilities {
+    #[serde(default)]
+    incoming: Vec<String>,
+    #[serde(default)]
+    outgoing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutgoingPacket {
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(default)]
+    body: Value,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+        }
+    }
+}
+
+/// Messages read from the plugin.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PluginMessage {
+    Capabilities(Capabilities),
+    Send {
+        device: String,
+        packet: OutgoingPacket,
+        payload: Option<String>,
+        id: Option<u64>,
+    },
+    FetchPayload {
+        id: u64,
+        device: String,
+        port: u16,
+        size: usize,
+    },
+    Log {
+        level: LogLevel,
+        message: String,
+    },
+}
+
+/// Messages written to the plugin.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum HostMessage {
+    Connected {
+        device: String,
+        name: String,
+    },
+    Disconnected {
+        device: String,
+    },
+    Options {
+        device: String,
+        options: Option<Value>,
+    },
+    Packet {
+        device: String,
+        packet: NetworkPacket,
+    },
+    Sent {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Payload {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// A device the plugin is enabled for, kept to bring the plugin up to date after a restart.
+#[derive(Debug)]
+struct DeviceEntry {
+    handle: DeviceHandle,
+    connected: bool,
+    options: Option<Value>,
+}
+
+/// Pipes of a started plugin process.
+struct Running {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+/// Start the plugin process and read its capabilities.
+async fn spawn(settings: &ExternalPluginSettings) -> Result<(Running, Capabilities)> {
+    let mut command = Command::new(&settings.command);
+    command
+        .args(&settings.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(windows)]
+    {
+        // Don't open a console window for each plugin.
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Run {:?}", settings.command))?;
+    let stdin = child.stdin.take().context("No stdin")?;
+    let mut stdout = BufReader::new(child.stdout.take().context("No stdout")?).lines();
+
+    let line = tokio::time::timeout(CAPABILITIES_TIMEOUT, stdout.next_line())
+        .await
+        .context("Capabilities were not declared in time")?
+        .context("Read capabilities")?
+        .context("Exited without declaring capabilities")?;
+    match serde_json::from_str(&line).context("Parse capabilities")? {
+        PluginMessage::Capabilities(capabilities) => Ok((
+            Running {
+                child,
+                stdin,
+                stdout,
+            },
+            capabilities,
+        )),
+        message => anyhow::bail!("Expected capabilities, got {:?}", message),
+    }
+}
+
+async fn write_message<W>(stdin: &mut W, message: &HostMessage) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut line = serde_json::to_vec(message).expect("Failed to serialize message");
+    line.push(b'\n');
+    stdin.write_all(&line).await?;
+    stdin.flush().await
+}
+
+/// A running external plugin, shared by the devices it's enabled for.
+#[derive(Debug)]
+struct ExternalProcess {
+    name: String,
+    /// Declared on the first start, later changes need a restart of the daemon to be announced.
+    capabilities: Capabilities,
+    tx: mpsc::Sender<HostMessage>,
+    devices: Mutex<HashMap<String, DeviceEntry>>,
+}
+
+impl ExternalProcess {
+    async fn start(name: &str, settings: &ExternalPluginSettings) -> Result<Arc<Self>> {
+        let (running, capabilities) = spawn(settings).await?;
+        log::info!("Started external plugin {}: {:?}", name, capabilities);
+
+        let (tx, rx) = mpsc::channel(INBOX_CAPACITY);
+        let this = Arc::new(Self {
+            name: name.to_string(),
+            capabilities,
+            tx,
+            devices: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(this.clone().supervise(running, settings.clone(), rx));
+
+        Ok(this)
+    }
+
+    /// Pass messages to the plugin, and restart it whenever it exits.
+    async fn supervise(
+        self: Arc<Self>,
+        mut running: Running,
+        settings: ExternalPluginSettings,
+        mut rx: mpsc::Receiver<HostMessage>,
+    ) {
+        let mut delay = MIN_RESTART_DELAY;
+        loop {
+            let started = Instant::now();
+            self.run(&mut running.stdin, &mut running.stdout, &mut rx)
+                .await;
+
+            let _ = running.child.start_kill();
+            match running.child.wait().await {
+                Ok(status) => log::warn!("External plugin {} exited: {}", self.name, status),
+                Err(e) => log::error!("Failed to wait for external plugin {}: {:?}", self.name, e),
+            }
+
+            // A plugin that ran for a while is restarted right away, one that keeps exiting
+            // is restarted less and less often.
+            if started.elapsed() > MAX_RESTART_DELAY {
+                delay = MIN_RESTART_DELAY;
+            }
+            running = loop {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+                match spawn(&settings).await {
+                    Ok((running, capabilities)) => {
+                        if capabilities != self.capabilities {
+                            log::warn!(
+                                "External plugin {} changed its capabilities, restart to announce them",
+                                self.name
+                            );
+                        }
+                        break running;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to restart external plugin {}: {:?}", self.name, e)
+                    }
+                }
+            };
+            log::info!("Restarted external plugin {}", self.name);
+
+            // Messages queued while the plugin was down are replaced by the current state.
+            while rx.try_recv().is_ok() {}
+            self.replay_devices();
+        }
+    }
+
+    /// Exchange messages with the plugin until it closes its stdin or stdout, or doesn't read
+    /// a message within [`WRITE_TIMEOUT`].
+    ///
+    /// Reading and writing are independent, so that a plugin that is slow to read its input
+    /// still has its messages handled.
+    async fn run<W, R>(
+        &self,
+        stdin: &mut W,
+        stdout: &mut Lines<R>,
+        rx: &mut mpsc::Receiver<HostMessage>,
+    ) where
+        W: AsyncWrite + Unpin,
+        R: AsyncBufRead + Unpin,
+    {
+        let read = async {
+            loop {
+                match stdout.next_line().await {
+                    Ok(Some(line)) => self.handle_line(&line),
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::error!("Failed to read from external plugin {}: {:?}", self.name, e);
+                        break;
+                    }
+                }
+            }
+        };
+        let write = async {
+            while let Some(message) = rx.recv().await {
+                match tokio::time::timeout(WRITE_TIMEOUT, write_message(stdin, &message)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        log::error!("Failed to write to external plugin {}: {:?}", self.name, e);
+                        break;
+                    }
+                    Err(_) => {
+                        log::error!(
+                            "External plugin {} stopped reading its input, restarting it",
+                            self.name
+                        );
+                        break;
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = read => {}
+            _ = write => {}
+        }
+    }
+
+    /// Queue a message for the plugin, dropping it if the plugin is too far behind.
+    fn send(&self, message: HostMessage) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(message) {
+            log::warn!(
+                "External plugin {} is not reading its input, message dropped",
+                self.name
+            );
+        }
+    }
+
+    /// Send options and connection state of all devices, after the plugin restarted.
+    fn replay_devices(&self) {
+        let devices = self.devices.lock().unwrap();
+        for (device_id, entry) in devices.iter() {
+            self.send(HostMessage::Options {
+                device: device_id.clone(),
+                options: entry.options.clone(),
+            });
+            if entry.connected {
+                self.send(HostMessage::Connected {
+                    device: device_id.clone(),
+                    name: entry.handle.device_name(),
+                });
+            }
+        }
+    }
+
+    fn device(&self, device_id: &str) -> Result<DeviceHandle> {
+        match self.devices.lock().unwrap().get(device_id) {
+            Some(entry) => Ok(entry.handle.clone()),
+            None => anyhow::bail!("Plugin is not enabled for device {}", device_id),
+        }
+    }
+
+    fn handle_line(&self, line: &str) {
+        let message = match serde_json::from_str::<PluginMessage>(line) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Invalid message from external plugin {}: {}", self.name, e);
+                return;
+            }
+        };
+
+        match message {
+            PluginMessage::Capabilities(_) => {
+                log::warn!(
+                    "External plugin {} declared capabilities again, ignored",
+                    self.name
+                );
+            }
+            PluginMessage::Send {
+                device,
+                packet,
+                payload,
+                id,
+            } => {
+                let dev = self.device(&device);
+                let allowed = self.capabilities.outgoing.contains(&packet.typ);
+                let name = self.name.clone();
+                let tx = self.tx.clone();
+                tokio::spawn(async move {
+                    let result = async {
+                        if !allowed {
+                            anyhow::bail!("{} is not an outgoing capability", packet.typ);
+                        }
+                        let payload = payload
+                            .map(base64::decode)
+                            .transpose()
+                            .context("Decode payload")?;
+                        dev?.send_packet(NetworkPacketWithPayload {
+                            packet: NetworkPacket::new(packet.typ, packet.body),
+                            payload: payload.map(|data| Arc::new(data).into()),
+                        })
+                        .await?;
+                        Ok::<_, anyhow::Error>(())
+                    }
+                    .await;
+
+                    if let Err(e) = &result {
+                        log::warn!("External plugin {} failed to send: {:#}", name, e);
+                    }
+                    if let Some(id) = id {
+                        let error = result.err().map(|e| format!("{:#}", e));
+                        let _ = tx.send(HostMessage::Sent { id, error }).await;
+                    }
+                });
+            }
+            PluginMessage::FetchPayload {
+                id,
+                device,
+                port,
+                size,
+            } => {
+                if size > MAX_FETCH_SIZE {
+                    self.send(HostMessage::Payload {
+                        id,
+                        data: None,
+                        error: Some(format!(
+                            "Payload of {} bytes is over the limit of {} bytes",
+                            size, MAX_FETCH_SIZE
+                        )),
+                    });
+                    return;
+                }
+
+                let dev = self.device(&device);
+                let tx = self.tx.clone();
+                tokio::spawn(async move {
+                    let result = async { dev?.fetch_payload(port, size).await }.await;
+                    let message = match result {
+                        Ok(data) => HostMessage::Payload {
+                            id,
+                            data: Some(base64::encode(data)),
+                            error: None,
+                        },
+                        Err(e) => HostMessage::Payload {
+                            id,
+                            data: None,
+                            error: Some(format!("{:#}", e)),
+                        },
+                    };
+                    let _ = tx.send(message).await;
+                });
+            }
+            PluginMessage::Log { level, message } => {
+                log::log!(log::Level::from(level), "[{}] {}", self.name, message);
+            }
+        }
+    }
+}
+
+/// External plugins that were started, see [`ExternalPlugins::start`].
+#[derive(Debug, Default)]
+pub struct ExternalPlugins {
+    processes: BTreeMap<String, Arc<ExternalProcess>>,
+}
+
+impl ExternalPlugins {
+    /// Start the configured plugins and wait for them to declare their capabilities.
+    ///
+    /// Plugins that fail to start are left out, and can't be enabled until the next start.
+    pub async fn start(settings: &HashMap<String, ExternalPluginSettings>) -> Self {
+        let starts = settings.iter().map(|(name, settings)| async move {
+            (name, ExternalProcess::start(name, settings).await)
+        });
+
+        let mut processes = BTreeMap::new();
+        for (name, result) in futures::future::join_all(starts).await {
+            match result {
+                Ok(process) => {
+                    processes.insert(name.clone(), process);
+                }
+                Err(e) => log::error!("Failed to start external plugin {}: {:?}", name, e),
+            }
+        }
+
+        Self { processes }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.processes.keys().map(String::as_str)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.processes.contains_key(name)
+    }
+
+    /// Capabilities of all external plugins, to add to those of built-in plugins.
+    pub fn capabilities(&self) -> (Vec<String>, Vec<String>) {
+        let mut incoming_caps = vec![];
+        let mut outgoing_caps = vec![];
+        for process in self.processes.values() {
+            incoming_caps.extend(process.capabilities.incoming.iter().cloned());
+            outgoing_caps.extend(process.capabilities.outgoing.iter().cloned());
+        }
+        (incoming_caps, outgoing_caps)
+    }
+
+    /// Create the plugin for a device, like `create_plugin` does for built-in plugins.
+    pub fn create_plugin(
+        &self,
+        name: &str,
+        dev: DeviceHandle,
+    ) -> Result<(HashSet<String>, Arc<dyn KdeConnectPlugin>)> {
+        let process = self
+            .processes
+            .get(name)
+            .with_context(|| format!("External plugin {} is not running", name))?;
+        let in_caps = process.capabilities.incoming.iter().cloned().collect();
+        Ok((in_caps, Arc::new(ExternalPlugin::new(process.clone(), dev))))
+    }
+}
+
+/// An external plugin as loaded for one device, forwarding to the shared process.
+#[derive(Debug)]
+struct ExternalPlugin {
+    process: Arc<ExternalProcess>,
+    dev: DeviceHandle,
+}
+
+impl ExternalPlugin {
+    fn new(process: Arc<ExternalProcess>, dev: DeviceHandle) -> Self {
+        process.devices.lock().unwrap().insert(
+            dev.device_id().to_string(),
+            DeviceEntry {
+                handle: dev.clone(),
+                connected: false,
+                options: None,
+            },
+        );
+        Self { process, dev }
+    }
+
+    /// Update the state kept for this device and send the change, with the lock held so that
+    /// a restart in between can't reorder them.
+    fn update(&self, f: impl FnOnce(&mut DeviceEntry) -> HostMessage) {
+        let mut devices = self.process.devices.lock().unwrap();
+        if let Some(entry) = devices.get_mut(self.dev.device_id()) {
+            let message = f(entry);
+            self.process.send(message);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for ExternalPlugin {
+    async fn on_connected(self: Arc<Self>) -> Result<()> {
+        self.update(|entry| {
+            entry.connected = true;
+            HostMessage::Connected {
+                device: self.dev.device_id().to_string(),
+                name: self.dev.device_name(),
+            }
+        });
+        Ok(())
+    }
+
+    async fn on_disconnected(self: Arc<Self>) -> Result<()> {
+        self.update(|entry| {
+            entry.connected = false;
+            HostMessage::Disconnected {
+                device: self.dev.device_id().to_string(),
+            }
+        });
+        Ok(())
+    }
+
+    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
+        self.process.send(HostMessage::Packet {
+            device: self.dev.device_id().to_string(),
+            packet,
+        });
+        Ok(())
+    }
+
+    async fn apply_options(&self, options: Option<&toml::Value>) -> Result<()> {
+        let options = options.map(serde_json::to_value).transpose()?;
+        self.update(|entry| {
+            entry.options = options.clone();
+            HostMessage::Options {
+                device: self.dev.device_id().to_string(),
+                options,
+            }
+        });
+        Ok(())
+    }
+
+    async fn dispose(&self) {
+        let entry = self
+            .process
+            .devices
+            .lock()
+            .unwrap()
+            .remove(self.dev.device_id());
+        if matches!(entry, Some(entry) if entry.connected) {
+            self.process.send(HostMessage::Disconnected {
+                device: self.dev.device_id().to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn process(capacity: usize) -> (ExternalProcess, mpsc::Receiver<HostMessage>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let process = ExternalProcess {
+            name: "test".into(),
+            capabilities: Capabilities::default(),
+            tx,
+            devices: Mutex::new(HashMap::new()),
+        };
+        (process, rx)
+    }
+
+    /// Pipes of a plugin, with the other ends to act as the plugin.
+    struct Pipes {
+        stdin: DuplexStream,
+        stdout: Lines<BufReader<DuplexStream>>,
+        plugin_stdin: DuplexStream,
+        plugin_stdout: DuplexStream,
+    }
+
+    fn pipes() -> Pipes {
+        let (stdin, plugin_stdin) = tokio::io::duplex(64);
+        let (plugin_stdout, stdout) = tokio::io::duplex(64);
+        Pipes {
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+            plugin_stdin,
+            plugin_stdout,
+        }
+    }
+
+    fn large_message() -> HostMessage {
+        HostMessage::Disconnected {
+            device: "device".repeat(20),
+        }
+    }
+
+    #[test]
+    fn plugin_messages_are_parsed() {
+        let line = r#"{"type": "send", "device": "a", "packet": {"type": "kdeconnect.ping"}, "payload": "AAE=", "id": 1}"#;
+        match serde_json::from_str(line).unwrap() {
+            PluginMessage::Send {
+                device,
+                packet,
+                payload,
+                id,
+            } => {
+                assert_eq!(device, "a");
+                assert_eq!(packet.typ, "kdeconnect.ping");
+                assert_eq!(packet.body, Value::Null);
+                assert_eq!(payload.as_deref(), Some("AAE="));
+                assert_eq!(id, Some(1));
+            }
+            message => panic!("Unexpected message: {:?}", message),
+        }
+
+        let line = r#"{"type": "fetch_payload", "id": 2, "device": "a", "port": 1739, "size": 10}"#;
+        assert!(matches!(
+            serde_json::from_str(line).unwrap(),
+            PluginMessage::FetchPayload {
+                id: 2,
+                port: 1739,
+                size: 10,
+                ..
+            }
+        ));
+
+        let line = r#"{"type": "log", "level": "trace", "message": "Hello"}"#;
+        assert!(serde_json::from_str::<PluginMessage>(line).is_err());
+    }
+
+    #[test]
+    fn host_messages_are_serialized() {
+        let message = HostMessage::Connected {
+            device: "a".into(),
+            name: "Phone".into(),
+        };
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            serde_json::json!({"type": "connected", "device": "a", "name": "Phone"})
+        );
+
+        let message = HostMessage::Sent { id: 1, error: None };
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            serde_json::json!({"type": "sent", "id": 1})
+        );
+    }
+
+    #[tokio::test]
+    async fn large_payloads_are_refused() {
+        let (process, mut rx) = process(4);
+        let line = format!(
+            r#"{{"type": "fetch_payload", "id": 3, "device": "a", "port": 1739, "size": {}}}"#,
+            MAX_FETCH_SIZE + 1
+        );
+        process.handle_line(&line);
+
+        match rx.try_recv().unwrap() {
+            HostMessage::Payload {
+                id: 3,
+                data: None,
+                error: Some(error),
+            } => assert!(error.contains("over the limit"), "{}", error),
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_inbox_drops_messages() {
+        let (process, mut rx) = process(1);
+        process.send(large_message());
+        process.send(large_message());
+
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plugin_that_stops_reading_is_restarted() {
+        let (process, mut rx) = process(4);
+        let mut pipes = pipes();
+        for _ in 0..4 {
+            process.send(large_message());
+        }
+
+        let started = tokio::time::Instant::now();
+        tokio::time::timeout(
+            WRITE_TIMEOUT * 2,
+            process.run(&mut pipes.stdin, &mut pipes.stdout, &mut rx),
+        )
+        .await
+        .expect("Plugin was not given up on");
+        assert!(started.elapsed() >= WRITE_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn output_is_read_while_input_is_stuck() {
+        let (process, mut rx) = process(4);
+        let mut pipes = pipes();
+        process.send(large_message());
+
+        pipes
+            .plugin_stdout
+            .write_all(b"{\"type\": \"log\", \"level\": \"info\", \"message\": \"Hi\"}\n")
+            .await
+            .unwrap();
+        pipes.plugin_stdout.shutdown().await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        process
+            .run(&mut pipes.stdin, &mut pipes.stdout, &mut rx)
+            .await;
+        assert!(started.elapsed() < WRITE_TIMEOUT);
+    }
+}
@@ -0,0 +1,88 @@
+/*!
+This plugin makes the device ring, to find it. It sends an empty packet with type
+"kdeconnect.findmyphone.request", and the device rings until the user stops it there.
+ */
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+    device::{DeviceHandle, SendError},
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::{ContextMenu, MenuId, MenuItemAttributes},
+    utils,
+};
+
+use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+
+const PACKET_TYPE_FINDMYPHONE_REQUEST: &str = "kdeconnect.findmyphone.request";
+
+#[derive(Debug)]
+pub struct FindMyPhonePlugin {
+    dev: DeviceHandle,
+    menu_id: MenuId,
+}
+
+impl FindMyPhonePlugin {
+    pub fn new(dev: DeviceHandle) -> Self {
+        FindMyPhonePlugin {
+            menu_id: MenuId::new(&format!("{}:find-my-phone", dev.device_id())),
+            dev,
+        }
+    }
+
+    /// Not kept while the device is offline, it would ring long after it was asked to.
+    pub async fn ring(&self) -> Result<(), SendError> {
+        self.dev
+            .send_packet(NetworkPacket::new(
+                PACKET_TYPE_FINDMYPHONE_REQUEST,
+                serde_json::json!({}),
+            ))
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for FindMyPhonePlugin {
+    async fn handle(&self, _packet: NetworkPacket) -> Result<()> {
+        Ok(())
+    }
+
+    async fn tray_menu(&self, menu: &mut ContextMenu) {
+        menu.add_item(MenuItemAttributes::new("Ring").with_id(self.menu_id));
+    }
+
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event.is_menu_clicked(self.menu_id) {
+            if let Err(e) = self.ring().await {
+                utils::simple_toast(
+                    "Failed to ring",
+                    Some(&e.to_string()),
+                    Some(&self.dev.device_name()),
+                )
+                .await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, _params: serde_json::Value) -> Result<serde_json::Value> {
+        match method {
+            "ring" => {
+                self.ring().await?;
+                Ok(serde_json::Value::Null)
+            }
+            _ => anyhow::bail!("Unknown method: {}", method),
+        }
+    }
+}
+
+impl KdeConnectPluginMetadata for FindMyPhonePlugin {
+    fn incoming_capabilities() -> Vec<String> {
+        vec![]
+    }
+    fn outgoing_capabilities() -> Vec<String> {
+        vec![PACKET_TYPE_FINDMYPHONE_REQUEST.into()]
+    }
+}
@@ -1,27 +1,70 @@
-use anyhow::Result;
-use std::{collections::HashSet, sync::Arc};
-use tao::menu::ContextMenu;
+use anyhow::{Context, Result};
+use futures::FutureExt;
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{mpsc, RwLock};
+use tracing::{Instrument, Span};
 
 use crate::{
-    context::AppContextRef, device::DeviceHandle, event::SystemEvent, packet::NetworkPacket, utils,
+    context::{AppContextRef, ApplicationContext},
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::ContextMenu,
 };
 
+#[cfg(feature = "plugin-battery")]
 mod battery;
+#[cfg(all(feature = "plugin-clipboard", windows))]
 mod clipboard;
+#[cfg(feature = "plugin-connectivity-report")]
 mod connectivity_report;
+pub mod external;
+#[cfg(feature = "plugin-find-my-phone")]
+mod find_my_phone;
+#[cfg(all(feature = "plugin-input-receive", windows))]
 mod input_receive;
+#[cfg(any(feature = "plugin-mpris-send", feature = "plugin-mpris-remote"))]
 mod mpris;
+#[cfg(feature = "plugin-notification-receive")]
 mod notification_receive;
+#[cfg(feature = "plugin-ping")]
 mod ping;
+#[cfg(feature = "plugin-run-command")]
 mod run_command;
+#[cfg(feature = "plugin-share")]
 mod share;
+#[cfg(all(feature = "plugin-system-volume", windows))]
 mod system_volume;
 
+/// How long a plugin may take to handle a packet, an event or a connection change.
+const HANDLER_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[async_trait::async_trait]
 pub trait KdeConnectPlugin: std::fmt::Debug + Send + Sync {
     async fn start(self: Arc<Self>) -> Result<()> {
         Ok(())
     }
+    /// The device has connected, or reconnected after being offline.
+    ///
+    /// Requests for the current remote state belong here rather than in `start`, which is
+    /// only called once. Also called when the plugin is loaded while the device is connected.
+    async fn on_connected(self: Arc<Self>) -> Result<()> {
+        Ok(())
+    }
+    /// The device has disconnected, state that is no longer valid should be dropped.
+    async fn on_disconnected(self: Arc<Self>) -> Result<()> {
+        Ok(())
+    }
     async fn handle(&self, packet: NetworkPacket) -> Result<()>;
     async fn handle_event(self: Arc<Self>, _event: SystemEvent) -> Result<()> {
         Ok(())
@@ -31,166 +74,607 @@ pub trait KdeConnectPlugin: std::fmt::Debug + Send + Sync {
     }
     /// Create necessary context menu items for this plugin.
     async fn tray_menu(&self, _menu: &mut ContextMenu) {}
+    /// Apply options from user settings, `None` if the user has not set any.
+    ///
+    /// This is called after the plugin is loaded, and again each time settings are reloaded.
+    async fn apply_options(&self, _options: Option<&toml::Value>) -> Result<()> {
+        Ok(())
+    }
+    /// Short status shown along with the device, e.g. the battery level.
+    async fn status(&self) -> Option<String> {
+        None
+    }
+    /// State to keep while the device is offline, including across restarts.
+    async fn save_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+    /// Restore state saved by [`KdeConnectPlugin::save_state`] in a previous run.
+    async fn restore_state(&self, _state: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+    /// Run a method on behalf of a local client, see [`crate::control`].
+    ///
+    /// `params` is a JSON object, the result is sent back to the client as it is.
+    async fn call(&self, method: &str, _params: serde_json::Value) -> Result<serde_json::Value> {
+        Err(anyhow::anyhow!("Unknown method: {}", method))
+    }
     async fn dispose(&self) {}
 }
 
 pub trait KdeConnectPluginMetadata {
     fn incoming_capabilities() -> Vec<String>;
     fn outgoing_capabilities() -> Vec<String>;
+    /// Check that user-provided options are valid, without applying them.
+    fn validate_options(_options: &toml::Value) -> Result<()> {
+        Ok(())
+    }
 }
 
-lazy_static::lazy_static! {
-    pub static ref ALL_CAPS: (Vec<String>, Vec<String>) = {
-        let mut incoming_caps = vec![];
-        let mut outgoing_caps = vec![];
-
-        incoming_caps.extend(ping::PingPlugin::incoming_capabilities());
-        outgoing_caps.extend(ping::PingPlugin::outgoing_capabilities());
-        // incoming_caps
-        //     .extend(connectivity_report::ConnectivityReportPlugin::incoming_capabilities());
-        // outgoing_caps
-        //     .extend(connectivity_report::ConnectivityReportPlugin::outgoing_capabilities());
-        incoming_caps.extend(clipboard::ClipboardPlugin::incoming_capabilities());
-        outgoing_caps.extend(clipboard::ClipboardPlugin::outgoing_capabilities());
-        incoming_caps.extend(mpris::MprisPlugin::incoming_capabilities());
-        outgoing_caps.extend(mpris::MprisPlugin::outgoing_capabilities());
-        incoming_caps.extend(mpris::remote::MprisRemotePlugin::incoming_capabilities());
-        outgoing_caps.extend(mpris::remote::MprisRemotePlugin::outgoing_capabilities());
-        incoming_caps
-            .extend(notification_receive::NotificationReceivePlugin::incoming_capabilities());
-        outgoing_caps
-            .extend(notification_receive::NotificationReceivePlugin::outgoing_capabilities());
-        incoming_caps.extend(input_receive::InputReceivePlugin::incoming_capabilities());
-        outgoing_caps.extend(input_receive::InputReceivePlugin::outgoing_capabilities());
-        incoming_caps.extend(battery::BatteryPlugin::incoming_capabilities());
-        outgoing_caps.extend(battery::BatteryPlugin::outgoing_capabilities());
-        incoming_caps.extend(share::SharePlugin::incoming_capabilities());
-        outgoing_caps.extend(share::SharePlugin::outgoing_capabilities());
-        incoming_caps.extend(run_command::RunCommandPlugin::incoming_capabilities());
-        outgoing_caps.extend(run_command::RunCommandPlugin::outgoing_capabilities());
-        incoming_caps.extend(system_volume::SystemVolumePlugin::incoming_capabilities());
-        outgoing_caps.extend(system_volume::SystemVolumePlugin::outgoing_capabilities());
-
-        (incoming_caps, outgoing_caps)
+/// Declare all plugins: the name used in settings, the cargo feature that includes the plugin
+/// in the build, its type and how it's created for a device. Plugins that need Windows APIs are
+/// also marked with `windows`, and left out of the build elsewhere.
+///
+/// This generates the list of plugins, their capabilities and the functions to validate
+/// options and create plugins by name. The order also determines the order in which plugins
+/// are shown in the tray menu.
+macro_rules! plugins {
+    ($(
+        $name:literal ($feature:literal $(, $platform:ident)?) =>
+            $plugin:ty = |$dev:pat_param, $ctx:pat_param| $create:expr;
+    )*) => {
+        /// Names of the plugins included in this build.
+        const PLUGINS: &[&str] = &[$(#[cfg(all(feature = $feature $(, $platform)?))] $name,)*];
+
+        /// Names of all plugins, including those left out of this build.
+        const KNOWN_PLUGINS: &[&str] = &[$($name,)*];
+
+        fn all_capabilities() -> (Vec<String>, Vec<String>) {
+            let mut incoming_caps = vec![];
+            let mut outgoing_caps = vec![];
+            $(
+                #[cfg(all(feature = $feature $(, $platform)?))]
+                {
+                    incoming_caps.extend(<$plugin>::incoming_capabilities());
+                    outgoing_caps.extend(<$plugin>::outgoing_capabilities());
+                }
+            )*
+            (incoming_caps, outgoing_caps)
+        }
+
+        /// Check that the user-provided options are valid for the named plugin.
+        pub fn validate_options(name: &str, options: &toml::Value) -> Result<()> {
+            match name {
+                $(
+                    #[cfg(all(feature = $feature $(, $platform)?))]
+                    $name => <$plugin>::validate_options(options),
+                )*
+                name if KNOWN_PLUGINS.contains(&name) => {
+                    log::warn!("Plugin {} is not included in this build", name);
+                    Ok(())
+                }
+                name => anyhow::bail!("Unknown plugin: {}", name),
+            }
+        }
+
+        async fn create_plugin(
+            name: &str,
+            dev: DeviceHandle,
+            ctx: AppContextRef,
+        ) -> Result<(HashSet<String>, Arc<dyn KdeConnectPlugin>)> {
+            fn entry<P>(plugin: P) -> (HashSet<String>, Arc<dyn KdeConnectPlugin>)
+            where
+                P: KdeConnectPlugin + KdeConnectPluginMetadata + 'static,
+            {
+                (
+                    P::incoming_capabilities().into_iter().collect(),
+                    Arc::new(plugin),
+                )
+            }
+
+            Ok(match name {
+                $(
+                    #[cfg(all(feature = $feature $(, $platform)?))]
+                    $name => {
+                        let ($dev, $ctx) = (dev, ctx);
+                        entry::<$plugin>($create)
+                    }
+                )*
+                name => anyhow::bail!("Unknown plugin: {}", name),
+            })
+        }
     };
 }
 
+plugins! {
+    "battery" ("plugin-battery") =>
+        battery::BatteryPlugin = |dev, ctx| battery::BatteryPlugin::new(dev, ctx);
+    "ping" ("plugin-ping") =>
+        ping::PingPlugin = |dev, _| ping::PingPlugin::new(dev);
+    "find-my-phone" ("plugin-find-my-phone") =>
+        find_my_phone::FindMyPhonePlugin = |dev, _| find_my_phone::FindMyPhonePlugin::new(dev);
+    "connectivity-report" ("plugin-connectivity-report") =>
+        connectivity_report::ConnectivityReportPlugin =
+            |_, _| connectivity_report::ConnectivityReportPlugin;
+    "clipboard" ("plugin-clipboard", windows) =>
+        clipboard::ClipboardPlugin = |dev, _| clipboard::ClipboardPlugin::new(dev);
+    "mpris-send" ("plugin-mpris-send", windows) =>
+        mpris::MprisPlugin = |dev, ctx| mpris::MprisPlugin::new(dev, ctx).await?;
+    "mpris-remote" ("plugin-mpris-remote") =>
+        mpris::remote::MprisRemotePlugin =
+            |dev, ctx| mpris::remote::MprisRemotePlugin::new(dev, ctx);
+    "notification-receive" ("plugin-notification-receive") =>
+        notification_receive::NotificationReceivePlugin =
+            |dev, ctx| notification_receive::NotificationReceivePlugin::new(dev, ctx);
+    "input-receive" ("plugin-input-receive", windows) =>
+        input_receive::InputReceivePlugin = |_, _| input_receive::InputReceivePlugin;
+    "share" ("plugin-share") =>
+        share::SharePlugin = |dev, ctx| share::SharePlugin::new(dev, ctx);
+    "run-command" ("plugin-run-command") =>
+        run_command::RunCommandPlugin = |dev, _| run_command::RunCommandPlugin::new(dev);
+    "system-volume" ("plugin-system-volume", windows) =>
+        system_volume::SystemVolumePlugin = |dev, _| system_volume::SystemVolumePlugin::new(dev);
+}
+
+lazy_static::lazy_static! {
+    static ref ALL_CAPS: (Vec<String>, Vec<String>) = all_capabilities();
+}
+
+/// Capabilities of built-in and external plugins, to announce in the identity packet.
+pub fn capabilities(ctx: &ApplicationContext) -> (Vec<String>, Vec<String>) {
+    let (mut incoming_caps, mut outgoing_caps) = ALL_CAPS.clone();
+    let (external_incoming, external_outgoing) = ctx.external_plugins.capabilities();
+    incoming_caps.extend(external_incoming);
+    outgoing_caps.extend(external_outgoing);
+
+    for caps in [&mut incoming_caps, &mut outgoing_caps] {
+        caps.sort();
+        caps.dedup();
+    }
+    (incoming_caps, outgoing_caps)
+}
+
+/// Whether the name is taken by a built-in plugin, even one left out of this build.
+pub fn is_builtin(name: &str) -> bool {
+    KNOWN_PLUGINS.contains(&name)
+}
+
+/// Deserialize plugin options, falling back to defaults if there's none.
+fn parse_options<T>(options: Option<&toml::Value>) -> Result<T>
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    match options {
+        Some(options) => Ok(T::deserialize(options.clone())?),
+        None => Ok(T::default()),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Run a plugin handler, logging errors, panics and handlers that take too long.
+async fn run_handler(plugin: &str, action: &str, handler: impl Future<Output = Result<()>>) {
+    let handler = AssertUnwindSafe(handler).catch_unwind();
+    match tokio::time::timeout(HANDLER_TIMEOUT, handler).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => log::error!("Plugin {} failed to {}: {:?}", plugin, action, e),
+        Ok(Err(panic)) => log::error!(
+            "Plugin {} panicked trying to {}: {}",
+            plugin,
+            action,
+            panic_message(&*panic)
+        ),
+        Err(_) => log::error!(
+            "Plugin {} did not {} within {:?}, cancelled",
+            plugin,
+            action,
+            HANDLER_TIMEOUT
+        ),
+    }
+}
+
+type PacketSender = mpsc::UnboundedSender<(NetworkPacket, Span)>;
+
+/// Something for a plugin to handle, see [`PluginEntry::inbox`].
+#[derive(Debug)]
+enum Work {
+    Packet(NetworkPacket, Span),
+    /// The device has connected or disconnected.
+    Link(bool),
+    Event(SystemEvent),
+}
+
+#[derive(Debug)]
+struct PluginEntry {
+    name: String,
+    in_caps: HashSet<String>,
+    plugin: Arc<dyn KdeConnectPlugin>,
+    /// Work for this plugin, handled one at a time once it has started. Dropping it stops
+    /// the handler task.
+    inbox: mpsc::UnboundedSender<Work>,
+}
+
+/// Start the plugin, then handle its work in order until the inbox is dropped.
+///
+/// Each plugin runs in its own task, so that a slow or failing plugin doesn't hold up the
+/// others.
+async fn run_plugin(
+    name: String,
+    plugin: Arc<dyn KdeConnectPlugin>,
+    mut work_rx: mpsc::UnboundedReceiver<Work>,
+) {
+    run_handler(&name, "start", plugin.clone().start()).await;
+    while let Some(work) = work_rx.recv().await {
+        match work {
+            Work::Packet(packet, span) => {
+                run_handler(&name, "handle packet", plugin.handle(packet))
+                    .instrument(span)
+                    .await
+            }
+            Work::Link(true) => {
+                run_handler(&name, "handle connection", plugin.clone().on_connected()).await
+            }
+            Work::Link(false) => {
+                run_handler(
+                    &name,
+                    "handle disconnection",
+                    plugin.clone().on_disconnected(),
+                )
+                .await
+            }
+            Work::Event(event) => {
+                run_handler(&name, "handle event", plugin.clone().handle_event(event)).await
+            }
+        }
+    }
+}
+
+/// Pass packets to the plugins that handle them, in the order they were received.
+///
+/// Each plugin has its own queue, so that a slow plugin doesn't hold up the others.
+async fn dispatch_packets(
+    plugins: Arc<RwLock<Vec<PluginEntry>>>,
+    mut rx: mpsc::UnboundedReceiver<(NetworkPacket, Span)>,
+) {
+    while let Some((packet, span)) = rx.recv().await {
+        let mut handled = false;
+        for entry in plugins.read().await.iter() {
+            if entry.in_caps.contains(&packet.typ) {
+                let _ = entry.inbox.send(Work::Packet(packet.clone(), span.clone()));
+                handled = true;
+            }
+        }
+
+        if !handled {
+            span.in_scope(|| tracing::warn!("No plugin found for packet type {}", packet.typ));
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PluginRepository {
-    plugins: Vec<(HashSet<String>, Arc<dyn KdeConnectPlugin>)>,
-    pub incoming_caps: HashSet<String>,
-    pub outgoing_caps: HashSet<String>,
+    plugins: Arc<RwLock<Vec<PluginEntry>>>,
+    packet_tx: PacketSender,
+    connected: AtomicBool,
     dev: DeviceHandle,
+    ctx: AppContextRef,
 }
 
 impl PluginRepository {
-    pub async fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
-        let mut this = Self {
-            plugins: vec![],
-            incoming_caps: HashSet::new(),
-            outgoing_caps: HashSet::new(),
-            dev: dev.clone(),
-        };
+    /// Create the repository, plugins are loaded in background.
+    ///
+    /// Packets, events and other calls wait until loading has finished.
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Arc<Self> {
+        let plugins = Arc::new(RwLock::new(vec![]));
+        let (packet_tx, packet_rx) = mpsc::unbounded_channel();
+        tokio::spawn(dispatch_packets(plugins.clone(), packet_rx));
 
-        // This also determines the order in which plugins are shown in tray menu.
-        this.register(battery::BatteryPlugin::new(dev.clone(), ctx.clone()));
-        this.register(ping::PingPlugin::new(dev.clone()));
-        // this.register(connectivity_report::ConnectivityReportPlugin);
-        this.register(clipboard::ClipboardPlugin::new(dev.clone()));
-        utils::log_if_error(
-            "Failed to initialize MPRIS plugin",
-            mpris::MprisPlugin::new(dev.clone(), ctx.clone())
-                .await
-                .map(|p| this.register(p)),
-        );
-        this.register(mpris::remote::MprisRemotePlugin::new(
-            dev.clone(),
-            ctx.clone(),
-        ));
-        this.register(notification_receive::NotificationReceivePlugin::new(
-            dev.clone(),
-            ctx.clone(),
-        ));
-        this.register(input_receive::InputReceivePlugin);
-        this.register(share::SharePlugin::new(dev.clone()));
-        this.register(run_command::RunCommandPlugin::new(dev.clone()));
-        this.register(system_volume::SystemVolumePlugin::new(dev.clone()));
-
-        // Start the plugins
+        let this = Arc::new(Self {
+            plugins,
+            packet_tx,
+            connected: AtomicBool::new(false),
+            dev,
+            ctx,
+        });
+
+        // Nobody else has the lock yet, so this never fails.
         let plugins = this
             .plugins
-            .iter()
-            .map(|(_, p)| Arc::clone(p))
-            .collect::<Vec<_>>();
+            .clone()
+            .try_write_owned()
+            .expect("Plugins are locked");
+        let repo = this.clone();
         tokio::spawn(async move {
-            for plugin in plugins {
-                if let Err(e) = plugin.clone().start().await {
-                    log::error!("Failed to start plugin {:?}: {:?}", plugin, e);
-                }
-            }
+            let mut plugins = plugins;
+            repo.apply_settings_locked(&mut plugins).await;
         });
 
         this
     }
 
-    pub fn register<P>(&mut self, plugin: P)
-    where
-        P: KdeConnectPlugin + KdeConnectPluginMetadata + 'static,
-    {
-        let in_caps = P::incoming_capabilities();
-        let out_caps = P::outgoing_capabilities();
+    /// Load the built-in or external plugin and start it in background.
+    async fn load(&self, name: &str) -> Result<PluginEntry> {
+        let (in_caps, plugin) = if self.ctx.external_plugins.contains(name) {
+            self.ctx
+                .external_plugins
+                .create_plugin(name, self.dev.clone())?
+        } else {
+            create_plugin(name, self.dev.clone(), self.ctx.clone()).await?
+        };
 
-        log::debug!(
-            "Registering plugin: {:?} with in={:?}, out={:?}",
-            plugin,
-            in_caps,
-            out_caps
-        );
+        log::debug!("Loaded plugin {}: {:?} with in={:?}", name, plugin, in_caps);
 
-        self.incoming_caps.extend(in_caps.iter().cloned());
-        self.outgoing_caps.extend(out_caps.into_iter());
+        // Only read with the plugins locked for writing, see `set_connected`.
+        let (inbox, work_rx) = mpsc::unbounded_channel();
+        if self.connected.load(Ordering::Relaxed) {
+            let _ = inbox.send(Work::Link(true));
+        }
 
-        self.plugins
-            .push((in_caps.into_iter().collect(), Arc::new(plugin)));
+        tokio::spawn(run_plugin(name.to_string(), plugin.clone(), work_rx));
+
+        Ok(PluginEntry {
+            name: name.to_string(),
+            in_caps,
+            plugin,
+            inbox,
+        })
     }
 
-    pub async fn handle_packet(&self, packet: NetworkPacket) -> Result<()> {
-        let typ = packet.typ.as_str();
+    /// Load enabled plugins, unload disabled ones and update plugin options.
+    pub async fn apply_settings(&self) {
+        let mut plugins = self.plugins.write().await;
+        self.apply_settings_locked(&mut plugins).await;
+    }
 
-        tracing::debug!("Incoming packet: {:?}", packet);
+    async fn apply_settings_locked(&self, plugins: &mut Vec<PluginEntry>) {
+        // Read settings with the lock held, so that the latest ones are applied last.
+        let settings = self.ctx.settings();
+        let device_id = self.dev.device_id();
 
-        let mut handled = false;
-        for (in_caps, plguin) in &self.plugins {
-            if in_caps.contains(typ) {
-                plguin.handle(packet.clone()).await?;
-                handled = true;
+        let mut i = 0;
+        while i < plugins.len() {
+            if settings.plugin_enabled(device_id, &plugins[i].name) {
+                i += 1;
+            } else {
+                let entry = plugins.remove(i);
+                log::info!("Unloading plugin {} for {}", entry.name, device_id);
+                entry.plugin.dispose().await;
             }
         }
 
-        if handled {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("No plugin found for packet type {}", typ))
+        let names = PLUGINS
+            .iter()
+            .copied()
+            .chain(self.ctx.external_plugins.names());
+        for name in names {
+            if !settings.plugin_enabled(device_id, name) || plugins.iter().any(|p| p.name == name) {
+                continue;
+            }
+
+            match self.load(name).await {
+                Ok(entry) => plugins.push(entry),
+                Err(e) => log::error!("Failed to load plugin {}: {:?}", name, e),
+            }
+        }
+
+        // External plugins come after built-in ones.
+        plugins.sort_by_key(|p| {
+            PLUGINS
+                .iter()
+                .position(|name| *name == p.name)
+                .unwrap_or(usize::MAX)
+        });
+
+        for entry in plugins.iter() {
+            let options = settings.plugin_options(device_id, &entry.name);
+            if let Err(e) = entry.plugin.apply_options(options).await {
+                log::error!("Failed to apply options to plugin {}: {:?}", entry.name, e);
+            }
+        }
+    }
+
+    /// Queue the connection hooks of all plugins, each plugin runs them after the work it
+    /// already has.
+    pub async fn set_connected(&self, connected: bool) {
+        // Changed with the lock held, so that a plugin being loaded either sees the new state
+        // or is already in the list, and the hook is queued exactly once.
+        let plugins = self.plugins.read().await;
+        self.connected.store(connected, Ordering::Relaxed);
+
+        for entry in plugins.iter() {
+            let _ = entry.inbox.send(Work::Link(connected));
         }
     }
 
+    /// Queue a packet for the plugins that handle it, `span` is entered while handling it.
+    ///
+    /// Packets are handled in order by each plugin, without waiting for other plugins.
+    pub fn dispatch_packet(&self, packet: NetworkPacket, span: Span) {
+        span.in_scope(|| tracing::debug!("Incoming packet: {:?}", packet));
+        let _ = self.packet_tx.send((packet, span));
+    }
+
+    /// Queue an event for all plugins, each plugin handles it after the work it already has.
     pub async fn handle_event(&self, event: SystemEvent) {
-        for (_, plugin) in &self.plugins {
-            if let Err(e) = plugin.clone().handle_event(event).await {
-                log::error!("Error handling event: {}", e);
-            }
+        for entry in self.plugins.read().await.iter() {
+            let _ = entry.inbox.send(Work::Event(event));
+        }
+    }
+
+    /// Call a method of the named plugin, see [`KdeConnectPlugin::call`].
+    pub async fn call(
+        &self,
+        plugin: &str,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let entry = self
+            .plugins
+            .read()
+            .await
+            .iter()
+            .find(|p| p.name == plugin)
+            .map(|p| p.plugin.clone())
+            .with_context(|| format!("Plugin {} is not enabled", plugin))?;
+
+        match tokio::time::timeout(HANDLER_TIMEOUT, entry.call(method, params)).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("No result within {:?}", HANDLER_TIMEOUT),
         }
     }
 
     pub async fn create_tray_menu(&self, menu: &mut ContextMenu) {
-        for (_, plugin) in &self.plugins {
-            plugin.tray_menu(menu).await;
+        for entry in self.plugins.read().await.iter() {
+            entry.plugin.tray_menu(menu).await;
+        }
+    }
+
+    pub async fn status(&self) -> Vec<String> {
+        let mut status = vec![];
+        for entry in self.plugins.read().await.iter() {
+            status.extend(entry.plugin.status().await);
+        }
+        status
+    }
+
+    pub async fn save_state(&self) -> HashMap<String, serde_json::Value> {
+        let mut states = HashMap::new();
+        for entry in self.plugins.read().await.iter() {
+            if let Some(state) = entry.plugin.save_state().await {
+                states.insert(entry.name.clone(), state);
+            }
+        }
+        states
+    }
+
+    pub async fn restore_state(&self, states: &HashMap<String, serde_json::Value>) {
+        for entry in self.plugins.read().await.iter() {
+            if let Some(state) = states.get(&entry.name) {
+                if let Err(e) = entry.plugin.restore_state(state.clone()).await {
+                    log::error!("Failed to restore state of plugin {}: {:?}", entry.name, e);
+                }
+            }
         }
     }
 
     pub async fn dispose(&self) {
-        for (_, plugin) in &self.plugins {
-            plugin.dispose().await;
+        for entry in self.plugins.read().await.iter() {
+            entry.plugin.dispose().await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records what it handled, and fails, panics or hangs on packets of the matching type.
+    #[derive(Debug, Default)]
+    struct RecordingPlugin {
+        handled: Mutex<Vec<String>>,
+    }
+
+    impl RecordingPlugin {
+        fn record(&self, what: &str) {
+            self.handled.lock().unwrap().push(what.to_string());
+        }
+
+        fn handled(&self) -> Vec<String> {
+            self.handled.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl KdeConnectPlugin for RecordingPlugin {
+        async fn on_connected(self: Arc<Self>) -> Result<()> {
+            self.record("connected");
+            Ok(())
+        }
+
+        async fn handle(&self, packet: NetworkPacket) -> Result<()> {
+            match packet.typ.as_str() {
+                "test.fail" => anyhow::bail!("Failed on purpose"),
+                "test.panic" => panic!("Panicked on purpose"),
+                "test.hang" => std::future::pending().await,
+                typ => {
+                    self.record(typ);
+                    Ok(())
+                }
+            }
+        }
+
+        async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+            self.record(&format!("{:?}", event));
+            Ok(())
+        }
+    }
+
+    fn spawn_plugin(plugin: &Arc<RecordingPlugin>) -> mpsc::UnboundedSender<Work> {
+        let (inbox, work_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_plugin("test".into(), plugin.clone(), work_rx));
+        inbox
+    }
+
+    fn packet(typ: &str) -> Work {
+        Work::Packet(NetworkPacket::new(typ, ()), Span::none())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn work_is_handled_in_order_despite_failures() {
+        let plugin = Arc::new(RecordingPlugin::default());
+        let inbox = spawn_plugin(&plugin);
+        for work in [
+            packet("test.first"),
+            Work::Link(true),
+            packet("test.panic"),
+            packet("test.second"),
+            packet("test.fail"),
+            Work::Event(SystemEvent::ClipboardUpdated),
+            packet("test.hang"),
+            packet("test.third"),
+        ] {
+            inbox.send(work).unwrap();
+        }
+
+        tokio::time::sleep(HANDLER_TIMEOUT * 2).await;
+        assert_eq!(
+            plugin.handled(),
+            [
+                "test.first",
+                "connected",
+                "test.second",
+                "ClipboardUpdated",
+                "test.third"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_plugin_does_not_delay_others() {
+        let stuck = Arc::new(RecordingPlugin::default());
+        let other = Arc::new(RecordingPlugin::default());
+        let stuck_inbox = spawn_plugin(&stuck);
+        let other_inbox = spawn_plugin(&other);
+
+        stuck_inbox.send(packet("test.hang")).unwrap();
+        stuck_inbox.send(packet("test.after")).unwrap();
+        other_inbox.send(packet("test.after")).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(stuck.handled().is_empty());
+        assert_eq!(other.handled(), ["test.after"]);
+
+        // The stuck handler is cancelled once it times out.
+        tokio::time::sleep(HANDLER_TIMEOUT).await;
+        assert_eq!(stuck.handled(), ["test.after"]);
+    }
+}
@@ -1,20 +1,37 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use crate::{
     context::AppContextRef,
-    device::DeviceHandle,
+    device::{DeviceHandle, SendError},
     event::SystemEvent,
     packet::NetworkPacket,
     plugin::{KdeConnectPlugin, KdeConnectPluginMetadata},
+    tray::{ContextMenu, MenuId, MenuItem, MenuItemAttributes},
+    utils,
 };
 use anyhow::Result;
-use tao::menu::{ContextMenu, MenuId, MenuItem, MenuItemAttributes};
+use serde::Deserialize;
 use tokio::sync::RwLock;
 
 use super::{
     MprisMetadata, MprisPacket, MprisRequest, PACKET_TYPE_MPRIS, PACKET_TYPE_MPRIS_REQUEST,
 };
 
+/// Name of the plugin in settings, see [`DeviceHandle::plugin_changed`].
+const PLUGIN_NAME: &str = "mpris-remote";
+
+/// Actions that can be sent to a remote player.
+const ACTIONS: &[&str] = &["Play", "Pause", "PlayPause", "Stop", "Next", "Previous"];
+
+#[derive(Debug, Deserialize)]
+struct ActionParams {
+    player: String,
+    action: String,
+}
+
 #[derive(Debug)]
 struct Player {
     metadata: Option<MprisMetadata>,
@@ -40,6 +57,7 @@ impl Player {
 pub struct MprisRemotePlugin {
     ctx: AppContextRef,
     dev: DeviceHandle,
+    /// Players of the device, kept while it's offline and refreshed on reconnect.
     players: RwLock<HashMap<String, Player>>,
 }
 
@@ -52,7 +70,7 @@ impl MprisRemotePlugin {
         }
     }
 
-    async fn request_player_list(&self) {
+    async fn request_player_list(&self) -> Result<(), SendError> {
         self.dev
             .send_packet(NetworkPacket::new(
                 PACKET_TYPE_MPRIS_REQUEST,
@@ -61,10 +79,10 @@ impl MprisRemotePlugin {
                     ..Default::default()
                 },
             ))
-            .await;
+            .await
     }
 
-    async fn request_now_playing(&self, player_id: &str) {
+    async fn request_now_playing(&self, player_id: &str) -> Result<(), SendError> {
         self.dev
             .send_packet(NetworkPacket::new(
                 PACKET_TYPE_MPRIS_REQUEST,
@@ -74,10 +92,10 @@ impl MprisRemotePlugin {
                     ..Default::default()
                 },
             ))
-            .await;
+            .await
     }
 
-    async fn send_action(&self, player_id: &str, action: &str) {
+    async fn send_action(&self, player_id: &str, action: &str) -> Result<(), SendError> {
         let mut commands = HashMap::new();
         commands.insert("action".to_string(), serde_json::Value::from(action));
 
@@ -90,14 +108,39 @@ impl MprisRemotePlugin {
                     ..Default::default()
                 },
             ))
-            .await;
+            .await
     }
 }
 
 #[async_trait::async_trait]
 impl KdeConnectPlugin for MprisRemotePlugin {
-    async fn start(self: Arc<Self>) -> Result<()> {
-        self.request_player_list().await;
+    async fn on_connected(self: Arc<Self>) -> Result<()> {
+        self.request_player_list().await?;
+        Ok(())
+    }
+
+    async fn save_state(&self) -> Option<serde_json::Value> {
+        let players = self.players.read().await;
+        if players.is_empty() {
+            return None;
+        }
+        let players = players
+            .iter()
+            .map(|(id, player)| (id, &player.metadata))
+            .collect::<BTreeMap<_, _>>();
+        serde_json::to_value(players).ok()
+    }
+
+    async fn restore_state(&self, state: serde_json::Value) -> Result<()> {
+        let saved: BTreeMap<String, Option<MprisMetadata>> = serde_json::from_value(state)?;
+        let mut players = self.players.write().await;
+        for (id, metadata) in saved {
+            // Players received since then are more recent.
+            players.entry(id).or_insert_with_key(|id| Player {
+                metadata,
+                ..Player::new(self.dev.device_id(), id)
+            });
+        }
         Ok(())
     }
 
@@ -120,12 +163,14 @@ impl KdeConnectPlugin for MprisRemotePlugin {
                     }
                 }
                 self.ctx.update_tray().await;
+                self.dev.plugin_changed(PLUGIN_NAME);
             }
             MprisPacket::Metadata(metadata) => {
                 let mut players = self.players.write().await;
                 if let Some(player) = players.get_mut(&metadata.properties.player) {
                     player.metadata = Some(metadata);
                     self.ctx.update_tray().await;
+                    self.dev.plugin_changed(PLUGIN_NAME);
                 }
             }
             MprisPacket::TransferringAlbumArt { .. } => {
@@ -186,17 +231,66 @@ impl KdeConnectPlugin for MprisRemotePlugin {
             let players = self.players.read().await;
 
             for (id, player) in players.iter() {
-                if menu_id == player.play_menu_id {
-                    self.send_action(id, "PlayPause").await;
+                let action = if menu_id == player.play_menu_id {
+                    "PlayPause"
                 } else if menu_id == player.previous_menu_id {
-                    self.send_action(id, "Previous").await;
+                    "Previous"
                 } else if menu_id == player.next_menu_id {
-                    self.send_action(id, "Next").await;
+                    "Next"
+                } else {
+                    continue;
+                };
+
+                if let Err(e) = self.send_action(id, action).await {
+                    utils::simple_toast(
+                        "Failed to control media",
+                        Some(&e.to_string()),
+                        Some(&self.dev.device_name()),
+                    )
+                    .await;
                 }
             }
         }
         Ok(())
     }
+
+    async fn call(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        match method {
+            "players" => {
+                let players = self.players.read().await;
+                let players = players
+                    .iter()
+                    .map(|(id, player)| match &player.metadata {
+                        Some(metadata) => serde_json::json!({
+                            "id": id,
+                            "is_playing": metadata.status.is_playing,
+                            "now_playing": metadata.properties.now_playing,
+                            "can_go_next": metadata.status.can_go_next,
+                            "can_go_previous": metadata.status.can_go_previous,
+                        }),
+                        None => serde_json::json!({ "id": id }),
+                    })
+                    .collect();
+                Ok(serde_json::Value::Array(players))
+            }
+            "action" => {
+                let params: ActionParams = serde_json::from_value(params)?;
+                if !ACTIONS.contains(&params.action.as_str()) {
+                    anyhow::bail!(
+                        "Unknown action {}, expected one of {:?}",
+                        params.action,
+                        ACTIONS
+                    );
+                }
+                if !self.players.read().await.contains_key(&params.player) {
+                    anyhow::bail!("Unknown player: {}", params.player);
+                }
+                self.send_action(&params.player, &params.action).await?;
+                Ok(serde_json::Value::Null)
+            }
+            _ => anyhow::bail!("Unknown method: {}", method),
+        }
+    }
 }
 
 impl KdeConnectPluginMetadata for MprisRemotePlugin {
@@ -36,30 +36,43 @@ set to true. The other device will answer with a notification package with
 "isCancel" set to true when it is dismissed.
  */
 use std::{
+    collections::BTreeMap,
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::Duration,
 };
 
 use anyhow::{Context, Result};
 use lru_cache::LruCache;
 use serde::{Deserialize, Serialize};
-use tao::menu::{ContextMenu, MenuId, MenuItemAttributes};
-use tokio::sync::Mutex;
+#[cfg(windows)]
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+#[cfg(windows)]
 use winrt_toast::{DismissalReason, Header, Text, Toast};
 
 use crate::{
-    cache::PAYLOAD_CACHE, context::AppContextRef, device::DeviceHandle, event::SystemEvent,
-    packet::NetworkPacket, utils,
+    cache::PAYLOAD_CACHE,
+    context::AppContextRef,
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::{ContextMenu, MenuId, MenuItemAttributes},
+    utils,
 };
 
 use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
 
 const PACKET_TYPE_NOTIFICATION_REQUEST: &str = "kdeconnect.notification.request";
 
+/// Name of the plugin's store, see [`DeviceHandle::store`].
+const STORE_NAME: &str = "notification-receive";
+
+/// Name of the plugin in settings, see [`DeviceHandle::plugin_changed`].
+const PLUGIN_NAME: &str = "notification-receive";
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 enum NotificationBody {
@@ -83,6 +96,33 @@ struct IncomingNotification {
     text: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DismissAction {
+    /// Dismiss the notification on the other side as well.
+    Dismiss,
+    /// Keep the notification on the other side.
+    Keep,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct NotificationOptions {
+    /// What to do with the remote notification when the local one is dismissed.
+    on_local_dismiss: DismissAction,
+    /// What to do with the local notification when the remote one is dismissed.
+    on_remote_dismiss: DismissAction,
+}
+
+impl Default for NotificationOptions {
+    fn default() -> Self {
+        Self {
+            on_local_dismiss: DismissAction::Dismiss,
+            on_remote_dismiss: DismissAction::Dismiss,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct NotificationReceivePlugin {
     ctx: AppContextRef,
@@ -91,6 +131,27 @@ pub struct NotificationReceivePlugin {
     id_to_icon_path: Mutex<LruCache<String, PathBuf>>,
     mute_menu_id: MenuId,
     muted: AtomicBool,
+    options: RwLock<NotificationOptions>,
+    /// Notifications currently shown on the other device, by ID. The last known ones are kept
+    /// while it's offline, and replaced on reconnect.
+    active: RwLock<BTreeMap<String, IncomingNotification>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DismissParams {
+    id: String,
+}
+
+/// Ask the other device to dismiss a notification.
+async fn dismiss_remote(dev: &DeviceHandle, id: &str) -> Result<()> {
+    dev.send_packet(NetworkPacket::new(
+        PACKET_TYPE_NOTIFICATION_REQUEST,
+        serde_json::json!({
+            "cancel": id,
+        }),
+    ))
+    .await?;
+    Ok(())
 }
 
 impl NotificationReceivePlugin {
@@ -99,11 +160,13 @@ impl NotificationReceivePlugin {
             ctx,
             group_hash: format!(
                 "{:x}",
-                md5::compute(&format!("receive_notifications:{}", dev.device_id()))
+                md5::compute(format!("receive_notifications:{}", dev.device_id()))
             ),
             mute_menu_id: MenuId::new(&format!("{}:notifications:mute", dev.device_id())),
             muted: AtomicBool::new(false),
             id_to_icon_path: Mutex::new(LruCache::new(100)),
+            options: RwLock::new(NotificationOptions::default()),
+            active: RwLock::new(BTreeMap::new()),
             device: dev,
         }
     }
@@ -113,9 +176,6 @@ impl NotificationReceivePlugin {
         notification: IncomingNotification,
         payload_info: Option<PayloadInfo>,
     ) -> Result<()> {
-        let id_hash = format!("{:x}", md5::compute(&notification.id));
-        let app_name_hash = format!("{:x}", md5::compute(&notification.app_name));
-
         let (title, text) =
             if let (Some(title), Some(text)) = (notification.title, notification.text) {
                 (title, text)
@@ -160,20 +220,38 @@ impl NotificationReceivePlugin {
             }
         };
 
+        self.show_toast(
+            &notification.id,
+            &notification.app_name,
+            title,
+            text,
+            icon_path,
+        )
+        .await
+    }
+
+    #[cfg(windows)]
+    async fn show_toast(
+        &self,
+        id: &str,
+        app_name: &str,
+        title: String,
+        text: String,
+        icon_path: Option<PathBuf>,
+    ) -> Result<()> {
+        let id_hash = format!("{:x}", md5::compute(id));
+        let app_name_hash = format!("{:x}", md5::compute(app_name));
+
         let mut toast = Toast::new();
         toast
-            .header(Header::new(
-                &app_name_hash,
-                &notification.app_name,
-                "action=headerClick",
-            ))
+            .header(Header::new(&app_name_hash, app_name, "action=headerClick"))
             .text1(title)
             .text2(text)
             .text3(Text::new(self.device.device_name()).as_attribution())
             .expires_in(Duration::from_secs(60 * 60 * 12))
             .tag(&id_hash)
             .group(&self.group_hash)
-            .remote_id(&notification.id);
+            .remote_id(id);
 
         if let Some(path) = icon_path {
             toast.image(
@@ -183,23 +261,19 @@ impl NotificationReceivePlugin {
             );
         }
 
-        let id = notification.id.clone();
+        let remote_id = id.to_string();
         let dev = self.device.clone();
         let rt_handle = tokio::runtime::Handle::current();
+        let on_local_dismiss = self.options.read().await.on_local_dismiss;
         let on_dismissed = Box::new(move |reason| match reason {
-            Ok(DismissalReason::UserCanceled) => {
+            Ok(DismissalReason::UserCanceled) if on_local_dismiss == DismissAction::Dismiss => {
                 // Dismiss the remote notification
                 let dev = dev.clone();
-                let id = id.clone();
+                let id = remote_id.clone();
 
                 let task = async move {
-                    dev.send_packet(NetworkPacket::new(
-                        PACKET_TYPE_NOTIFICATION_REQUEST,
-                        serde_json::json!({
-                            "cancel": id,
-                        }),
-                    ))
-                    .await;
+                    let result = dismiss_remote(&dev, &id).await;
+                    utils::log_if_error("Failed to dismiss remote notification", result);
                 };
 
                 rt_handle.spawn(task);
@@ -210,7 +284,7 @@ impl NotificationReceivePlugin {
             }
         });
 
-        let id = notification.id.clone();
+        let id = id.to_string();
         let on_failed = Box::new(move |e| {
             tracing::error!("Failed to show notification {}: {:?}", id, e);
         });
@@ -230,6 +304,27 @@ impl NotificationReceivePlugin {
         Ok(())
     }
 
+    /// Without toasts, notifications only show up in the log and for local clients.
+    #[cfg(not(windows))]
+    async fn show_toast(
+        &self,
+        _id: &str,
+        app_name: &str,
+        title: String,
+        text: String,
+        _icon_path: Option<PathBuf>,
+    ) -> Result<()> {
+        log::info!(
+            "Notification from {} on {}: {}: {}",
+            app_name,
+            self.device.device_name(),
+            title,
+            text
+        );
+        Ok(())
+    }
+
+    #[cfg(windows)]
     async fn remove_notification(&self, id: &str) -> Result<()> {
         let group_hash = self.group_hash.clone();
         let id_hash = format!("{:x}", md5::compute(id));
@@ -242,6 +337,11 @@ impl NotificationReceivePlugin {
         Ok(())
     }
 
+    #[cfg(not(windows))]
+    async fn remove_notification(&self, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
     fn is_muted(&self) -> bool {
         self.muted.load(Ordering::Relaxed)
     }
@@ -273,11 +373,22 @@ impl KdeConnectPlugin for NotificationReceivePlugin {
         match body {
             NotificationBody::Cancelled { id, .. } => {
                 tracing::debug!("Cancelled {}", id);
-                self.remove_notification(&id)
-                    .await
-                    .context("Remove notification")?;
+                if self.active.write().await.remove(&id).is_some() {
+                    self.device.plugin_changed(PLUGIN_NAME);
+                }
+                if self.options.read().await.on_remote_dismiss == DismissAction::Dismiss {
+                    self.remove_notification(&id)
+                        .await
+                        .context("Remove notification")?;
+                }
             }
             NotificationBody::Posted(notif) => {
+                self.active
+                    .write()
+                    .await
+                    .insert(notif.id.clone(), notif.clone());
+                self.device.plugin_changed(PLUGIN_NAME);
+
                 if self.is_muted() {
                     tracing::debug!("Posted {} (muted)", notif.id);
                 } else {
@@ -294,22 +405,54 @@ impl KdeConnectPlugin for NotificationReceivePlugin {
     }
 
     async fn start(self: Arc<Self>) -> Result<()> {
+        let store = self.device.store(STORE_NAME)?;
+        if let Some(muted) = store.get("muted").await? {
+            self.muted.store(muted, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    async fn on_connected(self: Arc<Self>) -> Result<()> {
+        // Notifications dismissed while offline aren't cancelled, so the list is replaced.
+        self.active.write().await.clear();
+        self.device.plugin_changed(PLUGIN_NAME);
+
         // Request all remote notifications
         let dev = self.device.clone();
 
         tokio::spawn(async move {
-            dev.send_packet(NetworkPacket::new(
-                PACKET_TYPE_NOTIFICATION_REQUEST,
-                serde_json::json!({
-                    "request": true,
-                }),
-            ))
-            .await;
+            let result = dev
+                .send_packet(NetworkPacket::new(
+                    PACKET_TYPE_NOTIFICATION_REQUEST,
+                    serde_json::json!({
+                        "request": true,
+                    }),
+                ))
+                .await;
+            utils::log_if_error("Failed to request notifications", result);
         });
 
         Ok(())
     }
 
+    async fn save_state(&self) -> Option<serde_json::Value> {
+        let active = self.active.read().await;
+        if active.is_empty() {
+            return None;
+        }
+        serde_json::to_value(active.values().collect::<Vec<_>>()).ok()
+    }
+
+    async fn restore_state(&self, state: serde_json::Value) -> Result<()> {
+        let saved: Vec<IncomingNotification> = serde_json::from_value(state)?;
+        let mut active = self.active.write().await;
+        for notif in saved {
+            // Notifications received since then are more recent.
+            active.entry(notif.id.clone()).or_insert(notif);
+        }
+        Ok(())
+    }
+
     async fn tray_menu(&self, menu: &mut ContextMenu) {
         let mut submenu = ContextMenu::new();
         submenu.add_item(
@@ -322,11 +465,44 @@ impl KdeConnectPlugin for NotificationReceivePlugin {
 
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
         if event.is_menu_clicked(self.mute_menu_id) {
-            self.muted.fetch_xor(true, Ordering::Relaxed);
+            let muted = !self.muted.fetch_xor(true, Ordering::Relaxed);
             self.ctx.update_tray().await;
+
+            // Remembered across restarts.
+            self.device
+                .store(STORE_NAME)?
+                .set("muted", &muted)
+                .await
+                .context("Save mute state")?;
         }
         Ok(())
     }
+
+    async fn apply_options(&self, options: Option<&toml::Value>) -> Result<()> {
+        *self.options.write().await = super::parse_options(options)?;
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        match method {
+            // Notifications shown on the other device.
+            "list" => {
+                let active = self.active.read().await;
+                Ok(serde_json::to_value(active.values().collect::<Vec<_>>())?)
+            }
+            "dismiss" => {
+                let params: DismissParams = serde_json::from_value(params)?;
+                match self.active.read().await.get(&params.id) {
+                    Some(notif) if notif.is_clearable => {}
+                    Some(_) => anyhow::bail!("Notification can't be dismissed: {}", params.id),
+                    None => anyhow::bail!("Unknown notification: {}", params.id),
+                }
+                dismiss_remote(&self.device, &params.id).await?;
+                Ok(serde_json::Value::Null)
+            }
+            _ => anyhow::bail!("Unknown method: {}", method),
+        }
+    }
 }
 
 impl KdeConnectPluginMetadata for NotificationReceivePlugin {
@@ -339,4 +515,8 @@ impl KdeConnectPluginMetadata for NotificationReceivePlugin {
             "kdeconnect.notification.reply".into(),
         ]
     }
+    fn validate_options(options: &toml::Value) -> Result<()> {
+        NotificationOptions::deserialize(options.clone())?;
+        Ok(())
+    }
 }
@@ -7,9 +7,14 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tao::menu::{ContextMenu, MenuId, MenuItemAttributes};
 
-use crate::{device::DeviceHandle, event::SystemEvent, packet::NetworkPacket, utils};
+use crate::{
+    device::{DeviceHandle, SendError},
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::{ContextMenu, MenuId, MenuItemAttributes},
+    utils,
+};
 
 use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
 
@@ -35,13 +40,12 @@ impl PingPlugin {
         }
     }
 
-    pub async fn send_ping(&self) {
+    /// Not kept while the device is offline, so that whoever asked for the ping hears about
+    /// it right away.
+    pub async fn send_ping(&self, message: Option<String>) -> Result<(), SendError> {
         self.dev
-            .send_packet(NetworkPacket::new(
-                PACKET_TYPE_PING,
-                PingPacket { message: None },
-            ))
-            .await;
+            .send_packet(NetworkPacket::new(PACKET_TYPE_PING, PingPacket { message }))
+            .await
     }
 }
 
@@ -53,7 +57,7 @@ impl KdeConnectPlugin for PingPlugin {
         utils::simple_toast(
             "Ping",
             body.message.as_deref(),
-            Some(self.dev.device_name()),
+            Some(&self.dev.device_name()),
         )
         .await;
 
@@ -66,10 +70,29 @@ impl KdeConnectPlugin for PingPlugin {
 
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
         if event.is_menu_clicked(self.menu_id) {
-            self.send_ping().await;
+            if let Err(e) = self.send_ping(None).await {
+                utils::simple_toast(
+                    "Failed to send ping",
+                    Some(&e.to_string()),
+                    Some(&self.dev.device_name()),
+                )
+                .await;
+            }
         }
         Ok(())
     }
+
+    async fn call(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        match method {
+            // Params are the same as the packet body, with an optional message.
+            "ping" => {
+                let body: PingPacket = serde_json::from_value(params)?;
+                self.send_ping(body.message).await?;
+                Ok(serde_json::Value::Null)
+            }
+            _ => anyhow::bail!("Unknown method: {}", method),
+        }
+    }
 }
 
 impl KdeConnectPluginMetadata for PingPlugin {
@@ -70,7 +70,7 @@ impl RunCommandPlugin {
                 PACKET_TYPE_RUNCOMMAND,
                 RunCommandPacket { command_list },
             ))
-            .await;
+            .await?;
 
         Ok(())
     }
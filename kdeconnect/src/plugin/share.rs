@@ -9,13 +9,37 @@ with the content instead of saving it as a file.
 
 If the content transferred is a url, it can be sent in a field "url" (string).
 In that case, this plugin opens that url in the default browser.
+
+Received files are saved to the `destination` option, or the Downloads folder. They are
+written to a `.part` file first, which is moved into place once complete, never replacing an
+existing file, and removed if the download fails or the daemon shuts down.
+
+Files are sent the same way, with "numberOfFiles" and "totalPayloadSize" describing the
+whole batch. A batch of several files is announced with a kdeconnect.share.request.update
+packet first.
  */
-use anyhow::Result;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    sync::RwLock,
+};
 
 use crate::{
-    device::DeviceHandle,
-    packet::NetworkPacket,
+    context::AppContextRef,
+    device::{DeviceHandle, SendError, SendOptions},
+    event::SystemEvent,
+    packet::{NetworkPacket, NetworkPacketWithPayload, Payload},
+    paths,
+    shutdown::Shutdown,
+    tray::{ContextMenu, MenuId, MenuItemAttributes},
     utils::{self, clipboard::ClipboardContent},
 };
 
@@ -31,16 +55,175 @@ enum ShareRequestPacket {
     Url { url: String },
 }
 
+/// Body of a share request with a payload.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ShareFile {
+    filename: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_modified: Option<i64>,
+    /// Files in the batch this file belongs to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    number_of_files: Option<u64>,
+    /// Size of all the files in the batch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_payload_size: Option<u64>,
+}
+
+/// Body of kdeconnect.share.request.update, announcing a batch of files.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ShareUpdate {
+    number_of_files: u64,
+    total_payload_size: u64,
+}
+
+/// Params of the `share` method: `paths` of files, or `text` or `url` like the packet body.
+///
+/// File URLs are shared as files.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ShareParams {
+    Files { paths: Vec<PathBuf> },
+    Request(ShareRequestPacket),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ShareOptions {
+    /// Where received files are saved, the Downloads folder if not set.
+    destination: Option<PathBuf>,
+}
+
 #[derive(Debug)]
 pub struct SharePlugin {
+    ctx: AppContextRef,
     dev: DeviceHandle,
+    options: RwLock<ShareOptions>,
+    menu_id: MenuId,
 }
 
 impl SharePlugin {
-    pub fn new(dev: DeviceHandle) -> Self {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
         SharePlugin {
+            ctx,
+            options: RwLock::new(ShareOptions::default()),
+            menu_id: MenuId::new(&format!("{}:share", dev.device_id())),
             dev,
-            // ctx,
+        }
+    }
+
+    /// Share text or a URL, kept until the device connects if it's offline.
+    async fn share(&self, request: ShareRequestPacket) -> Result<(), SendError> {
+        self.dev
+            .send_packet_with(
+                NetworkPacket::new(PACKET_TYPE_SHARE_REQUEST, request),
+                SendOptions::buffered(),
+            )
+            .await
+    }
+
+    /// Share files as one batch, kept until the device connects if it's offline.
+    ///
+    /// The files are read when the device fetches them.
+    async fn share_files(&self, paths: &[PathBuf]) -> Result<()> {
+        anyhow::ensure!(!paths.is_empty(), "No files to share");
+
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let metadata = tokio::fs::metadata(path)
+                .await
+                .with_context(|| format!("Read {:?}", path))?;
+            anyhow::ensure!(metadata.is_file(), "Not a file: {:?}", path);
+
+            let filename = path.file_name().map(|n| n.to_string_lossy().into_owned());
+            let last_modified = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_millis() as i64);
+            files.push((path, filename, last_modified, metadata.len()));
+        }
+
+        let number_of_files = files.len() as u64;
+        let total_payload_size: u64 = files.iter().map(|(_, _, _, size)| size).sum();
+
+        let mut packets = Vec::with_capacity(files.len() + 1);
+        if number_of_files > 1 {
+            let update = ShareUpdate {
+                number_of_files,
+                total_payload_size,
+            };
+            packets.push(NetworkPacket::new(PACKET_TYPE_SHARE_REQUEST_UPDATE, update).into());
+        }
+
+        for (path, filename, last_modified, size) in files {
+            let body = ShareFile {
+                filename,
+                last_modified,
+                number_of_files: Some(number_of_files),
+                total_payload_size: Some(total_payload_size),
+            };
+            let payload = Payload::File {
+                path: Arc::new(path.clone()),
+                size,
+            };
+            packets.push(NetworkPacketWithPayload::new(
+                NetworkPacket::new(PACKET_TYPE_SHARE_REQUEST, body),
+                payload,
+            ));
+        }
+
+        // Queue every packet before waiting for any, otherwise only the first one is kept for
+        // an offline device. The sends are polled in order, so the update still comes first.
+        let sends = packets
+            .into_iter()
+            .map(|packet| self.dev.send_packet_with(packet, SendOptions::buffered()));
+        for result in futures::future::join_all(sends).await {
+            result?;
+        }
+        for path in paths {
+            log::info!("Shared {:?} with {}", path, self.dev.device_name());
+        }
+
+        Ok(())
+    }
+
+    async fn share_params(&self, params: ShareParams) -> Result<()> {
+        match params {
+            ShareParams::Files { paths } => self.share_files(&paths).await,
+            ShareParams::Request(ShareRequestPacket::Url { url }) if url.starts_with("file:") => {
+                let path = url::Url::parse(&url)?
+                    .to_file_path()
+                    .map_err(|_| anyhow::anyhow!("Not a local file: {}", url))?;
+                self.share_files(&[path]).await
+            }
+            ShareParams::Request(request) => Ok(self.share(request).await?),
+        }
+    }
+
+    /// Share the files, URL or text in the clipboard.
+    async fn share_clipboard(&self) -> Result<()> {
+        let content = tokio::task::spawn_blocking(utils::clipboard::read).await??;
+        match content {
+            ClipboardContent::Files(paths) => {
+                let paths: Vec<PathBuf> = paths.into_iter().map(PathBuf::from).collect();
+                self.share_files(&paths).await
+            }
+            ClipboardContent::Text(text) => {
+                let is_url = url::Url::parse(text.trim())
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+                let request = if is_url {
+                    ShareRequestPacket::Url {
+                        url: text.trim().to_string(),
+                    }
+                } else {
+                    ShareRequestPacket::Text { text }
+                };
+                Ok(self.share(request).await?)
+            }
+            ClipboardContent::Unsupported => anyhow::bail!("Nothing to share in the clipboard"),
         }
     }
 }
@@ -49,6 +232,47 @@ impl SharePlugin {
 impl KdeConnectPlugin for SharePlugin {
     async fn handle(&self, packet: NetworkPacket) -> Result<()> {
         match packet.typ.as_str() {
+            PACKET_TYPE_SHARE_REQUEST if packet.payload_transfer_info.is_some() => {
+                let port = packet.payload_transfer_info.as_ref().unwrap().port;
+                let size = packet.payload_size.context("Payload without a size")?;
+                let file: ShareFile = packet.into_body()?;
+
+                let dir = match &self.options.read().await.destination {
+                    Some(dir) => dir.clone(),
+                    None => paths::get().downloads_dir.clone(),
+                };
+
+                // Downloads can outlast the handler timeout, but not the daemon.
+                let dev = self.dev.clone();
+                let shutdown = self.ctx.shutdown.clone();
+                self.ctx.shutdown.spawn(async move {
+                    let name = file
+                        .filename
+                        .clone()
+                        .unwrap_or_else(|| "Unnamed file".into());
+                    match receive_file(&dev, &shutdown, &dir, file, port, size).await {
+                        Ok(path) => {
+                            log::info!("Received {:?} from {}", path, dev.device_name());
+                            let path = path.display().to_string();
+                            utils::simple_toast(
+                                "File received",
+                                Some(path.as_str()),
+                                Some(&dev.device_name()),
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to receive {:?}: {:?}", name, e);
+                            utils::simple_toast(
+                                "Failed to receive file",
+                                Some(name.as_str()),
+                                Some(&dev.device_name()),
+                            )
+                            .await;
+                        }
+                    }
+                });
+            }
             PACKET_TYPE_SHARE_REQUEST => {
                 let body: ShareRequestPacket = packet.into_body()?;
                 match body {
@@ -65,12 +289,53 @@ impl KdeConnectPlugin for SharePlugin {
                     }
                 }
             }
-            PACKET_TYPE_SHARE_REQUEST_UPDATE => {}
+            PACKET_TYPE_SHARE_REQUEST_UPDATE => {
+                let update: ShareUpdate = packet.into_body()?;
+                log::info!(
+                    "Receiving {} files ({} bytes) from {}",
+                    update.number_of_files,
+                    update.total_payload_size,
+                    self.dev.device_name()
+                );
+            }
             _ => {}
         }
 
         Ok(())
     }
+
+    async fn tray_menu(&self, menu: &mut ContextMenu) {
+        menu.add_item(MenuItemAttributes::new("Share clipboard").with_id(self.menu_id));
+    }
+
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event.is_menu_clicked(self.menu_id) {
+            if let Err(e) = self.share_clipboard().await {
+                utils::simple_toast(
+                    "Failed to share clipboard",
+                    Some(&format!("{:#}", e)),
+                    Some(&self.dev.device_name()),
+                )
+                .await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        match method {
+            "share" => {
+                self.share_params(serde_json::from_value(params)?).await?;
+                Ok(serde_json::Value::Null)
+            }
+            _ => anyhow::bail!("Unknown method: {}", method),
+        }
+    }
+
+    async fn apply_options(&self, options: Option<&toml::Value>) -> Result<()> {
+        *self.options.write().await = super::parse_options(options)?;
+        Ok(())
+    }
 }
 
 impl KdeConnectPluginMetadata for SharePlugin {
@@ -86,4 +351,302 @@ impl KdeConnectPluginMetadata for SharePlugin {
             PACKET_TYPE_SHARE_REQUEST_UPDATE.into(),
         ]
     }
+    fn validate_options(options: &toml::Value) -> Result<()> {
+        ShareOptions::deserialize(options.clone())?;
+        Ok(())
+    }
+}
+
+/// Download a shared file into `dir`, returns where it was saved. Stops early if the daemon
+/// shuts down.
+async fn receive_file(
+    dev: &DeviceHandle,
+    shutdown: &Shutdown,
+    dir: &Path,
+    file: ShareFile,
+    port: u16,
+    size: u64,
+) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Create {:?}", dir))?;
+
+    let name = file
+        .filename
+        .as_deref()
+        .and_then(sanitize_file_name)
+        .unwrap_or_else(|| format!("kdeconnect-{}", utils::unix_ts_ms()));
+
+    let path = available_path(dir, &name);
+    let part_path = part_path(&path);
+    let part = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&part_path)
+        .await
+        .with_context(|| format!("Create {:?}", part_path))?;
+
+    let download = async {
+        let mut writer = BufWriter::new(part);
+        let mut payload = dev.open_payload(port).await?.take(size);
+        let written = tokio::io::copy(&mut payload, &mut writer).await?;
+        anyhow::ensure!(
+            written == size,
+            "Payload size mismatch: {} (fetched) != {} (announced)",
+            written,
+            size
+        );
+        writer.flush().await?;
+
+        let part = writer.into_inner().into_std().await;
+        if let Some(modified) = file.last_modified.filter(|&ms| ms > 0) {
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(modified as u64);
+            utils::log_if_error("Set modification time", part.set_modified(modified));
+        }
+        Ok(())
+    };
+
+    let result = tokio::select! {
+        result = download => result,
+        _ = shutdown.triggered() => Err(anyhow::anyhow!("Shutting down")),
+    };
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&part_path).await;
+        return Err(e);
+    }
+
+    persist(&part_path, path, dir, &name).await
+}
+
+/// Move a finished download to `path`, or to the next available name if another file took
+/// it in the meantime. Returns where it was saved.
+async fn persist(part_path: &Path, mut path: PathBuf, dir: &Path, name: &str) -> Result<PathBuf> {
+    loop {
+        // Unlike renaming, linking fails instead of replacing an existing file.
+        match tokio::fs::hard_link(part_path, &path).await {
+            Ok(()) => {
+                let removed = tokio::fs::remove_file(part_path).await;
+                utils::log_if_error("Failed to remove the .part file", removed);
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path = available_path(dir, name);
+            }
+            // File systems like FAT don't support links.
+            Err(_) => {
+                if is_taken(&path) {
+                    path = available_path(dir, name);
+                }
+                tokio::fs::rename(part_path, &path)
+                    .await
+                    .with_context(|| format!("Rename {:?} to {:?}", part_path, path))?;
+                return Ok(path);
+            }
+        }
+    }
+}
+
+/// Keep only the last component of a name chosen by the device, with characters that aren't
+/// allowed in file names replaced.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    const RESERVED: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"<>:"|?*"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // Windows drops trailing dots and spaces, which also rules out `.` and `..`.
+    let name = name.trim().trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        return None;
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return Some(format!("_{}", name));
+    }
+    Some(name.to_string())
+}
+
+/// The first of `name`, `name (1)`, `name (2)`… that isn't used in `dir`, by a file or a
+/// download in progress.
+fn available_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()));
+
+    (0..)
+        .map(|i| match i {
+            0 => dir.join(name),
+            i => dir.join(format!(
+                "{} ({}){}",
+                stem,
+                i,
+                extension.as_deref().unwrap_or_default()
+            )),
+        })
+        .find(|path| !is_taken(path) && !is_taken(&part_path(path)))
+        .unwrap()
+}
+
+/// Whether anything is at `path`, including a broken link.
+fn is_taken(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok()
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::test_context;
+
+    use super::*;
+
+    #[test]
+    fn file_names_are_sanitized() {
+        let cases = [
+            ("photo.jpg", Some("photo.jpg")),
+            ("DCIM/Camera/photo.jpg", Some("photo.jpg")),
+            (r"C:\Users\me\notes.txt", Some("notes.txt")),
+            ("a/../b", Some("b")),
+            ("..", None),
+            ("../", None),
+            (".", None),
+            ("", None),
+            ("  . ", None),
+            ("report.pdf. ", Some("report.pdf")),
+            ("what?<now>.txt", Some("what__now_.txt")),
+            ("tab\tname", Some("tab_name")),
+            ("CON.txt", Some("_CON.txt")),
+            ("con", Some("_con")),
+            ("LPT1.tar.gz", Some("_LPT1.tar.gz")),
+            ("CONSOLE.txt", Some("CONSOLE.txt")),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(sanitize_file_name(name).as_deref(), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn available_paths_skip_files_and_downloads() {
+        let dir = utils::test_dir("share-available");
+        assert_eq!(available_path(&dir, "name.ext"), dir.join("name.ext"));
+
+        std::fs::write(dir.join("name.ext"), "").unwrap();
+        assert_eq!(available_path(&dir, "name.ext"), dir.join("name (1).ext"));
+
+        std::fs::write(dir.join("name (1).ext.part"), "").unwrap();
+        assert_eq!(available_path(&dir, "name.ext"), dir.join("name (2).ext"));
+
+        std::fs::write(dir.join("name (1).ext"), "").unwrap();
+        assert_eq!(
+            available_path(&dir, "name (1).ext"),
+            dir.join("name (1) (1).ext")
+        );
+
+        std::fs::write(dir.join("README"), "").unwrap();
+        assert_eq!(available_path(&dir, "README"), dir.join("README (1)"));
+    }
+
+    #[tokio::test]
+    async fn downloads_never_replace_files() {
+        let dir = utils::test_dir("share-persist");
+        let path = dir.join("photo.jpg");
+        let part = part_path(&path);
+        std::fs::write(&part, "new").unwrap();
+        // Saved while downloading.
+        std::fs::write(&path, "old").unwrap();
+
+        let saved = persist(&part, path.clone(), &dir, "photo.jpg")
+            .await
+            .unwrap();
+        assert_eq!(saved, dir.join("photo (1).jpg"));
+        assert_eq!(std::fs::read_to_string(&saved).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+        assert!(!part.exists());
+    }
+
+    #[tokio::test]
+    async fn shared_files_are_queued_as_a_batch() {
+        let dir = utils::test_dir("share-send");
+        let paths = [dir.join("a.txt"), dir.join("b.txt")];
+        std::fs::write(&paths[0], "hello").unwrap();
+        std::fs::write(&paths[1], "world!").unwrap();
+
+        let ctx = test_context().await;
+        let ip = std::net::IpAddr::from([127, 0, 0, 1]);
+        let (_, queue, dev) = ctx
+            .device_manager
+            .add_device("share-send", "Phone", "phone", ip)
+            .await
+            .unwrap();
+        let plugin = Arc::new(SharePlugin::new(dev, ctx));
+
+        let share = tokio::spawn({
+            let plugin = plugin.clone();
+            let paths = paths.clone();
+            async move { plugin.share_files(&paths).await }
+        });
+
+        // None are delivered until all are queued, as if the device were offline.
+        let mut shared = vec![];
+        while shared.len() < 3 {
+            let packet = tokio::time::timeout(Duration::from_secs(5), queue.pop())
+                .await
+                .expect("Share packets were not queued")
+                .unwrap();
+            if packet
+                .packet
+                .packet
+                .typ
+                .starts_with(PACKET_TYPE_SHARE_REQUEST)
+            {
+                shared.push(packet);
+            } else {
+                packet.complete(Ok(()));
+            }
+        }
+        let packets: Vec<_> = shared.iter().map(|p| p.packet.packet.clone()).collect();
+        for packet in shared {
+            packet.complete(Ok(()));
+        }
+        share.await.unwrap().unwrap();
+
+        assert_eq!(packets[0].typ, PACKET_TYPE_SHARE_REQUEST_UPDATE);
+        let update: ShareUpdate = packets[0].clone().into_body().unwrap();
+        assert_eq!(update.number_of_files, 2);
+        assert_eq!(update.total_payload_size, 11);
+
+        for (packet, path) in packets[1..].iter().zip(&paths) {
+            assert_eq!(packet.typ, PACKET_TYPE_SHARE_REQUEST);
+            let modified = std::fs::metadata(path).unwrap().modified().unwrap();
+            let file: ShareFile = packet.clone().into_body().unwrap();
+            assert_eq!(
+                file.filename,
+                path.file_name().unwrap().to_str().map(Into::into)
+            );
+            assert_eq!(file.number_of_files, Some(2));
+            assert_eq!(file.total_payload_size, Some(11));
+            assert_eq!(
+                file.last_modified,
+                Some(modified.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64)
+            );
+        }
+    }
 }
@@ -6,7 +6,11 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use windows_audio_manager::AudioManagerHandle;
 
-use crate::{device::DeviceHandle, packet::NetworkPacket};
+use crate::{
+    device::{DeviceHandle, SendError},
+    packet::NetworkPacket,
+    utils,
+};
 
 use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
 
@@ -86,12 +90,17 @@ impl SystemVolumePlugin {
                 PACKET_TYPE_SYSTEM_VOLUME,
                 SystemVolumePacket::SinkList { sink_list },
             ))
-            .await;
+            .await?;
 
         Ok(())
     }
 
-    async fn send_volume_update(&self, name: String, volume: u8, muted: bool) {
+    async fn send_volume_update(
+        &self,
+        name: String,
+        volume: u8,
+        muted: bool,
+    ) -> Result<(), SendError> {
         self.dev
             .send_packet(NetworkPacket::new(
                 PACKET_TYPE_SYSTEM_VOLUME,
@@ -101,7 +110,7 @@ impl SystemVolumePlugin {
                     muted,
                 },
             ))
-            .await;
+            .await
     }
 }
 
@@ -124,7 +133,10 @@ impl KdeConnectPlugin for SystemVolumePlugin {
                             volume,
                             muted,
                         } => {
-                            this.send_volume_update(name, volume, muted).await;
+                            utils::log_if_error(
+                                "Failed to send volume update",
+                                this.send_volume_update(name, volume, muted).await,
+                            );
                         }
                     }
                 } else {
@@ -0,0 +1,368 @@
+//! User settings stored in a TOML file, as opposed to the identity in [`crate::config`].
+//!
+//! The file is watched while the daemon runs, and changes are applied to connected
+//! devices without restarting.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{context::AppContextRef, plugin, utils};
+
+/// How often the settings file is checked for modifications.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceType {
+    /// Laptop if the system has a battery, desktop otherwise.
+    #[default]
+    Auto,
+    Desktop,
+    Laptop,
+    Tablet,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CertificateAlgorithm {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    /// Not supported by older versions of KDE Connect.
+    Ed25519,
+}
+
+impl CertificateAlgorithm {
+    pub fn signature_algorithm(&self) -> &'static rcgen::SignatureAlgorithm {
+        match self {
+            CertificateAlgorithm::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            CertificateAlgorithm::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+            CertificateAlgorithm::Ed25519 => &rcgen::PKCS_ED25519,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyStorage {
+    /// In the identity file, unencrypted.
+    #[default]
+    File,
+    /// In the identity file, encrypted with a passphrase that is asked for on start.
+    Passphrase,
+    /// In the system keyring.
+    Keyring,
+}
+
+/// Used when generating a new certificate, existing ones are not affected.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CertificateSettings {
+    pub algorithm: CertificateAlgorithm,
+    pub validity_days: u32,
+}
+
+impl Default for CertificateSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: CertificateAlgorithm::default(),
+            validity_days: 365 * 10,
+        }
+    }
+}
+
+/// Outgoing packets of each device.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct OutboundSettings {
+    /// Packets that can wait for a connection before lower priority ones are dropped.
+    pub queue_depth: usize,
+    /// How long packets sent to an offline device are kept for, if the sender asks for it.
+    /// Set to 0 to never keep them.
+    pub offline_ttl_secs: u64,
+}
+
+impl Default for OutboundSettings {
+    fn default() -> Self {
+        Self {
+            queue_depth: 256,
+            offline_ttl_secs: 5 * 60,
+        }
+    }
+}
+
+impl OutboundSettings {
+    pub fn offline_ttl(&self) -> Duration {
+        Duration::from_secs(self.offline_ttl_secs)
+    }
+}
+
+/// A plugin running as a separate process, see [`crate::plugin::external`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExternalPluginSettings {
+    /// Executable to run, looked up in `PATH` if it's not a path.
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    /// Name shown on remote devices, the host name is used if not set.
+    pub device_name: Option<String>,
+    /// Type announced to remote devices, which determines the icon they show.
+    #[serde(default)]
+    pub device_type: DeviceType,
+    /// Where the private key is kept, an existing key is moved on start if this is changed.
+    #[serde(default)]
+    pub key_storage: KeyStorage,
+    #[serde(default)]
+    pub certificate: CertificateSettings,
+    #[serde(default)]
+    pub outbound: OutboundSettings,
+    /// Plugins running as separate processes, keyed by the plugin name used in device settings.
+    ///
+    /// They are started with the daemon, changes take effect after a restart.
+    #[serde(default)]
+    pub external_plugins: HashMap<String, ExternalPluginSettings>,
+    /// Per-device settings, keyed by a user-chosen alias.
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceSettings {
+    /// Device ID as announced by the remote device.
+    pub id: String,
+    /// Enabled plugins and their options, keyed by plugin name.
+    ///
+    /// All plugins are enabled with default options if this is not set.
+    pub plugins: Option<HashMap<String, toml::Value>>,
+}
+
+impl Settings {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let s = std::fs::read_to_string(path)?;
+        let settings: Settings = toml::from_str(&s)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Loads settings from a file, or uses the defaults if it doesn't exist.
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Check certificate settings, plugin names and options so that invalid settings are
+    /// rejected as a whole.
+    fn validate(&self) -> Result<()> {
+        if self.certificate.validity_days == 0 {
+            anyhow::bail!("Certificate validity must be at least one day");
+        }
+        if self.outbound.queue_depth == 0 {
+            anyhow::bail!("Outbound queue depth must be at least 1");
+        }
+        for name in self.external_plugins.keys() {
+            if plugin::is_builtin(name) {
+                anyhow::bail!("External plugin {} has the name of a built-in plugin", name);
+            }
+        }
+        for (alias, device) in &self.devices {
+            for (name, options) in device.plugins.iter().flatten() {
+                if self.external_plugins.contains_key(name) {
+                    // Options are passed to the plugin as they are.
+                    continue;
+                }
+                plugin::validate_options(name, options)
+                    .with_context(|| format!("Plugin {} of device {}", name, alias))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Name to announce in the identity packet.
+    pub fn device_name(&self) -> String {
+        match &self.device_name {
+            Some(name) => name.clone(),
+            None => gethostname::gethostname().to_string_lossy().to_string(),
+        }
+    }
+
+    /// Type to announce in the identity packet.
+    pub fn device_type(&self) -> &'static str {
+        match self.device_type {
+            DeviceType::Auto if utils::has_battery() => "laptop",
+            DeviceType::Auto => "desktop",
+            DeviceType::Desktop => "desktop",
+            DeviceType::Laptop => "laptop",
+            DeviceType::Tablet => "tablet",
+        }
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<&DeviceSettings> {
+        self.devices.values().find(|d| d.id == device_id)
+    }
+
+    pub fn plugin_enabled(&self, device_id: &str, plugin: &str) -> bool {
+        match self.device(device_id).and_then(|d| d.plugins.as_ref()) {
+            Some(plugins) => plugins.contains_key(plugin),
+            None => true,
+        }
+    }
+
+    pub fn plugin_options(&self, device_id: &str, plugin: &str) -> Option<&toml::Value> {
+        self.device(device_id)
+            .and_then(|d| d.plugins.as_ref())
+            .and_then(|plugins| plugins.get(plugin))
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Loads the settings file again when it's modified.
+struct Reloader {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+}
+
+impl Reloader {
+    fn new(path: PathBuf) -> Self {
+        let last_modified = modified_time(&path);
+        Self {
+            path,
+            last_modified,
+        }
+    }
+
+    /// The settings if the file was modified since the last check.
+    fn poll(&mut self) -> Option<Result<Settings>> {
+        let modified = modified_time(&self.path);
+        if modified == self.last_modified {
+            return None;
+        }
+        self.last_modified = modified;
+
+        Some(Settings::load_or_default(&self.path))
+    }
+}
+
+/// Watch the settings file and apply changes as they happen.
+///
+/// Invalid settings are reported to the user, and the previous settings are kept.
+pub async fn watch(path: PathBuf, ctx: AppContextRef) {
+    let mut reloader = Reloader::new(path.clone());
+
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+
+        let settings = match reloader.poll() {
+            None => continue,
+            Some(Ok(settings)) => settings,
+            Some(Err(e)) => {
+                log::error!("Failed to reload settings from {:?}: {:?}", path, e);
+                utils::simple_toast(
+                    "Invalid settings",
+                    Some(&format!("{:#}", e)),
+                    Some("Previous settings are kept"),
+                )
+                .await;
+                continue;
+            }
+        };
+
+        if *ctx.settings() == settings {
+            continue;
+        }
+
+        log::info!("Settings reloaded from {:?}", path);
+        ctx.set_settings(Arc::new(settings));
+        ctx.device_manager.reload_settings().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the settings file with a new modification time, as an editor would.
+    fn edit(path: &Path, content: &str) {
+        std::fs::write(path, content).unwrap();
+        let modified = modified_time(path).unwrap() + Duration::from_secs(1);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn reloads_only_when_modified() {
+        let path = utils::test_dir("settings-reload").join("config.toml");
+        std::fs::write(&path, "device-name = \"Before\"").unwrap();
+
+        let mut reloader = Reloader::new(path.clone());
+        assert!(reloader.poll().is_none());
+
+        edit(&path, "device-name = \"After\"");
+        let settings = reloader.poll().unwrap().unwrap();
+        assert_eq!(settings.device_name.as_deref(), Some("After"));
+        assert!(reloader.poll().is_none());
+    }
+
+    #[test]
+    fn reports_invalid_edits() {
+        let path = utils::test_dir("settings-invalid").join("config.toml");
+        std::fs::write(&path, "").unwrap();
+        let mut reloader = Reloader::new(path.clone());
+
+        edit(&path, "[certificate]\nvalidity-days = 0");
+        assert!(reloader.poll().unwrap().is_err());
+
+        edit(&path, "device-name = [");
+        assert!(reloader.poll().unwrap().is_err());
+
+        edit(
+            &path,
+            "[devices.phone]\nid = \"abc\"\n[devices.phone.plugins.unknown]",
+        );
+        assert!(reloader.poll().unwrap().is_err());
+
+        // Fixing the file is picked up again.
+        edit(&path, "device-name = \"Fixed\"");
+        let settings = reloader.poll().unwrap().unwrap();
+        assert_eq!(settings.device_name.as_deref(), Some("Fixed"));
+    }
+
+    #[test]
+    fn removed_file_means_defaults() {
+        let path = utils::test_dir("settings-removed").join("config.toml");
+        std::fs::write(&path, "device-name = \"Gone\"").unwrap();
+        let mut reloader = Reloader::new(path.clone());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloader.poll().unwrap().unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn sample_settings_are_valid() {
+        let sample = concat!(env!("CARGO_MANIFEST_DIR"), "/../config.toml");
+        Settings::load(sample).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Coordinated shutdown of the daemon.
+//!
+//! Long-running tasks stop when [`Shutdown::trigger`] is called, either from the tray or by
+//! a signal. Connections and transfers are spawned with [`Shutdown::spawn`] so that the
+//! daemon can wait for them to close cleanly, for at most [`DRAIN_TIMEOUT`].
+use std::{future::Future, time::Duration};
+
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// How long to wait for connections to close before exiting anyway.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask all tasks to stop, can be called more than once.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait until shutdown is triggered.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Spawn a task that shutdown waits for, such as a connection.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task)
+    }
+
+    /// Run `task` until shutdown is triggered, logging why it stopped otherwise.
+    pub fn spawn_service<F, E>(&self, name: &'static str, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Debug,
+    {
+        let token = self.token.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = task => log::warn!("{} exited with {:?}", name, result),
+                _ = token.cancelled() => log::debug!("{} stopped", name),
+            }
+        })
+    }
+
+    /// Wait for tasks spawned with [`Shutdown::spawn`], returns false if they didn't finish
+    /// within `timeout`.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.tasks.close();
+        tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_ok()
+    }
+}
+
+/// Wait for a signal asking the process to exit: SIGINT or SIGTERM on Unix, Ctrl+C elsewhere.
+pub async fn signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = interrupt.recv() => log::info!("Received SIGINT"),
+            _ = terminate.recv() => log::info!("Received SIGTERM"),
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        log::info!("Received Ctrl+C");
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! Persistent key-value stores for plugins, scoped to a device and a plugin.
+//!
+//! Values of all plugins of a device are kept in a single JSON file under `devices` in the
+//! data directory. The file is replaced as a whole on each write, so that it's never left
+//! half-written. Files written in an older format are migrated when they are loaded.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+/// Version of the file format, bumped when existing values need to be migrated.
+const VERSION: u32 = 1;
+
+lazy_static::lazy_static! {
+    /// Keyed by file name, so that devices never share a store.
+    static ref DEVICE_STORES: std::sync::Mutex<HashMap<String, Arc<DeviceStore>>> =
+        Default::default();
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoreFile {
+    version: u32,
+    /// Values keyed by plugin name, then by key.
+    plugins: HashMap<String, HashMap<String, Value>>,
+}
+
+impl Default for StoreFile {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            plugins: HashMap::new(),
+        }
+    }
+}
+
+/// Values of all plugins of a device, loaded on first use.
+#[derive(Debug)]
+struct DeviceStore {
+    path: PathBuf,
+    data: Mutex<Option<StoreFile>>,
+}
+
+impl DeviceStore {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            data: Mutex::new(None),
+        }
+    }
+
+    async fn load(path: &Path) -> Result<StoreFile> {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(StoreFile::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Read {:?}", path)),
+        };
+
+        let mut file: StoreFile =
+            serde_json::from_slice(&data).with_context(|| format!("Parse {:?}", path))?;
+        if file.version > VERSION {
+            anyhow::bail!(
+                "{:?} was written by a newer version (format {}, supported {})",
+                path,
+                file.version,
+                VERSION
+            );
+        }
+        migrate(&mut file);
+        Ok(file)
+    }
+
+    /// Write to a temporary file first, then replace the store with it.
+    async fn save(path: &Path, file: &StoreFile) -> Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(file)?)
+            .await
+            .with_context(|| format!("Write {:?}", tmp_path))?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("Replace {:?}", path))?;
+        Ok(())
+    }
+
+    async fn get(&self, plugin: &str, key: &str) -> Result<Option<Value>> {
+        let mut data = self.data.lock().await;
+        if data.is_none() {
+            *data = Some(Self::load(&self.path).await?);
+        }
+        let file = data.as_ref().unwrap();
+
+        Ok(file.plugins.get(plugin).and_then(|p| p.get(key)).cloned())
+    }
+
+    /// Set or remove (with `None`) a value, and save the store.
+    async fn update(&self, plugin: &str, key: &str, value: Option<Value>) -> Result<()> {
+        let mut data = self.data.lock().await;
+        let mut file = match data.take() {
+            Some(file) => file,
+            None => Self::load(&self.path).await?,
+        };
+
+        let values = file.plugins.entry(plugin.to_string()).or_default();
+        match value {
+            Some(value) => {
+                values.insert(key.to_string(), value);
+            }
+            None => {
+                values.remove(key);
+            }
+        }
+        if values.is_empty() {
+            file.plugins.remove(plugin);
+        }
+
+        // Keep the loaded values even if saving fails, they are saved again on the next write.
+        let result = Self::save(&self.path, &file).await;
+        *data = Some(file);
+        result
+    }
+}
+
+/// Values of a plugin for one device, see [`crate::device::DeviceHandle::store`].
+#[derive(Debug, Clone)]
+pub struct PluginStore {
+    device: Arc<DeviceStore>,
+    plugin: String,
+}
+
+impl PluginStore {
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        match self.device.get(&self.plugin, key).await? {
+            Some(value) => Ok(Some(serde_json::from_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        self.device.update(&self.plugin, key, Some(value)).await
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        self.device.update(&self.plugin, key, None).await
+    }
+}
+
+/// Update values written in an older format of the file.
+///
+/// Each format version adds a step here, e.g. `if file.version < 2 { ... }`, so that files
+/// of any older version are brought up to date one version at a time.
+fn migrate(file: &mut StoreFile) {
+    file.version = VERSION;
+}
+
+/// Device IDs come from remote devices, so only keep characters that are safe in file names.
+///
+/// IDs with other characters get a hash of the ID appended, after a `.` that safe IDs can't
+/// contain, so that two devices never get the same file.
+fn file_name(device_id: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if !device_id.is_empty() && device_id.chars().all(is_safe) {
+        return format!("{}.json", device_id);
+    }
+
+    let name: String = device_id
+        .chars()
+        .map(|c| if is_safe(c) { c } else { '_' })
+        .collect();
+    format!("{}.{:x}.json", name, md5::compute(device_id))
+}
+
+/// Get the store of a plugin for a device.
+pub fn open(device_id: &str, plugin: &str) -> Result<PluginStore> {
+    let file_name = file_name(device_id);
+    let mut stores = DEVICE_STORES.lock().unwrap();
+    let device = match stores.get(&file_name) {
+        Some(device) => device.clone(),
+        None => {
+            let dir = crate::paths::get().device_stores_dir();
+            std::fs::create_dir_all(&dir).with_context(|| format!("Create {:?}", dir))?;
+
+            let device = Arc::new(DeviceStore::new(dir.join(&file_name)));
+            stores.insert(file_name, device.clone());
+            device
+        }
+    };
+
+    Ok(PluginStore {
+        device,
+        plugin: plugin.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    fn plugin_store(path: PathBuf) -> PluginStore {
+        PluginStore {
+            device: Arc::new(DeviceStore::new(path)),
+            plugin: "test".into(),
+        }
+    }
+
+    #[test]
+    fn file_names_are_unique_and_safe() {
+        assert_eq!(file_name("abc_123-def"), "abc_123-def.json");
+
+        let names = ["a.b", "a_b", "a/b", "..", "a\\b"].map(file_name);
+        for (i, name) in names.iter().enumerate() {
+            assert!(!name.contains(['/', '\\']), "{}", name);
+            assert!(!name.starts_with('.'), "{}", name);
+            assert!(!names[..i].contains(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn values_are_saved() {
+        let path = utils::test_dir("store-saved").join("device.json");
+        let store = plugin_store(path.clone());
+        assert_eq!(store.get::<u32>("count").await.unwrap(), None);
+
+        store.set("count", &3).await.unwrap();
+        store.set("name", &"Phone").await.unwrap();
+        store.remove("name").await.unwrap();
+
+        let reloaded = plugin_store(path.clone());
+        assert_eq!(reloaded.get::<u32>("count").await.unwrap(), Some(3));
+        assert_eq!(reloaded.get::<String>("name").await.unwrap(), None);
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn older_formats_are_migrated() {
+        let path = utils::test_dir("store-migrated").join("device.json");
+        std::fs::write(
+            &path,
+            r#"{"version": 0, "plugins": {"test": {"count": 1}}}"#,
+        )
+        .unwrap();
+
+        let store = plugin_store(path.clone());
+        assert_eq!(store.get::<u32>("count").await.unwrap(), Some(1));
+        store.set("count", &2).await.unwrap();
+
+        let file: StoreFile = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file.version, VERSION);
+    }
+
+    #[tokio::test]
+    async fn newer_formats_are_rejected() {
+        let path = utils::test_dir("store-newer").join("device.json");
+        let newer = serde_json::json!({"version": VERSION + 1, "plugins": {}});
+        std::fs::write(&path, newer.to_string()).unwrap();
+
+        let store = plugin_store(path.clone());
+        assert!(store.get::<u32>("count").await.is_err());
+        assert!(store.set("count", &1).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), newer.to_string());
+    }
+}
@@ -0,0 +1,212 @@
+//! Integration with systemd, to run the daemon as a user service on Linux.
+//!
+//! With socket activation (`LISTEN_FDS`), the TCP listener and the UDP discovery socket are
+//! bound by systemd and passed to the daemon instead, see [`take_sockets`]. Readiness, status
+//! and watchdog pings are sent to `NOTIFY_SOCKET` if it is set, see [`notify`].
+//!
+//! Nothing happens on other platforms, or when not started by systemd. The daemon itself
+//! still depends on Windows-only crates, so this is only built there for now.
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::context::AppContextRef;
+
+/// Sockets passed by systemd, see [`take_sockets`].
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    pub tcp: Option<std::net::TcpListener>,
+    pub udp: Option<std::net::UdpSocket>,
+}
+
+/// Take the sockets passed with socket activation, to use instead of binding new ones.
+///
+/// Must be called before the async runtime or any other thread is started, since it changes
+/// the environment: the variables are removed, so that plugins started by the daemon don't
+/// take the sockets as well. Files opened before are fine, the passed descriptors are already
+/// in use. Other descriptors are left open.
+#[cfg(unix)]
+pub fn take_sockets() -> ActivatedSockets {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    /// The first passed file descriptor, after stdin, stdout and stderr.
+    const LISTEN_FDS_START: i32 = 3;
+
+    let mut sockets = ActivatedSockets::default();
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    // The variables may have been left by a parent process.
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return sockets;
+    }
+    let count = match count.and_then(|count| count.parse::<i32>().ok()) {
+        Some(count) => count,
+        None => return sockets,
+    };
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd passes us these descriptors, and nothing else has used them yet.
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        match socket.r#type() {
+            Ok(socket2::Type::STREAM) if sockets.tcp.is_none() => {
+                log::info!("Using TCP listener from systemd: {:?}", socket.local_addr());
+                sockets.tcp = Some(socket.into());
+            }
+            Ok(socket2::Type::DGRAM) if sockets.udp.is_none() => {
+                log::info!("Using UDP socket from systemd: {:?}", socket.local_addr());
+                sockets.udp = Some(socket.into());
+            }
+            other => {
+                log::warn!("Ignoring socket {} from systemd: {:?}", fd, other);
+                let _ = socket.into_raw_fd();
+            }
+        }
+    }
+
+    sockets
+}
+
+#[cfg(not(unix))]
+pub fn take_sockets() -> ActivatedSockets {
+    ActivatedSockets::default()
+}
+
+/// Send a state like `READY=1` to the service manager. Returns false if the daemon was not
+/// started by systemd.
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send_notification(&path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// Send a state to the notification socket at `path`, which starts with `@` if it's in the
+/// abstract namespace.
+#[cfg(unix)]
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> Result<()> {
+    use anyhow::Context;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path);
+    match bytes.strip_prefix(b"@") {
+        // A socket in the abstract namespace.
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), path),
+    }
+    .with_context(|| format!("Notify {:?}", path))?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<bool> {
+    Ok(false)
+}
+
+/// How often the service manager expects a watchdog ping, if it does.
+fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+    )
+}
+
+/// The watchdog interval from `WATCHDOG_PID` and `WATCHDOG_USEC`, if it's meant for us.
+fn parse_watchdog(pid: Option<&str>, usec: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = usec?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the watchdog while the device manager responds, so that a stuck daemon is restarted.
+pub async fn watchdog(ctx: AppContextRef) -> Result<()> {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return std::future::pending().await,
+    };
+
+    // Twice as often as required, as recommended by sd_watchdog_enabled(3).
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        let response = tokio::time::timeout(interval / 2, ctx.device_manager.list_devices());
+        if matches!(response.await, Ok(Ok(_))) {
+            notify("WATCHDOG=1")?;
+        } else {
+            log::warn!("Device manager didn't respond, skipping watchdog ping");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_is_parsed() {
+        let pid = std::process::id().to_string();
+        let pid = Some(pid.as_str());
+        assert_eq!(
+            parse_watchdog(pid, Some("30000000")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(None, Some("500000")),
+            Some(Duration::from_millis(500))
+        );
+        // Meant for another process, disabled, or invalid.
+        assert_eq!(parse_watchdog(Some("1"), Some("30000000")), None);
+        assert_eq!(parse_watchdog(pid, Some("0")), None);
+        assert_eq!(parse_watchdog(pid, Some("soon")), None);
+        assert_eq!(parse_watchdog(pid, None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notifications_are_sent_to_the_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = crate::utils::test_dir("notify").join("notify.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        send_notification(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(send_notification(path.as_os_str(), "READY=1").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifications_are_sent_to_abstract_sockets() {
+        use std::os::{linux::net::SocketAddrExt, unix::net};
+
+        let name = format!("kdeconnect-rs-notify-{}", std::process::id());
+        let addr = net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let server = net::UnixDatagram::bind_addr(&addr).unwrap();
+        send_notification(format!("@{}", name).as_ref(), "WATCHDOG=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+}
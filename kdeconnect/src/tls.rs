@@ -1,13 +1,19 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 
 use rcgen::{CertificateParams, DistinguishedName};
 use tokio_rustls::rustls;
 use tokio_rustls::rustls::Error as TlsError;
+use tokio_rustls::rustls::{ClientConfig, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::{config::Config, settings::CertificateSettings};
 
 /// Parse a `rustls::Certificate` as an `x509_signature::X509Certificate`, if possible.
 fn get_cert(
     c: &tokio_rustls::rustls::Certificate,
-) -> Result<x509_signature::X509Certificate, TlsError> {
+) -> Result<x509_signature::X509Certificate<'_>, TlsError> {
     x509_signature::parse_certificate(c.as_ref()).map_err(|e| {
         TlsError::InvalidCertificateData(format!("Failed to parse certificate: {:?}", e))
     })
@@ -53,6 +59,9 @@ fn convert_scheme(
 }
 
 /// A TLS server verifier that does not actually verify the certificate.
+///
+/// Certificates are self-signed, those of paired devices are checked once the device has
+/// identified itself, see [`crate::trust::TrustStore::verify`].
 pub enum ServerVerifier {
     /// A server verifier that always returns `Ok`.
     AlwaysOk,
@@ -112,7 +121,8 @@ impl tokio_rustls::rustls::client::ServerCertVerifier for ServerVerifier {
     }
 }
 
-/// A TLS client verifier that does not actually verify the certificate.
+/// A TLS client verifier that does not actually verify the certificate, like
+/// [`ServerVerifier`].
 pub enum ClientVerifier {
     /// A client verifier that always returns `Ok`.
     AlwaysOk,
@@ -174,8 +184,12 @@ impl tokio_rustls::rustls::server::ClientCertVerifier for ClientVerifier {
     }
 }
 
-pub fn generate_certs(device_id: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+pub fn generate_certs(
+    device_id: &str,
+    settings: &CertificateSettings,
+) -> Result<(Vec<u8>, Vec<u8>)> {
     let mut cert_params = CertificateParams::new(vec![]);
+    cert_params.alg = settings.algorithm.signature_algorithm();
 
     let mut dn = DistinguishedName::new();
     dn.push(rcgen::DnType::CommonName, device_id);
@@ -185,7 +199,7 @@ pub fn generate_certs(device_id: &str) -> Result<(Vec<u8>, Vec<u8>)> {
 
     let now_utc = time::OffsetDateTime::now_utc();
     cert_params.not_before = now_utc - time::Duration::WEEK * 7;
-    cert_params.not_after = now_utc + time::Duration::DAY * 365 * 10;
+    cert_params.not_after = now_utc + time::Duration::DAY * settings.validity_days;
 
     let cert = rcgen::Certificate::from_params(cert_params)?;
 
@@ -194,3 +208,85 @@ pub fn generate_certs(device_id: &str) -> Result<(Vec<u8>, Vec<u8>)> {
 
     Ok((cert_der, key_der))
 }
+
+/// Check that the private key belongs to the certificate.
+pub fn check_key_pair(key_der: &[u8], cert_der: &[u8]) -> Result<()> {
+    let key_pair = rcgen::KeyPair::from_der(key_der)?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {}", e))?;
+
+    if cert.public_key().subject_public_key.data != key_pair.public_key_raw() {
+        anyhow::bail!("Private key does not match the certificate");
+    }
+
+    Ok(())
+}
+
+/// Time left until the certificate expires, `None` if it has already expired.
+pub fn time_to_expiration(cert_der: &[u8]) -> Result<Option<time::Duration>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {}", e))?;
+
+    Ok(cert.validity().time_to_expiration())
+}
+
+/// Create the acceptor and connector using our certificate.
+///
+/// The same certificate is used when we are acting as client and server.
+pub fn setup(config: &Config) -> Result<(TlsAcceptor, TlsConnector)> {
+    let cert_chain = vec![rustls::Certificate(config.tls_cert.clone())];
+    let key = rustls::PrivateKey(config.tls_key.clone());
+
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(ServerVerifier::AlwaysOk))
+        .with_single_cert(cert_chain.clone(), key.clone())?;
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(ClientVerifier::AlwaysOk))
+        .with_single_cert(cert_chain, key)?;
+
+    Ok((
+        TlsAcceptor::from(Arc::new(server_config)),
+        TlsConnector::from(Arc::new(client_config)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::CertificateAlgorithm;
+
+    #[test]
+    fn certificates_follow_settings() {
+        for algorithm in [
+            CertificateAlgorithm::EcdsaP256,
+            CertificateAlgorithm::EcdsaP384,
+            CertificateAlgorithm::Ed25519,
+        ] {
+            let settings = CertificateSettings {
+                algorithm,
+                validity_days: 30,
+            };
+            let (cert, key) = generate_certs("new_id", &settings).unwrap();
+            check_key_pair(&key, &cert).unwrap();
+
+            let left = time_to_expiration(&cert).unwrap().unwrap();
+            assert!((29..=30).contains(&left.whole_days()), "{:?}", algorithm);
+
+            let (_, parsed) = x509_parser::parse_x509_certificate(&cert).unwrap();
+            let common_name = parsed.subject().iter_common_name().next().unwrap();
+            assert_eq!(common_name.as_str().unwrap(), "new_id");
+        }
+    }
+
+    #[test]
+    fn keys_of_other_certificates_are_rejected() {
+        let settings = CertificateSettings::default();
+        let (cert, _) = generate_certs("id", &settings).unwrap();
+        let (_, key) = generate_certs("id", &settings).unwrap();
+
+        assert!(check_key_pair(&key, &cert).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Tray menu types used by plugins.
+//!
+//! On Windows these are tao's. Elsewhere the daemon runs headless, so these are stand-ins with
+//! the same API that build nothing.
+
+#[cfg(windows)]
+pub use tao::menu::{ContextMenu, MenuId, MenuItem, MenuItemAttributes};
+
+#[cfg(not(windows))]
+pub use headless::{ContextMenu, MenuId, MenuItem, MenuItemAttributes};
+
+#[cfg(not(windows))]
+mod headless {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    /// Identifies a menu item in [`crate::event::SystemEvent::TrayMenuClicked`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct MenuId(u64);
+
+    impl MenuId {
+        pub fn new(unique_string: &str) -> Self {
+            let mut hasher = DefaultHasher::new();
+            unique_string.hash(&mut hasher);
+            MenuId(hasher.finish())
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    #[non_exhaustive]
+    pub enum MenuItem {
+        Separator,
+        Quit,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct MenuItemAttributes<'a> {
+        _title: &'a str,
+    }
+
+    impl<'a> MenuItemAttributes<'a> {
+        pub fn new(title: &'a str) -> Self {
+            MenuItemAttributes { _title: title }
+        }
+
+        pub fn with_id(self, _id: MenuId) -> Self {
+            self
+        }
+
+        pub fn with_enabled(self, _enabled: bool) -> Self {
+            self
+        }
+
+        pub fn with_selected(self, _selected: bool) -> Self {
+            self
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct ContextMenu;
+
+    impl ContextMenu {
+        pub fn new() -> Self {
+            ContextMenu
+        }
+
+        pub fn add_item(&mut self, _item: MenuItemAttributes) {}
+
+        pub fn add_native_item(&mut self, _item: MenuItem) {}
+
+        pub fn add_submenu(&mut self, _title: &str, _enabled: bool, _submenu: ContextMenu) {}
+    }
+}
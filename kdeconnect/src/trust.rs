@@ -0,0 +1,152 @@
+//! Devices we have paired with, stored along with their certificates.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustedDevice {
+    pub name: String,
+    /// DER-encoded certificate, base64 encoded.
+    pub certificate: String,
+}
+
+pub type TrustedDevices = HashMap<String, TrustedDevice>;
+
+#[derive(Debug)]
+pub struct TrustStore {
+    path: PathBuf,
+    devices: Mutex<TrustedDevices>,
+}
+
+impl TrustStore {
+    /// Loads trusted devices from a file, starting with none if it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let devices = if path.exists() {
+            Self::read(path)?
+        } else {
+            TrustedDevices::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            devices: Mutex::new(devices),
+        })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<TrustedDevices> {
+        let f = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    pub fn write(path: impl AsRef<Path>, devices: &TrustedDevices) -> Result<()> {
+        crate::paths::write_private_file(path, &serde_json::to_vec_pretty(devices)?)
+    }
+
+    pub fn is_trusted(&self, device_id: &str) -> bool {
+        self.devices.lock().unwrap().contains_key(device_id)
+    }
+
+    /// Check the certificate a device presented, as TLS accepts any certificate.
+    ///
+    /// A paired device must present the certificate it was paired with. Other devices are
+    /// accepted, they can only ask to pair.
+    pub fn verify(&self, device_id: &str, certificate: Option<&[u8]>) -> Result<()> {
+        let devices = self.devices.lock().unwrap();
+        let trusted = match devices.get(device_id) {
+            Some(trusted) => trusted,
+            None => return Ok(()),
+        };
+
+        match certificate {
+            Some(certificate) if base64::encode(certificate) == trusted.certificate => Ok(()),
+            Some(_) => anyhow::bail!(
+                "{} presented another certificate than the one it was paired with, unpair it to \
+                 pair again",
+                device_id
+            ),
+            None => anyhow::bail!("{} presented no certificate", device_id),
+        }
+    }
+
+    /// Snapshot of all trusted devices.
+    pub fn all(&self) -> TrustedDevices {
+        self.devices.lock().unwrap().clone()
+    }
+
+    pub fn trust(&self, device_id: &str, name: &str, certificate: &[u8]) -> Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.insert(
+            device_id.to_string(),
+            TrustedDevice {
+                name: name.to_string(),
+                certificate: base64::encode(certificate),
+            },
+        );
+        Self::write(&self.path, &devices)
+    }
+
+    /// Remove a device, returns whether it was trusted.
+    pub fn untrust(&self, device_id: &str) -> Result<bool> {
+        let mut devices = self.devices.lock().unwrap();
+        if devices.remove(device_id).is_none() {
+            return Ok(false);
+        }
+        Self::write(&self.path, &devices)?;
+        Ok(true)
+    }
+
+    /// Remove all devices.
+    pub fn clear(&self) -> Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.clear();
+        Self::write(&self.path, &devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_are_saved() {
+        let path = crate::utils::test_dir("trust-save").join("trusted_devices.json");
+
+        let store = TrustStore::load(&path).unwrap();
+        store.trust("phone", "Phone", b"cert").unwrap();
+        store.trust("tablet", "Tablet", b"other").unwrap();
+        assert!(store.untrust("tablet").unwrap());
+        assert!(!store.untrust("tablet").unwrap());
+
+        let store = TrustStore::load(&path).unwrap();
+        assert!(store.is_trusted("phone"));
+        assert!(!store.is_trusted("tablet"));
+        assert_eq!(store.all()["phone"].name, "Phone");
+
+        store.clear().unwrap();
+        assert!(TrustStore::load(&path).unwrap().all().is_empty());
+    }
+
+    #[test]
+    fn paired_devices_must_present_their_certificate() {
+        let path = crate::utils::test_dir("trust-verify").join("trusted_devices.json");
+        let store = TrustStore::load(&path).unwrap();
+        store.trust("phone", "Phone", b"cert").unwrap();
+
+        store.verify("phone", Some(b"cert")).unwrap();
+        assert!(store.verify("phone", Some(b"forged")).is_err());
+        assert!(store.verify("phone", None).is_err());
+
+        // Unknown devices can connect to ask for pairing.
+        store.verify("stranger", Some(b"anything")).unwrap();
+        store.verify("stranger", None).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
-use std::collections::HashSet;
+//! Access to the system clipboard, which is only supported on Windows.
 
 use anyhow::Result;
+#[cfg(windows)]
 use clipboard_win::{formats, Clipboard, Getter, Setter};
+#[cfg(windows)]
+use std::collections::HashSet;
 
 #[derive(Debug)]
 pub enum ClipboardContent {
@@ -11,6 +14,7 @@ pub enum ClipboardContent {
 }
 
 /// Attempt to open (and lock) the global clipboard with a 100ms attempt timeout.
+#[cfg(windows)]
 fn try_open_clipboard() -> Result<Clipboard> {
     let mut clipboard = None;
     for _ in 0..10 {
@@ -27,6 +31,7 @@ fn try_open_clipboard() -> Result<Clipboard> {
     }
 }
 
+#[cfg(windows)]
 pub fn read() -> Result<ClipboardContent> {
     let _clip = try_open_clipboard()?;
 
@@ -47,6 +52,7 @@ pub fn read() -> Result<ClipboardContent> {
     Ok(ClipboardContent::Unsupported)
 }
 
+#[cfg(windows)]
 pub fn write(content: ClipboardContent) -> Result<()> {
     let _clip = try_open_clipboard()?;
 
@@ -59,3 +65,13 @@ pub fn write(content: ClipboardContent) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(not(windows))]
+pub fn read() -> Result<ClipboardContent> {
+    anyhow::bail!("The clipboard is only supported on Windows")
+}
+
+#[cfg(not(windows))]
+pub fn write(_content: ClipboardContent) -> Result<()> {
+    anyhow::bail!("The clipboard is only supported on Windows")
+}
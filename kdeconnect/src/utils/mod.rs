@@ -1,15 +1,20 @@
-use std::{iter::once, os::windows::prelude::*};
+#[cfg(windows)]
+use std::{iter::once, mem::MaybeUninit, os::windows::prelude::*};
 
+#[cfg(windows)]
 use windows::Win32::{
     Foundation::{HWND, LPARAM, LRESULT, WPARAM},
+    System::Power::GetSystemPowerStatus,
     UI::WindowsAndMessaging::DefWindowProcW,
 };
+#[cfg(windows)]
 use winrt_toast::{Text, Toast, ToastManager};
 
 pub mod clipboard;
 pub mod open;
 pub mod debounce;
 
+#[cfg(windows)]
 lazy_static::lazy_static! {
     pub static ref TOAST_MANAGER: ToastManager = {
         ToastManager::new(crate::AUM_ID)
@@ -23,12 +28,45 @@ pub fn unix_ts_ms() -> u64 {
         .as_millis() as u64
 }
 
+/// Describe how long ago `time` was, e.g. "10 min ago".
+pub fn format_time_ago(time: std::time::SystemTime) -> String {
+    let secs = time.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", secs / 60),
+        3600..=86399 => format!("{} h ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+/// Whether the system has a battery, i.e. it's probably a laptop.
+#[cfg(windows)]
+pub fn has_battery() -> bool {
+    let power_status = unsafe {
+        let mut power_status = MaybeUninit::uninit();
+        if !GetSystemPowerStatus(power_status.as_mut_ptr()).as_bool() {
+            return false;
+        }
+        power_status.assume_init()
+    };
+
+    // 128: No system battery, 255: Unknown status
+    power_status.BatteryFlag & 128 == 0 && power_status.BatteryFlag != 255
+}
+
+/// Batteries are only detected on Windows.
+#[cfg(not(windows))]
+pub fn has_battery() -> bool {
+    false
+}
+
 pub fn log_if_error<R, E: std::fmt::Debug>(text: &str, res: Result<R, E>) {
     if let Err(e) = res {
         log::error!("{}: {:?}", text, e);
     }
 }
 
+#[cfg(windows)]
 pub async fn simple_toast(title: &str, content: Option<&str>, attribution: Option<&str>) {
     let mut toast = Toast::new();
     toast.text1(title);
@@ -53,10 +91,23 @@ pub async fn simple_toast(title: &str, content: Option<&str>, attribution: Optio
     }
 }
 
+/// Without toasts, notifications only show up in the log.
+#[cfg(not(windows))]
+pub async fn simple_toast(title: &str, content: Option<&str>, attribution: Option<&str>) {
+    log::info!(
+        "{}{}{}",
+        title,
+        content.map(|c| format!(": {}", c)).unwrap_or_default(),
+        attribution.map(|a| format!(" ({})", a)).unwrap_or_default()
+    );
+}
+
+#[cfg(windows)]
 pub fn encode_wide(string: impl AsRef<std::ffi::OsStr>) -> Vec<u16> {
     string.as_ref().encode_wide().chain(once(0)).collect()
 }
 
+#[cfg(windows)]
 pub unsafe extern "system" fn call_default_window_proc(
     hwnd: HWND,
     msg: u32,
@@ -65,3 +116,12 @@ pub unsafe extern "system" fn call_default_window_proc(
 ) -> LRESULT {
     DefWindowProcW(hwnd, msg, wparam, lparam)
 }
+
+/// An empty directory for a test, named after it so that tests don't share files.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("kdeconnect-rs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -1,16 +1,21 @@
 use anyhow::Result;
+#[cfg(windows)]
 use tokio::sync::{mpsc, oneshot};
+#[cfg(windows)]
 use windows::Win32::System::Com::COINIT_MULTITHREADED;
 
+#[cfg(windows)]
 enum RequestType {
     OpenItem(String),
 }
 
+#[cfg(windows)]
 struct WindowsApiRequest {
     ty: RequestType,
     response: oneshot::Sender<Result<()>>,
 }
 
+#[cfg(windows)]
 impl WindowsApiRequest {
     fn new(ty: RequestType) -> (Self, oneshot::Receiver<Result<()>>) {
         let (tx, rx) = oneshot::channel();
@@ -18,6 +23,7 @@ impl WindowsApiRequest {
     }
 }
 
+#[cfg(windows)]
 fn create_windows_api_thread() -> mpsc::Sender<WindowsApiRequest> {
     use windows::Win32::System::Com::{CoInitializeEx, COINIT_DISABLE_OLE1DDE};
     use windows::{
@@ -73,12 +79,14 @@ fn create_windows_api_thread() -> mpsc::Sender<WindowsApiRequest> {
     sender
 }
 
+#[cfg(windows)]
 lazy_static::lazy_static! {
     static ref WINDOWS_API_SENDER: mpsc::Sender<WindowsApiRequest> = {
         create_windows_api_thread()
     };
 }
 
+#[cfg(windows)]
 pub async fn open_url(url: impl Into<String>) -> Result<()> {
     let (req, rx) = WindowsApiRequest::new(RequestType::OpenItem(url.into()));
     match WINDOWS_API_SENDER.send(req).await {
@@ -88,3 +96,14 @@ pub async fn open_url(url: impl Into<String>) -> Result<()> {
         )),
     }
 }
+
+/// Open with the desktop's default handler, if there's one.
+#[cfg(not(windows))]
+pub async fn open_url(url: impl Into<String>) -> Result<()> {
+    let status = tokio::process::Command::new("xdg-open")
+        .arg(url.into())
+        .status()
+        .await?;
+    anyhow::ensure!(status.success(), "xdg-open failed: {}", status);
+    Ok(())
+}
@@ -1,7 +1,11 @@
 //! A mostly usable binding to the Windows `ToastNotification` API.
 //!
+//! The crate is empty on other platforms, so it can stay in a cross-platform workspace.
+//!
 //! # Example
 //! ```no_run
+//! # #[cfg(windows)]
+//! # fn main() {
 //! use winrt_toast::{Toast, Text, Header, ToastManager};
 //! use winrt_toast::content::text::TextPlacement;
 //!
@@ -26,8 +30,12 @@
 //!         eprintln!("Failed to show toast: {:?}", e);
 //!     }))
 //! ).expect("Failed to show toast");
+//! # }
+//! # #[cfg(not(windows))]
+//! # fn main() {}
 //! ```
 
+#![cfg(windows)]
 #![warn(missing_docs)]
 
 /// Contents in a toast notification.